@@ -0,0 +1,48 @@
+use std::fmt::Write;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use pprof::criterion::{Output, PProfProfiler};
+
+// The generated functions are declared but never called, so running the program is dominated by
+// compiling it.
+fn generate_program(functions: usize) -> String {
+    let mut code = String::from("var counter = 0;\n");
+    for i in 0..functions {
+        writeln!(
+            code,
+            "fun function{i}(first, second) {{\n  \
+               var sum = first + second;\n  \
+               var name = \"function{i}\";\n  \
+               counter = counter + sum;\n  \
+               if (sum > counter) {{ print name; }} else {{ print \"counter\"; }}\n  \
+               return sum;\n\
+             }}"
+        )
+        .unwrap();
+    }
+
+    code
+}
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let path = std::env::temp_dir().join("rlox_compile_bench.lox");
+    std::fs::write(&path, generate_program(2000)).unwrap();
+    let path = path.to_str().unwrap();
+
+    c.bench_function("compile", |b| {
+        b.iter(|| {
+            let result =
+                rlox::run_program(path, std::io::sink(), std::io::sink(), std::io::sink()).0;
+            if let Err(error) = result {
+                eprintln!("{:?}", error);
+            }
+        })
+    });
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().with_profiler(PProfProfiler::new(100, Output::Flamegraph(None)));
+    targets = criterion_benchmark
+}
+criterion_main!(benches);
@@ -12,8 +12,16 @@ fn run_fib() {
     }
 }
 
+fn run_fib_closure() {
+    let result = run_program("benches/files/fib_closure.lox");
+    if let Err(error) = result {
+        eprintln!("{:?}", error);
+    }
+}
+
 fn criterion_benchmark(c: &mut Criterion) {
     c.bench_function("fib", |b| b.iter(run_fib));
+    c.bench_function("fib_closure", |b| b.iter(run_fib_closure));
 }
 
 criterion_group! {
@@ -0,0 +1,40 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use pprof::criterion::{Output, PProfProfiler};
+
+fn run_program(file: &str) -> Result<(), rlox::Error> {
+    rlox::run_program(file, std::io::sink(), std::io::sink(), std::io::sink()).0
+}
+
+fn run_globals() {
+    let result = run_program("benches/files/globals.lox");
+    if let Err(error) = result {
+        eprintln!("{:?}", error);
+    }
+}
+
+fn run_globals_write() {
+    let result = run_program("benches/files/globals_write.lox");
+    if let Err(error) = result {
+        eprintln!("{:?}", error);
+    }
+}
+
+fn run_globals_increment() {
+    let result = run_program("benches/files/globals_increment.lox");
+    if let Err(error) = result {
+        eprintln!("{:?}", error);
+    }
+}
+
+fn criterion_benchmark(c: &mut Criterion) {
+    c.bench_function("globals", |b| b.iter(run_globals));
+    c.bench_function("globals_write", |b| b.iter(run_globals_write));
+    c.bench_function("globals_increment", |b| b.iter(run_globals_increment));
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().with_profiler(PProfProfiler::new(100, Output::Flamegraph(None)));
+    targets = criterion_benchmark
+}
+criterion_main!(benches);
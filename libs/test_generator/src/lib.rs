@@ -1,8 +1,21 @@
-fn input_to_glob_pattern(input: proc_macro::TokenStream) -> String {
-    let mut input = syn::parse::<syn::LitStr>(input)
+use syn::parse::Parser;
+
+// The input is the directory containing the tests, optionally followed by tests which should not be
+// run, given as "<sub-directory>/<file name without extension>".
+fn parse_input(input: proc_macro::TokenStream) -> (String, Vec<String>) {
+    let mut input = syn::punctuated::Punctuated::<syn::LitStr, syn::Token![,]>::parse_terminated
+        .parse(input)
         .expect("Could not parse make_test! input.")
-        .value();
+        .into_iter()
+        .map(|literal| literal.value());
+
+    let directory = input
+        .next()
+        .expect("make_test! requires the directory containing the tests.");
+    (directory, input.collect())
+}
 
+fn input_to_glob_pattern(mut input: String) -> String {
     if !input.ends_with('/') {
         input.push('/');
     }
@@ -35,10 +48,18 @@ fn glob_to_function_name_and_path(path: &glob::GlobResult) -> (String, String) {
     (function_name, path)
 }
 
-fn glob_pattern_to_function_name_and_path(pattern: &str) -> Vec<(String, String)> {
+fn glob_pattern_to_function_name_and_path(
+    pattern: &str,
+    excluded: &[String],
+) -> Vec<(String, String)> {
     let mut functions = glob::glob(pattern)
         .expect("Glob pattern should be correct if the given input is a path.")
         .map(|glob_res| glob_to_function_name_and_path(&glob_res))
+        .filter(|(_, path)| {
+            !excluded
+                .iter()
+                .any(|test| path.ends_with(format!("/{}.lox", test).as_str()))
+        })
         .collect::<Vec<(String, String)>>();
     functions.sort();
     functions
@@ -46,8 +67,9 @@ fn glob_pattern_to_function_name_and_path(pattern: &str) -> Vec<(String, String)
 
 #[proc_macro]
 pub fn make_tests(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
-    let pattern = input_to_glob_pattern(input);
-    let functions = glob_pattern_to_function_name_and_path(pattern.as_str());
+    let (directory, excluded) = parse_input(input);
+    let pattern = input_to_glob_pattern(directory);
+    let functions = glob_pattern_to_function_name_and_path(pattern.as_str(), &excluded);
 
     let name = functions
         .iter()
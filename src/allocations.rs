@@ -0,0 +1,165 @@
+//! This module counts how many objects are allocated while running a program. It is only compiled
+//! if the profile_allocations feature is enabled, so that counting does not cost anything
+//! otherwise. The counts are reported by the binary when it exits.
+//! The counters are kept per thread, so that programs run on different threads do not mix up
+//! their counts.
+
+use std::cell::Cell;
+
+/// The number of objects of each kind that have been allocated.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Allocations {
+    closures: usize,
+    instances: usize,
+    classes: usize,
+    strings: usize,
+    lists: usize,
+    maps: usize,
+}
+
+impl Allocations {
+    pub fn get_closures(&self) -> usize {
+        self.closures
+    }
+
+    pub fn get_instances(&self) -> usize {
+        self.instances
+    }
+
+    pub fn get_classes(&self) -> usize {
+        self.classes
+    }
+
+    pub fn get_strings(&self) -> usize {
+        self.strings
+    }
+
+    pub fn get_lists(&self) -> usize {
+        self.lists
+    }
+
+    pub fn get_maps(&self) -> usize {
+        self.maps
+    }
+}
+
+impl std::fmt::Display for Allocations {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        writeln!(f, "== allocations ==")?;
+        writeln!(f, "closures:  {}", self.closures)?;
+        writeln!(f, "instances: {}", self.instances)?;
+        writeln!(f, "classes:   {}", self.classes)?;
+        writeln!(f, "strings:   {}", self.strings)?;
+        writeln!(f, "lists:     {}", self.lists)?;
+        writeln!(f, "maps:      {}", self.maps)
+    }
+}
+
+thread_local! {
+    static ALLOCATIONS: Cell<Allocations> = Cell::new(Allocations::default());
+}
+
+fn update(f: impl FnOnce(&mut Allocations)) {
+    ALLOCATIONS.with(|allocations| {
+        let mut current = allocations.get();
+        f(&mut current);
+        allocations.set(current);
+    });
+}
+
+pub fn count_closure() {
+    update(|a| a.closures += 1);
+}
+
+pub fn count_instance() {
+    update(|a| a.instances += 1);
+}
+
+pub fn count_class() {
+    update(|a| a.classes += 1);
+}
+
+pub fn count_string() {
+    update(|a| a.strings += 1);
+}
+
+pub fn count_list() {
+    update(|a| a.lists += 1);
+}
+
+pub fn count_map() {
+    update(|a| a.maps += 1);
+}
+
+/// Returns the allocations counted on this thread since the last reset.
+pub fn get() -> Allocations {
+    ALLOCATIONS.with(|allocations| allocations.get())
+}
+
+pub fn reset() {
+    ALLOCATIONS.with(|allocations| allocations.set(Allocations::default()));
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::allocations::{self, Allocations};
+    use crate::compile::Parser;
+    use crate::scanner::Scanner;
+    use crate::vm::VM;
+
+    fn count(source: &str) -> Allocations {
+        allocations::reset();
+        let chars = source.chars().collect::<Vec<char>>();
+        let scanner = Scanner::new(chars.as_slice());
+        let parser = Parser::new(scanner.parse(), std::io::sink());
+        let (closure, symbol_table) = match parser.compile() {
+            Ok((closure, symbol_table, _)) => (closure, symbol_table),
+            Err(_) => panic!("Expected the program to compile."),
+        };
+
+        let vm = VM::with_write(closure, symbol_table, std::io::sink(), std::io::sink());
+        assert!(vm.interpret().is_ok());
+        allocations::get()
+    }
+
+    #[test]
+    fn count_instances() {
+        let allocations = count(
+            "class Node {}
+            var head = nil;
+            for (var i = 0; i < 100; i = i + 1) {
+                var node = Node();
+                node.next = head;
+                head = node;
+            }",
+        );
+
+        assert_eq!(allocations.get_instances(), 100);
+        assert_eq!(allocations.get_classes(), 1);
+        assert_eq!(allocations.get_closures(), 1);
+    }
+
+    #[test]
+    fn count_lists() {
+        let allocations = count(
+            "var lists = [];
+            for (var i = 0; i < 10; i = i + 1) {
+                lists = [lists, [i]];
+            }",
+        );
+
+        assert_eq!(allocations.get_lists(), 21);
+    }
+
+    #[test]
+    fn count_maps() {
+        let allocations = count(
+            "var maps = {:};
+            for (var i = 0; i < 10; i = i + 1) {
+                maps[i] = {\"index\": i};
+            }",
+        );
+
+        assert_eq!(allocations.get_maps(), 11);
+    }
+}
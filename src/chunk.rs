@@ -1,29 +1,34 @@
+//! This module supports creating and disassembling a chunk of code consisting of Opcode and
+//! integer arguments using the builder pattern.
+//!
+//! A ChunkBuilder can be used to construct a chunk.
+//! The builder patter is used to add extra run-time correctness checks for the produced chunk,
+//! however, it is still possible to create ill-formed chunk.
+//!
+//! Besides opcodes and arguments a chunk can contain constants. Adding a constant yields an index,
+//! that can be used to refer to the constant.
+//!
+//! Lastly, the ChunkBuilder allows for adding patches. These are punch-holes in the linear
+//! instruction sequence that can be filled in later. This is in particular useful when adding jump
+//! forward instructions, that is, when the index of the instruction which should be jumped to is
+//! not know at the time the jump instruction is written. Adding a path allows to continue appending
+//! instruction and filling the patch with the concrete index which should be jumped to later on
+//! when the exact index is known.
+
 use ::std::io::Write;
-use std::cell::RefCell;
-use std::cmp::Ordering;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
 use std::ops::Deref;
 use std::rc::Rc;
 
+use ::enum_map::Enum;
+
+use crate::error::DeserializeError;
+use crate::function::{Function, FunctionType};
+use crate::intern_string::{Symbol, SymbolTable};
 use crate::opcodes::{IndexesPerOpCode, OpCode};
 use crate::value::Value;
 
-/// This module supports creating and disassembling a chunk of code consisting of Opcode and
-/// integer arguments using the builder pattern.
-///
-/// A ChunkBuilder can be used to construct a chunk.
-/// The builder patter is used to add extra run-time correctness checks for the produced chunk,
-/// however, it is still possible to create ill-formed chunk.
-///
-/// Besides opcodes and arguments a chunk can contain constants. Adding a constant yields an index,
-/// that can be used to refer to the constant.
-///
-/// Lastly, the ChunkBuilder allows for adding patches. These are punch-holes in the linear
-/// instruction sequence that can be filled in later. This is in particular useful when adding jump
-/// forward instructions, that is, when the index of the instruction which should be jumped to is
-/// not know at the time the jump instruction is written. Adding a path allows to continue appending
-/// instruction and filling the patch with the concrete index which should be jumped to later on
-/// when the exact index is known.
-
 /// Some opcodes require arguments in form of values (e.g. doubles or strings).
 /// Instead of storing these inline we have a separate pool for values in which we index.
 /// The indexes are stored inline in the instruction sequence.
@@ -64,6 +69,10 @@ impl From<u8> for CodeUnit {
 // We want to fit code units in an Vec<u8> so, ensure that we have the right size.
 ::static_assertions::assert_eq_size! {CodeUnit, u8}
 
+/// The largest offset that can be encoded in the three byte operand of the long jump instructions.
+/// It is also the largest constant index the long constant instruction can encode.
+pub const MAX_LONG_ADDRESS: u32 = 0x00ff_ffff;
+
 struct LineInfo {
     line: u32,
     count: u32,
@@ -102,7 +111,7 @@ impl Chunk {
     /// Returns the code unit located at the given instruction index.
     /// Could be an opcode or an index.
     /// Panics if the given instruction index is out of range.
-    pub fn get_code_unit(&self, instruction_index: usize) -> CodeUnit {
+    pub(crate) fn get_code_unit(&self, instruction_index: usize) -> CodeUnit {
         self.code[instruction_index]
     }
 
@@ -123,8 +132,35 @@ impl Chunk {
         &self.constants[index as usize]
     }
 
+    /// Returns a reference to the value located at the given index of a long constant instruction.
+    /// Panics if the given index is out of range.
+    pub fn get_value_at_long_index(&self, index: u32) -> &Value {
+        &self.constants[index as usize]
+    }
+
+    /// Returns the constants of the chunk, ordered by their index.
+    pub fn get_constants(&self) -> &[Value] {
+        &self.constants
+    }
+
+    /// Returns the number of code units of the chunk, that is the opcodes and the indexes
+    /// following them.
+    pub fn get_code_len(&self) -> usize {
+        self.code.len()
+    }
+
+    /// Returns an iterator over the instructions of the chunk, decoded with their operands.
+    pub fn get_instructions(&self) -> Instructions<'_> {
+        Instructions {
+            chunk: self,
+            offset: 0,
+            indexes_per_op: IndexesPerOpCode::new(),
+        }
+    }
+
     /// Prints a disassemble of the chunk to stdout.
     /// Name is the name of this chunk.
+    #[allow(dead_code)]
     pub fn print_disassemble(&self, name: &str) -> std::io::Result<()> {
         self.disassemble(name, &mut std::io::stdout())
     }
@@ -144,7 +180,8 @@ impl Chunk {
 
     /// Writes a disassemble of the opcode at the given offset to the given writer.
     /// Safety: Requires that offset points to an opcode.
-    pub unsafe fn print_disassemble_instruction_unsafe(
+    #[allow(dead_code)]
+    pub(crate) unsafe fn print_disassemble_instruction_unsafe(
         &self,
         offset: usize,
     ) -> Result<(), std::io::Error> {
@@ -153,7 +190,8 @@ impl Chunk {
 
     /// Writes a disassemble of the opcode at the given offset to the given writer.
     /// Safety: Requires that offset points to an opcode.
-    pub unsafe fn disassemble_instruction_unsafe(
+    #[allow(dead_code)]
+    pub(crate) unsafe fn disassemble_instruction_unsafe(
         &self,
         offset: usize,
         writer: &mut impl Write,
@@ -174,14 +212,11 @@ impl Chunk {
 
     fn write_opcode(&mut self, opcode: OpCode, line: u32) -> usize {
         self.code.push(CodeUnit::from(opcode));
-        if let Some(info) = self.lines.last_mut() {
-            match info.line().cmp(&line) {
-                Ordering::Less => self.lines.push(LineInfo::new(line, 1)),
-                Ordering::Equal => info.inc_count(),
-                Ordering::Greater => panic!("Line numbers should not decrease."),
-            }
-        } else {
-            self.lines.push(LineInfo::new(line, 1));
+        // Lines usually increase, but code is not required to be emitted in source order. A line
+        // that differs from the one before always gets its own entry, so it is never lost.
+        match self.lines.last_mut() {
+            Some(info) if info.line() == line => info.inc_count(),
+            _ => self.lines.push(LineInfo::new(line, 1)),
         }
 
         self.code.len() - 1
@@ -203,6 +238,60 @@ impl Chunk {
         self.code[position] = CodeUnit::from(index);
     }
 
+    // Unconditionally override the code unit at the given position with the given opcode.
+    // Safety: Position needs to point to an opcode and the given opcode has to take the same number
+    // of indexes as the opcode that is replaced.
+    unsafe fn write_opcode_at(&mut self, opcode: OpCode, position: usize) {
+        self.code[position] = CodeUnit::from(opcode);
+    }
+
+    // Inserts an index at the given position, moving all following code units back by one. The
+    // inserted index is attributed to the same source code line as the code unit before it.
+    // Safety: Position must not be zero and the code unit before it needs to belong to the same
+    // instruction as the inserted index.
+    unsafe fn insert_index_at(&mut self, index: u8, position: usize) {
+        self.code.insert(position, CodeUnit::from(index));
+
+        let mut end = 0;
+        for info in self.lines.iter_mut() {
+            end += info.count() as usize;
+            if end >= position {
+                info.inc_count();
+                return;
+            }
+        }
+    }
+
+    // Returns the number of code units taken up by the instruction at the given offset.
+    // Safety: Requires that offset points to an opcode.
+    unsafe fn instruction_len(&self, offset: usize, indexes_per_op: &IndexesPerOpCode) -> usize {
+        match self.code[offset].get_opcode() {
+            OpCode::Closure | OpCode::ClosureLong => {
+                let (index, next) = self.constant_index(offset);
+                match &self.constants[index] {
+                    Value::Function(fun) => next - offset + 2 * fun.get_upvalue_count(),
+                    _ => panic!("Expected a function value."),
+                }
+            }
+            opcode => 1 + indexes_per_op.get(opcode) as usize,
+        }
+    }
+
+    // Returns the index of the constant that the instruction at the given offset refers to, and the
+    // offset of the code unit following that index.
+    // Safety: Requires that offset points to an opcode whose first operand is the index of a
+    // constant.
+    unsafe fn constant_index(&self, offset: usize) -> (usize, usize) {
+        if self.code[offset].get_opcode().has_long_constant_index() {
+            let index = (1..=3).fold(0usize, |index, i| {
+                (index << 8) + self.code[offset + i].get_index() as usize
+            });
+            (index, offset + 4)
+        } else {
+            (self.code[offset + 1].get_index() as usize, offset + 2)
+        }
+    }
+
     fn add_constant(&mut self, value: Value) -> usize {
         match self.constants.iter().position(|v| v == &value) {
             Some(index) => index,
@@ -261,37 +350,82 @@ impl Chunk {
             | OpCode::GetProperty
             | OpCode::SetProperty
             | OpCode::Method
-            | OpCode::GetSuper => self.constant_instruction(opcode, offset, writer),
+            | OpCode::StaticMethod
+            | OpCode::GetSuper
+            | OpCode::ConstantLong
+            | OpCode::DefineGlobalLong
+            | OpCode::GetGlobalLong
+            | OpCode::SetGlobalLong
+            | OpCode::ClassLong
+            | OpCode::GetPropertyLong
+            | OpCode::SetPropertyLong
+            | OpCode::MethodLong
+            | OpCode::StaticMethodLong
+            | OpCode::GetSuperLong
+            | OpCode::DefineConstGlobal
+            | OpCode::DefineConstGlobalLong => self.constant_instruction(opcode, offset, writer),
             OpCode::GetLocal
             | OpCode::SetLocal
             | OpCode::GetUpvalue
             | OpCode::SetUpvalue
-            | OpCode::Call => self.byte_instruction(opcode, offset, writer),
+            | OpCode::Call
+            | OpCode::PrintN
+            | OpCode::BuildList
+            | OpCode::BuildMap
+            | OpCode::Destructure => self.byte_instruction(opcode, offset, writer),
             OpCode::Return
             | OpCode::Print
             | OpCode::Pop
             | OpCode::Equal
             | OpCode::Less
             | OpCode::Greater
+            | OpCode::NotEqual
+            | OpCode::GreaterEqual
+            | OpCode::LessEqual
             | OpCode::Negate
             | OpCode::Not
             | OpCode::Add
             | OpCode::Subtract
             | OpCode::Multiply
             | OpCode::Divide
+            | OpCode::Modulo
             | OpCode::True
             | OpCode::False
             | OpCode::Nil
             | OpCode::CloseUpvalue
-            | OpCode::Inherit => self.simple_instruction(opcode, offset, writer),
+            | OpCode::Inherit
+            | OpCode::IndexGet
+            | OpCode::IndexSet => self.simple_instruction(opcode, offset, writer),
 
             OpCode::Jump | OpCode::JumpIfFalse => self.jump_instruction(opcode, offset, 1, writer),
             OpCode::Loop => self.jump_instruction(opcode, offset, -1, writer),
-            OpCode::Closure => self.closure(opcode, offset, writer),
-            OpCode::Invoke | OpCode::SuperInvoke => self.invoke_instruction(opcode, offset, writer),
+            OpCode::JumpLong | OpCode::JumpIfFalseLong => {
+                self.long_jump_instruction(opcode, offset, 1, writer)
+            }
+            OpCode::LoopLong => self.long_jump_instruction(opcode, offset, -1, writer),
+            OpCode::GetLocalLong | OpCode::SetLocalLong => {
+                self.short_instruction(opcode, offset, writer)
+            }
+            OpCode::Closure | OpCode::ClosureLong => self.closure(opcode, offset, writer),
+            OpCode::Invoke | OpCode::SuperInvoke | OpCode::InvokeLong | OpCode::SuperInvokeLong => {
+                self.invoke_instruction(opcode, offset, writer)
+            }
         }
     }
 
+    fn short_instruction(
+        &self,
+        opcode: OpCode,
+        offset: usize,
+        writer: &mut impl Write,
+    ) -> Result<usize, std::io::Error> {
+        // Safety: We know that the instruction at offset takes a two byte index, so the code units
+        // at offset + 1 and offset + 2 have to be indexes.
+        let high = unsafe { self.code[offset + 1].get_index() } as u16;
+        let low = unsafe { self.code[offset + 2].get_index() } as u16;
+        writeln!(writer, "{:-16} {:4}", opcode, (high << 8) + low).map(|_| offset + 3)
+    }
+
     fn byte_instruction(
         &self,
         opcode: OpCode,
@@ -313,14 +447,11 @@ impl Chunk {
         offset: usize,
         writer: &mut impl Write,
     ) -> Result<usize, std::io::Error> {
-        let code_unit = self.code[offset + 1];
-
         // Safety: We know that the instruction at offset is a constant instruction.
-        // That instruction requires exactly one index, the code unit at offset + 1 has to be an
-        // index.
-        let index = unsafe { code_unit.get_index() };
-        let value = &self.constants[index as usize];
-        writeln!(writer, "{:-16} {:4} '{}'", opcode, index, value).map(|_| offset + 2)
+        // That instruction requires the index of a constant as its only operand.
+        let (index, next) = unsafe { self.constant_index(offset) };
+        let value = &self.constants[index];
+        writeln!(writer, "{:-16} {:4} '{}'", opcode, index, value).map(|_| next)
     }
 
     fn invoke_instruction(
@@ -329,20 +460,17 @@ impl Chunk {
         offset: usize,
         writer: &mut impl Write,
     ) -> Result<usize, std::io::Error> {
-        let constant = self.code[offset + 1];
-        let arg_count = self.code[offset + 2];
-
-        // Safety: We know that the instruction at offset is the invoke instruction.
-        // That instruction requires exactly two indexes
-        let constant = unsafe { constant.get_index() };
-        let arg_count = unsafe { arg_count.get_index() };
-        let value = &self.constants[constant as usize];
+        // Safety: We know that the instruction at offset is an invoke instruction.
+        // That instruction requires the index of a constant followed by the argument count.
+        let (constant, next) = unsafe { self.constant_index(offset) };
+        let arg_count = unsafe { self.code[next].get_index() };
+        let value = &self.constants[constant];
         writeln!(
             writer,
             "{:-16} ({} args) {:4} '{}'",
             opcode, arg_count, constant, value
         )
-        .map(|_| offset + 3)
+        .map(|_| next + 1)
     }
 
     fn jump_instruction(
@@ -366,6 +494,23 @@ impl Chunk {
         writeln!(writer, "{:-16} {:4} -> {}", opcode, offset, dest).map(|_| offset + 3)
     }
 
+    fn long_jump_instruction(
+        &self,
+        opcode: OpCode,
+        offset: usize,
+        sign: isize,
+        writer: &mut impl Write,
+    ) -> Result<usize, std::io::Error> {
+        // Safety: We know that the instruction at offset is a long jump instruction.
+        // That instruction requires exactly three indexes, so the code units at offset + 1 up to
+        // offset + 3 have to be indexes.
+        let jump = (1..=3).fold(0u32, |jump, i| {
+            (jump << 8) + unsafe { self.code[offset + i].get_index() } as u32
+        });
+        let dest = (offset as isize + (sign * (jump as isize)) + 4) as usize;
+        writeln!(writer, "{:-16} {:4} -> {}", opcode, offset, dest).map(|_| offset + 4)
+    }
+
     fn simple_instruction(
         &self,
         opcode: OpCode,
@@ -381,22 +526,20 @@ impl Chunk {
         offset: usize,
         writer: &mut impl Write,
     ) -> Result<usize, std::io::Error> {
-        let mut o = offset + 1;
-        let code_unit = self.code[o];
-        o += 1;
-
-        let index = unsafe { code_unit.get_index() };
-        let value = &self.constants[index as usize];
+        let (index, mut o) = unsafe { self.constant_index(offset) };
+        let value = &self.constants[index];
         writeln!(writer, "{:-16}  {:4} '{}'", opcode, index, value)?;
 
         if let Value::Function(fun) = value {
-            for _ in 0..fun.get_upvalue_count() {
+            // Each upvalue is listed on its own line, with the | in the line column like an
+            // instruction continuing the line before.
+            for i in 0..fun.get_upvalue_count() {
                 let is_local = unsafe { self.code[o].get_index() };
                 let is_local = is_local != 0;
 
                 let index = unsafe { self.code[o + 1].get_index() };
                 let kind = if is_local { "local" } else { "upvalue" };
-                writeln!(writer, "{:04}    |{}{} {}", o, " ".repeat(17), kind, index)?;
+                writeln!(writer, "{:04}    | upvalue[{}] {} {}", o, i, kind, index)?;
                 o += 2;
             }
         } else {
@@ -407,13 +550,673 @@ impl Chunk {
     }
 }
 
+/// The first bytes of every serialized chunk.
+const MAGIC: &[u8; 4] = b"rlox";
+
+/// The version of the format written by Chunk::serialize(). It has to be increased whenever the
+/// format or the instruction set changes, so that old blobs are rejected instead of misread.
+pub const FORMAT_VERSION: u8 = 12;
+
+const CONSTANT_DOUBLE: u8 = 0;
+const CONSTANT_STRING: u8 = 1;
+const CONSTANT_FUNCTION: u8 = 2;
+
+// Serialization of a chunk.
+//
+// A serialized chunk starts with MAGIC and FORMAT_VERSION, followed by the chunk itself:
+// the code units, the constants and the line information, each prefixed with their count.
+// Integers are written as little endian u32 and strings as their length followed by their UTF-8
+// bytes. A function constant holds its name, arity, upvalue count, kind and its own chunk.
+impl Chunk {
+    /// Writes this chunk, including the chunks of the functions in its constants, into bytes that
+    /// can be loaded again with Chunk::deserialize().
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(FORMAT_VERSION);
+        self.write_to(&mut bytes);
+        bytes
+    }
+
+    /// Loads a chunk written by Chunk::serialize(). Strings are interned in the given symbol table,
+    /// which has to be the one of the VM that runs the chunk.
+    /// The code is checked to consist of valid instructions and every jump is checked to land on an
+    /// instruction, so that running it can not make the VM misinterpret an index as an opcode.
+    /// The code of each function is then verified like described at Chunk::verify(), so that it
+    /// can not pop an empty stack or refer to missing constants, locals or upvalues.
+    pub(crate) fn deserialize(
+        bytes: &[u8],
+        symbol_table: &mut SymbolTable,
+    ) -> Result<Chunk, DeserializeError> {
+        let mut reader = Reader::new(bytes);
+        if reader.read_bytes(MAGIC.len())? != MAGIC {
+            return Err(DeserializeError::NotAChunk);
+        }
+        let version = reader.read_u8()?;
+        if version != FORMAT_VERSION {
+            return Err(DeserializeError::VersionMismatch(version));
+        }
+
+        let chunk = Chunk::read_from(&mut reader, symbol_table, &IndexesPerOpCode::new())?;
+        // The frame of the script holds its closure.
+        chunk.verify(FunctionType::Script, 1, 0)?;
+        if reader.is_at_end() {
+            Ok(chunk)
+        } else {
+            Err(DeserializeError::Invalid(String::from(
+                "Unexpected bytes after the chunk.",
+            )))
+        }
+    }
+
+    fn write_to(&self, bytes: &mut Vec<u8>) {
+        write_u32(bytes, self.code.len());
+        // Safety: Every code unit is a single byte and every opcode is a valid u8.
+        bytes.extend(self.code.iter().map(|unit| unsafe { unit.get_index() }));
+
+        write_u32(bytes, self.constants.len());
+        for constant in self.constants.iter() {
+            match constant {
+                Value::Double(d) => {
+                    bytes.push(CONSTANT_DOUBLE);
+                    bytes.extend_from_slice(&d.to_le_bytes());
+                }
+                Value::String(s) => {
+                    bytes.push(CONSTANT_STRING);
+                    write_string(bytes, s);
+                }
+                Value::Function(function) => {
+                    bytes.push(CONSTANT_FUNCTION);
+                    match function.get_name() {
+                        Some(name) => {
+                            bytes.push(1);
+                            write_string(bytes, name);
+                        }
+                        None => bytes.push(0),
+                    }
+                    write_u32(bytes, function.get_arity());
+                    write_u32(bytes, function.get_upvalue_count());
+                    bytes.push(match function.get_kind() {
+                        FunctionType::Function => 0,
+                        FunctionType::Script => 1,
+                        FunctionType::Method => 2,
+                        FunctionType::Initializer => 3,
+                        FunctionType::Getter => 4,
+                        FunctionType::StaticMethod => 5,
+                    });
+                    function.get_chunk().write_to(bytes);
+                }
+                _ => unreachable!("Only numbers, strings and functions are stored as constants."),
+            }
+        }
+
+        write_u32(bytes, self.lines.len());
+        for info in self.lines.iter() {
+            bytes.extend_from_slice(&info.line().to_le_bytes());
+            bytes.extend_from_slice(&info.count().to_le_bytes());
+        }
+    }
+
+    fn read_from(
+        reader: &mut Reader,
+        symbol_table: &mut SymbolTable,
+        indexes_per_op: &IndexesPerOpCode,
+    ) -> Result<Chunk, DeserializeError> {
+        let code_len = reader.read_u32()? as usize;
+        let code = reader.read_bytes(code_len)?;
+
+        let constant_count = reader.read_u32()?;
+        let mut constants = Vec::new();
+        for _ in 0..constant_count {
+            constants.push(read_constant(reader, symbol_table, indexes_per_op)?);
+        }
+
+        let line_count = reader.read_u32()?;
+        let mut lines = Vec::new();
+        for _ in 0..line_count {
+            let line = reader.read_u32()?;
+            let count = reader.read_u32()?;
+            lines.push(LineInfo::new(line, count));
+        }
+
+        let mut chunk = Chunk {
+            code: Vec::with_capacity(code.len()),
+            constants,
+            lines,
+        };
+        let mut jumps: Vec<(usize, OpCode, usize)> = Vec::new();
+        let mut is_instruction = vec![false; code.len()];
+        while chunk.code.len() < code.len() {
+            let offset = chunk.code.len();
+            is_instruction[offset] = true;
+            let opcode = read_opcode(code[offset])?;
+            chunk.code.push(CodeUnit::from(opcode));
+            let len = match opcode {
+                OpCode::Closure | OpCode::ClosureLong => match closure_constant_index(code, offset)
+                    .map(|(index, len)| (chunk.constants.get(index), len))
+                {
+                    Some((Some(Value::Function(function)), len)) => {
+                        len + 2 * function.get_upvalue_count()
+                    }
+                    _ => {
+                        return Err(DeserializeError::Invalid(String::from(
+                            "A closure instruction does not refer to a function.",
+                        )))
+                    }
+                },
+                opcode => 1 + indexes_per_op.get(opcode) as usize,
+            };
+            if offset + len > code.len() {
+                return Err(DeserializeError::Invalid(format!(
+                    "The {} instruction at offset {} is cut off.",
+                    opcode, offset
+                )));
+            }
+            chunk.code.extend(
+                code[offset + 1..offset + len]
+                    .iter()
+                    .map(|&i| CodeUnit::from(i)),
+            );
+            if matches!(
+                opcode,
+                OpCode::Jump
+                    | OpCode::JumpIfFalse
+                    | OpCode::Loop
+                    | OpCode::JumpLong
+                    | OpCode::JumpIfFalseLong
+                    | OpCode::LoopLong
+            ) {
+                jumps.push((offset, opcode, len));
+            }
+        }
+
+        for (offset, opcode, len) in jumps {
+            let target = jump_target(offset, opcode, &code[offset + 1..offset + len]);
+            if !target.is_some_and(|target| target < code.len() && is_instruction[target]) {
+                return Err(DeserializeError::Invalid(format!(
+                    "The {} instruction at offset {} does not jump to an instruction.",
+                    opcode, offset
+                )));
+            }
+        }
+
+        match chunk.lines.last() {
+            Some(info) if info.count() as usize == chunk.code.len() => Ok(chunk),
+            None if chunk.code.is_empty() => Ok(chunk),
+            _ => Err(DeserializeError::Invalid(String::from(
+                "The line information does not match the code.",
+            ))),
+        }
+    }
+}
+
+// Verification of a loaded chunk.
+//
+// The VM trusts the code it runs, e.g. that there is a value on the stack whenever one is popped
+// and that the index of a global refers to a string constant. Compiled code ensures this, loaded
+// code is checked by following every path through it, tracking the number of values on the stack of
+// the frame. The types of the values on the stack are not tracked.
+impl Chunk {
+    // Checks the code of a function whose frame starts with the given number of values:
+    // - every path through the code ends in a return,
+    // - each instruction runs with the same number of values on the stack on every path to it, and
+    //   with at least as many as it pops,
+    // - constants are in range and of the type the instruction expects,
+    // - local slots are on the stack and upvalue indexes are in range,
+    // - the script returns with only its closure left on the stack.
+    // Requires that the code consists of whole instructions and that every jump lands on one.
+    fn verify(
+        &self,
+        kind: FunctionType,
+        slots: usize,
+        upvalue_count: usize,
+    ) -> Result<(), DeserializeError> {
+        let instructions = self
+            .get_instructions()
+            .map(|instruction| (instruction.get_offset(), instruction))
+            .collect::<HashMap<usize, Instruction>>();
+        let mut depths: Vec<Option<usize>> = vec![None; self.code.len()];
+        let mut pending = vec![(0, slots)];
+        while let Some((offset, depth)) = pending.pop() {
+            let instruction = instructions.get(&offset).ok_or_else(|| {
+                DeserializeError::Invalid(String::from("The code ends without returning."))
+            })?;
+            let opcode = instruction.get_opcode();
+            match depths[offset] {
+                Some(known) if known == depth => continue,
+                Some(_) => {
+                    return Err(invalid_instruction(
+                        instruction,
+                        "is reached with different numbers of values on the stack",
+                    ))
+                }
+                None => depths[offset] = Some(depth),
+            }
+
+            let (pops, pushes) = self.stack_effect(instruction, depth, upvalue_count)?;
+            if pops > depth {
+                return Err(invalid_instruction(
+                    instruction,
+                    "pops more values than there are on the stack",
+                ));
+            }
+            let depth = depth - pops + pushes;
+
+            let next = offset + 1 + instruction.get_operands().len();
+            match opcode {
+                OpCode::Return if kind == FunctionType::Script && depth != 1 => {
+                    return Err(invalid_instruction(
+                        instruction,
+                        "leaves values on the stack of the script",
+                    ))
+                }
+                OpCode::Return => {}
+                OpCode::Jump | OpCode::JumpLong | OpCode::Loop | OpCode::LoopLong => {
+                    // The targets have been checked by Chunk::read_from().
+                    let target = jump_target(offset, opcode, instruction.get_operands());
+                    pending.push((target.unwrap(), depth));
+                }
+                OpCode::JumpIfFalse | OpCode::JumpIfFalseLong => {
+                    let target = jump_target(offset, opcode, instruction.get_operands());
+                    pending.push((target.unwrap(), depth));
+                    pending.push((next, depth));
+                }
+                _ => pending.push((next, depth)),
+            }
+        }
+        Ok(())
+    }
+
+    // Returns how many values the instruction pops off the stack and how many it pushes onto it,
+    // where a value that is only read counts as popped and pushed again. Checks the constants,
+    // local slots and upvalues the instruction refers to, given the number of values on the stack.
+    fn stack_effect(
+        &self,
+        instruction: &Instruction,
+        depth: usize,
+        upvalue_count: usize,
+    ) -> Result<(usize, usize), DeserializeError> {
+        let opcode = instruction.get_opcode();
+        let operands = instruction.get_operands();
+        let count = || operands[operands.len() - 1] as usize;
+        let constant = || {
+            let index = if opcode.has_long_constant_index() {
+                read_big_endian(&operands[..3])
+            } else {
+                operands[0] as usize
+            };
+            self.constants
+                .get(index)
+                .ok_or_else(|| invalid_instruction(instruction, "refers to a missing constant"))
+        };
+        let string = || match constant()? {
+            Value::String(_) => Ok(()),
+            _ => Err(invalid_instruction(
+                instruction,
+                "does not refer to a string constant",
+            )),
+        };
+        let local = |slot: usize| {
+            if slot < depth {
+                Ok(())
+            } else {
+                Err(invalid_instruction(
+                    instruction,
+                    "refers to a slot beyond the stack",
+                ))
+            }
+        };
+        let upvalue = |index: usize| {
+            if index < upvalue_count {
+                Ok(())
+            } else {
+                Err(invalid_instruction(
+                    instruction,
+                    "refers to a missing upvalue",
+                ))
+            }
+        };
+
+        let effect = match opcode {
+            OpCode::Constant | OpCode::ConstantLong => {
+                constant()?;
+                (0, 1)
+            }
+            OpCode::Nil | OpCode::True | OpCode::False => (0, 1),
+            OpCode::Negate | OpCode::Not => (1, 1),
+            OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Modulo
+            | OpCode::Equal
+            | OpCode::Greater
+            | OpCode::Less
+            | OpCode::NotEqual
+            | OpCode::GreaterEqual
+            | OpCode::LessEqual => (2, 1),
+            OpCode::Return | OpCode::Print | OpCode::Pop | OpCode::CloseUpvalue => (1, 0),
+            OpCode::PrintN => (count(), 0),
+            OpCode::DefineGlobal
+            | OpCode::DefineGlobalLong
+            | OpCode::DefineConstGlobal
+            | OpCode::DefineConstGlobalLong => {
+                string()?;
+                (1, 0)
+            }
+            OpCode::GetGlobal | OpCode::GetGlobalLong => {
+                string()?;
+                (0, 1)
+            }
+            OpCode::SetGlobal | OpCode::SetGlobalLong => {
+                string()?;
+                (1, 1)
+            }
+            OpCode::GetLocal | OpCode::GetLocalLong => {
+                local(read_big_endian(operands))?;
+                (0, 1)
+            }
+            OpCode::SetLocal | OpCode::SetLocalLong => {
+                local(read_big_endian(operands))?;
+                (1, 1)
+            }
+            OpCode::GetUpvalue => {
+                upvalue(operands[0] as usize)?;
+                (0, 1)
+            }
+            OpCode::SetUpvalue => {
+                upvalue(operands[0] as usize)?;
+                (1, 1)
+            }
+            OpCode::Jump | OpCode::JumpLong | OpCode::Loop | OpCode::LoopLong => (0, 0),
+            OpCode::JumpIfFalse | OpCode::JumpIfFalseLong => (1, 1),
+            OpCode::Call => (count() + 1, 1),
+            OpCode::Closure | OpCode::ClosureLong => {
+                let first = if opcode == OpCode::ClosureLong { 3 } else { 1 };
+                for pair in operands[first..].chunks_exact(2) {
+                    let index = pair[1] as usize;
+                    if pair[0] == 0 {
+                        upvalue(index)?;
+                    } else if index != depth {
+                        // A local function captures itself from the slot the closure is pushed to.
+                        local(index)?;
+                    }
+                }
+                (0, 1)
+            }
+            OpCode::Class | OpCode::ClassLong => {
+                string()?;
+                (0, 1)
+            }
+            OpCode::GetProperty | OpCode::GetPropertyLong => {
+                string()?;
+                (1, 1)
+            }
+            // SetProperty pops the value and the instance, Method and StaticMethod pop the method
+            // and the class below it, GetSuper pops the superclass and the instance.
+            OpCode::SetProperty
+            | OpCode::SetPropertyLong
+            | OpCode::Method
+            | OpCode::MethodLong
+            | OpCode::StaticMethod
+            | OpCode::StaticMethodLong
+            | OpCode::GetSuper
+            | OpCode::GetSuperLong => {
+                string()?;
+                (2, 1)
+            }
+            OpCode::Invoke | OpCode::InvokeLong => {
+                string()?;
+                (count() + 1, 1)
+            }
+            OpCode::SuperInvoke | OpCode::SuperInvokeLong => {
+                string()?;
+                (count() + 2, 1)
+            }
+            OpCode::Inherit => (2, 1),
+            OpCode::BuildList => (count(), 1),
+            OpCode::BuildMap => (2 * count(), 1),
+            OpCode::IndexGet => (2, 1),
+            OpCode::IndexSet => (3, 1),
+            OpCode::Destructure => (1, count()),
+        };
+        Ok(effect)
+    }
+}
+
+fn invalid_instruction(instruction: &Instruction, problem: &str) -> DeserializeError {
+    DeserializeError::Invalid(format!(
+        "The {} instruction at offset {} {}.",
+        instruction.get_opcode(),
+        instruction.get_offset(),
+        problem
+    ))
+}
+
+// Returns the index of the constant the closure instruction at the given offset of the code refers
+// to and the number of code units up to the upvalues, or None if the code ends before the index.
+fn closure_constant_index(code: &[u8], offset: usize) -> Option<(usize, usize)> {
+    if code[offset] == OpCode::ClosureLong as u8 {
+        let bytes = code.get(offset + 1..offset + 4)?;
+        Some((read_big_endian(bytes), 4))
+    } else {
+        code.get(offset + 1).map(|&i| (i as usize, 2))
+    }
+}
+
+// Returns the offset the jump instruction at the given offset with the given operands jumps to, or
+// None if it jumps before the start of the code.
+fn jump_target(offset: usize, opcode: OpCode, operands: &[u8]) -> Option<usize> {
+    let distance = read_big_endian(operands);
+    let next = offset + 1 + operands.len();
+    match opcode {
+        OpCode::Jump | OpCode::JumpIfFalse | OpCode::JumpLong | OpCode::JumpIfFalseLong => {
+            Some(next + distance)
+        }
+        OpCode::Loop | OpCode::LoopLong => next.checked_sub(distance),
+        _ => unreachable!("Only jumps have a jump target."),
+    }
+}
+
+// Combines the indexes of a short or long operand, which are stored high byte first.
+fn read_big_endian(indexes: &[u8]) -> usize {
+    indexes
+        .iter()
+        .fold(0usize, |value, &index| (value << 8) + index as usize)
+}
+
+fn write_u32(bytes: &mut Vec<u8>, value: usize) {
+    let value = u32::try_from(value).expect("Chunks are smaller than 4 GiB.");
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_string(bytes: &mut Vec<u8>, s: &str) {
+    write_u32(bytes, s.len());
+    bytes.extend_from_slice(s.as_bytes());
+}
+
+fn read_opcode(byte: u8) -> Result<OpCode, DeserializeError> {
+    if (byte as usize) < OpCode::LENGTH {
+        Ok(OpCode::from_usize(byte as usize))
+    } else {
+        Err(DeserializeError::Invalid(format!(
+            "Unknown opcode {}.",
+            byte
+        )))
+    }
+}
+
+fn read_constant(
+    reader: &mut Reader,
+    symbol_table: &mut SymbolTable,
+    indexes_per_op: &IndexesPerOpCode,
+) -> Result<Value, DeserializeError> {
+    match reader.read_u8()? {
+        CONSTANT_DOUBLE => Ok(Value::Double(reader.read_f64()?)),
+        CONSTANT_STRING => Ok(Value::String(reader.read_string(symbol_table)?)),
+        CONSTANT_FUNCTION => {
+            let name = match reader.read_u8()? {
+                0 => None,
+                1 => Some(reader.read_string(symbol_table)?),
+                _ => {
+                    return Err(DeserializeError::Invalid(String::from(
+                        "Invalid function name.",
+                    )))
+                }
+            };
+            let arity = reader.read_u32()? as usize;
+            let upvalue_count = reader.read_u32()? as usize;
+            let kind = match reader.read_u8()? {
+                0 => FunctionType::Function,
+                1 => FunctionType::Script,
+                2 => FunctionType::Method,
+                3 => FunctionType::Initializer,
+                4 => FunctionType::Getter,
+                5 => FunctionType::StaticMethod,
+                kind => {
+                    return Err(DeserializeError::Invalid(format!(
+                        "Unknown function kind {}.",
+                        kind
+                    )))
+                }
+            };
+            let chunk = Chunk::read_from(reader, symbol_table, indexes_per_op)?;
+            // The frame of a function holds its closure followed by the arguments.
+            chunk.verify(kind, 1 + arity, upvalue_count)?;
+            Ok(Value::Function(Function::new(
+                name,
+                arity,
+                chunk,
+                upvalue_count,
+                kind,
+            )))
+        }
+        tag => Err(DeserializeError::Invalid(format!(
+            "Unknown constant type {}.",
+            tag
+        ))),
+    }
+}
+
+/// An instruction of a chunk: The offset of its opcode, the opcode and the indexes following it.
+/// The closure instruction is followed by the index of its function and two indexes per upvalue.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Instruction {
+    offset: usize,
+    opcode: OpCode,
+    operands: Vec<u8>,
+}
+
+impl Instruction {
+    pub fn get_offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn get_opcode(&self) -> OpCode {
+        self.opcode
+    }
+
+    pub fn get_operands(&self) -> &[u8] {
+        &self.operands
+    }
+}
+
+/// Decodes the instructions of a chunk one after another.
+pub struct Instructions<'a> {
+    chunk: &'a Chunk,
+    offset: usize,
+    indexes_per_op: IndexesPerOpCode,
+}
+
+impl<'a> Iterator for Instructions<'a> {
+    type Item = Instruction;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset == self.chunk.code.len() {
+            return None;
+        }
+
+        let offset = self.offset;
+        // Safety: Chunks are made of whole instructions, so the offset after an instruction points
+        //         to the opcode of the next one. The code units following an opcode are indexes.
+        let (opcode, operands, len) = unsafe {
+            let len = self.chunk.instruction_len(offset, &self.indexes_per_op);
+            let operands = self.chunk.code[offset + 1..offset + len]
+                .iter()
+                .map(|code_unit| code_unit.get_index())
+                .collect();
+            (self.chunk.code[offset].get_opcode(), operands, len)
+        };
+        self.offset += len;
+        Some(Instruction {
+            offset,
+            opcode,
+            operands,
+        })
+    }
+}
+
+/// Reads the parts of a serialized chunk, failing with DeserializeError::Truncated if the bytes
+/// end too early.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes, position: 0 }
+    }
+
+    fn is_at_end(&self) -> bool {
+        self.position == self.bytes.len()
+    }
+
+    fn read_bytes(&mut self, count: usize) -> Result<&'a [u8], DeserializeError> {
+        let end = self
+            .position
+            .checked_add(count)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or(DeserializeError::Truncated)?;
+        let bytes = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, DeserializeError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_u32(&mut self) -> Result<u32, DeserializeError> {
+        let bytes = self.read_bytes(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn read_f64(&mut self) -> Result<f64, DeserializeError> {
+        let bytes = self.read_bytes(8)?;
+        Ok(f64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn read_string(&mut self, symbol_table: &mut SymbolTable) -> Result<Symbol, DeserializeError> {
+        let len = self.read_u32()? as usize;
+        let bytes = self.read_bytes(len)?;
+        match std::str::from_utf8(bytes) {
+            Ok(s) => Ok(symbol_table.intern_str(s)),
+            Err(_) => Err(DeserializeError::Invalid(String::from(
+                "A string is not valid UTF-8.",
+            ))),
+        }
+    }
+}
+
 /// ChunkBuilder is used to incrementally build a Chunk.
 /// It ensures that the Chunk is in a valid state once it is build.
 pub struct ChunkBuilderInner {
     chunk: Chunk,
     required_indexes: u8,
     indexes_per_op: IndexesPerOpCode,
-    patch_count: usize,
+    patches: Vec<Rc<Cell<usize>>>,
+    last_opcode: Option<(OpCode, usize)>,
 }
 
 impl ChunkBuilderInner {
@@ -422,7 +1225,8 @@ impl ChunkBuilderInner {
             chunk: Chunk::new(),
             required_indexes: 0,
             indexes_per_op: IndexesPerOpCode::new(),
-            patch_count: 0,
+            patches: Vec::new(),
+            last_opcode: None,
         }
     }
 
@@ -430,14 +1234,15 @@ impl ChunkBuilderInner {
     pub fn write_opcode(&mut self, opcode: OpCode, line: u32) -> usize {
         if self.required_indexes == 0 || self.required_indexes == u8::MAX {
             self.required_indexes = self.indexes_per_op.get(opcode);
-            self.chunk.write_opcode(opcode, line)
+            let position = self.chunk.write_opcode(opcode, line);
+            self.last_opcode = Some((opcode, position));
+            position
         } else {
             panic!("Requiring an index next.");
         }
     }
 
-    // In case we will support > 255 constants, make sure to take a larger index here and break it
-    // up into multiple u8 which can be written individually.
+    // Larger indexes are broken up into multiple u8, see write_short_index and write_long_index.
     pub fn write_index(&mut self, index: u8) {
         if self.required_indexes != 0 {
             self.chunk.write_index(index);
@@ -461,40 +1266,266 @@ impl ChunkBuilderInner {
         }
     }
 
+    pub fn write_long_address(&mut self, position: u32) {
+        if position > MAX_LONG_ADDRESS {
+            panic!("Address does not fit into three indexes.");
+        } else if self.required_indexes >= 3 {
+            self.chunk.write_index(((position & 0xff0000) >> 16) as u8);
+            self.chunk.write_index(((position & 0x00ff00) >> 8) as u8);
+            self.chunk.write_index((position & 0x0000ff) as u8);
+            self.required_indexes -= 3;
+        } else {
+            panic!("Do not require three indexes");
+        }
+    }
+
+    /// Writes the index of a long constant instruction, which is encoded like a long address.
+    /// Unlike an address it may be followed by further operands, e.g. the upvalues of a closure.
+    pub fn write_long_index(&mut self, index: u32) {
+        if index > MAX_LONG_ADDRESS {
+            panic!("Index does not fit into three indexes.");
+        } else if self.required_indexes >= 3 {
+            self.write_index(((index & 0xff0000) >> 16) as u8);
+            self.write_index(((index & 0x00ff00) >> 8) as u8);
+            self.write_index((index & 0x0000ff) as u8);
+        } else {
+            panic!("Do not require three indexes");
+        }
+    }
+
+    /// Writes the opcode and its operands, panicking if the opcode takes a different number of
+    /// operands. Closure takes a varying number of operands, so any number is accepted for it.
+    /// Returns the index of the opcode.
+    pub fn emit(&mut self, opcode: OpCode, operands: &[u8], line: u32) -> usize {
+        let expected = self.indexes_per_op.get(opcode);
+        if expected != u8::MAX && operands.len() != expected as usize {
+            panic!(
+                "{:?} takes {} operands, but {} were given.",
+                opcode,
+                expected,
+                operands.len()
+            );
+        }
+
+        let position = self.write_opcode(opcode, line);
+        for &operand in operands {
+            self.write_index(operand);
+        }
+        position
+    }
+
+    /// Writes the two byte index of a long local instruction, which is encoded like an address.
+    pub fn write_short_index(&mut self, index: u16) {
+        self.write_address(index)
+    }
+
     pub fn add_constant(&mut self, value: Value) -> usize {
         self.chunk.add_constant(value)
     }
 
+    pub fn get_last_opcode(&self) -> Option<OpCode> {
+        self.last_opcode
+            .filter(|(_, position)| position + 1 == self.chunk.len())
+            .map(|(opcode, _)| opcode)
+    }
+
+    pub fn replace_last_opcode(&mut self, opcode: OpCode) {
+        match self.last_opcode {
+            Some((last, position)) if position + 1 == self.chunk.len() => {
+                if self.indexes_per_op.get(last) != 0 || self.indexes_per_op.get(opcode) != 0 {
+                    panic!("Can only replace opcodes which do not take indexes.");
+                }
+
+                // Safety: The position points to the last opcode, which takes no indexes, as does
+                // the new opcode.
+                unsafe { self.chunk.write_opcode_at(opcode, position) };
+                self.last_opcode = Some((opcode, position));
+            }
+            _ => panic!("The last code unit is not an opcode."),
+        }
+    }
+
     pub fn build(mut self) -> Chunk {
-        if self.required_indexes == 0 && self.patch_count == 0 {
+        if self.required_indexes == 0 && self.patches.is_empty() {
             self.chunk.finish();
             self.chunk
         } else if self.required_indexes != 0 {
             panic!("Still requiring an index.");
-        } else if self.patch_count != 0 {
+        } else if !self.patches.is_empty() {
             panic!("There are patches that still need to be applied.");
         } else {
             unreachable!()
         }
     }
 
+    fn remove_patch(&mut self, location: &Rc<Cell<usize>>) {
+        self.patches.retain(|patch| !Rc::ptr_eq(patch, location));
+    }
+
+    // Turns the jump instruction whose patch is located at the given location into its long form and
+    // lets it jump by offset, which is given relative to the end of the short form.
+    // Widening a jump inserts a code unit into the already written code. Every other jump that
+    // jumps across the inserted code unit gets its offset adjusted, which in turn might require
+    // widening that jump as well. Patches that have not been applied yet are moved along with the
+    // code.
+    // Returns the position right after the widened jump instruction.
+    fn widen_jump(&mut self, location: &Rc<Cell<usize>>, offset: u32) -> usize {
+        let mut jumps = self.decode_jumps();
+        self.remove_patch(location);
+        let own = jumps.len();
+        let position = location.get() - 1;
+        jumps.push(Jump {
+            position,
+            target: position + 3 + offset as usize,
+            long: false,
+            forward: true,
+        });
+
+        while let Some(jump) = jumps
+            .iter_mut()
+            .find(|j| !j.long && j.offset() > u16::MAX as usize)
+        {
+            jump.long = true;
+            let inserted = jump.position + 3;
+
+            // Safety: The inserted index is placed right behind the two indexes of a jump.
+            unsafe { self.chunk.insert_index_at(0, inserted) };
+
+            for jump in jumps.iter_mut() {
+                if jump.position >= inserted {
+                    jump.position += 1;
+                }
+                if jump.target >= inserted {
+                    jump.target += 1;
+                }
+            }
+
+            for patch in self.patches.iter() {
+                if patch.get() >= inserted {
+                    patch.set(patch.get() + 1);
+                }
+            }
+        }
+
+        for jump in jumps.iter() {
+            // Safety: Each jump was decoded from an opcode at its position, possibly moved back
+            // along with the code. Long jumps got an additional index inserted after their
+            // original two indexes.
+            unsafe { self.write_jump(jump) };
+        }
+
+        let own = &jumps[own];
+        own.position + if own.long { 4 } else { 3 }
+    }
+
+    // Returns all jump instructions of the chunk whose offset has already been written.
+    fn decode_jumps(&self) -> Vec<Jump> {
+        let mut jumps = Vec::new();
+        let mut offset = 0;
+
+        while offset < self.chunk.len() {
+            // Safety: Offset starts out pointing at the first opcode and is always increased by
+            // the length of the instruction it points to, so it always points to an opcode.
+            let opcode = unsafe { self.chunk.code[offset].get_opcode() };
+            let pending = self.patches.iter().any(|p| p.get() == offset + 1);
+            let (long, forward) = match opcode {
+                OpCode::Jump | OpCode::JumpIfFalse => (false, true),
+                OpCode::Loop => (false, false),
+                OpCode::JumpLong | OpCode::JumpIfFalseLong => (true, true),
+                OpCode::LoopLong => (true, false),
+                _ => {
+                    offset += unsafe { self.chunk.instruction_len(offset, &self.indexes_per_op) };
+                    continue;
+                }
+            };
+
+            let width = if long { 3 } else { 2 };
+            if !pending {
+                // Safety: The indexes following a jump instruction encode its offset.
+                let distance = (1..=width).fold(0usize, |distance, i| {
+                    (distance << 8) + unsafe { self.chunk.code[offset + i].get_index() } as usize
+                });
+                let end = offset + 1 + width;
+                let target = if forward {
+                    end + distance
+                } else {
+                    end - distance
+                };
+                jumps.push(Jump {
+                    position: offset,
+                    target,
+                    long,
+                    forward,
+                });
+            }
+
+            offset += 1 + width;
+        }
+
+        jumps
+    }
+
+    // Safety: The position of the given jump has to point to a jump instruction which has enough
+    // indexes to hold the offset of the jump.
+    unsafe fn write_jump(&mut self, jump: &Jump) {
+        let opcode = match (self.chunk.code[jump.position].get_opcode(), jump.long) {
+            (OpCode::Jump | OpCode::JumpLong, true) => OpCode::JumpLong,
+            (OpCode::JumpIfFalse | OpCode::JumpIfFalseLong, true) => OpCode::JumpIfFalseLong,
+            (OpCode::Loop | OpCode::LoopLong, true) => OpCode::LoopLong,
+            (opcode, false) => opcode,
+            (opcode, _) => panic!("Expected a jump instruction but got {}.", opcode),
+        };
+        self.chunk.write_opcode_at(opcode, jump.position);
+
+        let offset = jump.offset();
+        if offset > MAX_LONG_ADDRESS as usize {
+            panic!("Jump offset does not fit into three indexes.");
+        }
+
+        let bytes = (offset as u32).to_be_bytes();
+        let width = if jump.long { 3 } else { 2 };
+        for (i, byte) in bytes[4 - width..].iter().enumerate() {
+            self.chunk.write_index_at(*byte, jump.position + 1 + i);
+        }
+    }
+
     /// Writes a disassemble of the chunk that's been build so far to stdout.
     /// Name is the name of this chunk.
+    #[allow(dead_code)]
     pub fn print_disassemble(&self, name: &str) -> std::io::Result<()> {
         self.chunk.print_disassemble(name)
     }
 }
 
+// A jump instruction of a chunk under construction, with its target as an absolute position.
+struct Jump {
+    position: usize,
+    target: usize,
+    long: bool,
+    forward: bool,
+}
+
+impl Jump {
+    fn offset(&self) -> usize {
+        let end = self.position + if self.long { 4 } else { 3 };
+        if self.forward {
+            self.target - end
+        } else {
+            end - self.target
+        }
+    }
+}
+
 pub struct Patch {
     builder: Rc<RefCell<ChunkBuilderInner>>,
-    location: usize,
+    location: Rc<Cell<usize>>,
 }
 
 /// A patch represents an position in code, which cannot be determined at the time the position
 /// needs to be written. In that case a patch can be created which let's the user write the position
 /// once the user knows it later.
 impl Patch {
-    fn new(builder: Rc<RefCell<ChunkBuilderInner>>, location: usize) -> Self {
+    fn new(builder: Rc<RefCell<ChunkBuilderInner>>, location: Rc<Cell<usize>>) -> Self {
         Patch { builder, location }
     }
 
@@ -506,15 +1537,30 @@ impl Patch {
         let high = ((position & 0xff00u16) >> 8) as u8;
         let low = (position & 0x00ffu16) as u8;
         let mut builder = self.builder.deref().borrow_mut();
-        builder.chunk.write_index_at(high, self.location);
-        builder.chunk.write_index_at(low, self.location + 1);
-        builder.patch_count -= 1;
+        builder.chunk.write_index_at(high, self.location.get());
+        builder.chunk.write_index_at(low, self.location.get() + 1);
+        builder.remove_patch(&self.location);
+    }
+
+    /// Turns the jump for which the Patch has been created into a long jump and writes the position
+    /// to it. The position is given relative to the end of the jump before it has been widened.
+    /// Widening the jump moves all code following the jump back by one, so the position of the
+    /// first code unit after the jump is returned.
+    /// Safety:
+    ///     The user has to make sure that the position is valid for the given instruction.
+    ///     That is the position has to point to a valid opcode in the code stream.
+    ///     The Patch has to be created for one of OpCode::Jump or OpCode::JumpIfFalse.
+    pub unsafe fn apply_long(self, position: u32) -> usize {
+        self.builder
+            .deref()
+            .borrow_mut()
+            .widen_jump(&self.location, position)
     }
 
     /// Returns the location of the this patch, that is the position in code at which the patch need
     /// to be applied.
     pub fn get_own_index(&self) -> usize {
-        self.location
+        self.location.get()
     }
 }
 
@@ -534,8 +1580,7 @@ impl ChunkBuilder {
         self.builder.deref().borrow_mut().write_opcode(opcode, line)
     }
 
-    // In case we will support > 255 constants, make sure to take a larger index here and break it
-    // up into multiple u8 which can be written individually.
+    // Larger indexes are broken up into multiple u8, see write_short_index and write_long_index.
     pub fn write_index(&mut self, index: u8) {
         self.builder.deref().borrow_mut().write_index(index)
     }
@@ -544,13 +1589,37 @@ impl ChunkBuilder {
         self.builder.deref().borrow_mut().write_address(position)
     }
 
+    pub fn write_long_address(&mut self, position: u32) {
+        self.builder
+            .deref()
+            .borrow_mut()
+            .write_long_address(position)
+    }
+
+    pub fn write_long_index(&mut self, index: u32) {
+        self.builder.deref().borrow_mut().write_long_index(index)
+    }
+
+    pub fn write_short_index(&mut self, index: u16) {
+        self.builder.deref().borrow_mut().write_short_index(index)
+    }
+
+    /// Writes the opcode and its operands in one go, see ChunkBuilderInner::emit().
+    #[allow(dead_code)]
+    pub fn emit(&mut self, opcode: OpCode, operands: &[u8], line: u32) -> usize {
+        self.builder
+            .deref()
+            .borrow_mut()
+            .emit(opcode, operands, line)
+    }
+
     pub fn write_patch(&mut self) -> Patch {
         let mut builder = self.builder.deref().borrow_mut();
         if builder.required_indexes >= 2 {
-            let location = builder.chunk.write_index(u8::MAX);
+            let location = Rc::new(Cell::new(builder.chunk.write_index(u8::MAX)));
             builder.chunk.write_index(u8::MAX);
             builder.required_indexes -= 2;
-            builder.patch_count += 1;
+            builder.patches.push(Rc::clone(&location));
             Patch::new(Rc::clone(&self.builder), location)
         } else {
             panic!("Requiring an opcode next.")
@@ -561,6 +1630,20 @@ impl ChunkBuilder {
         self.builder.deref().borrow_mut().add_constant(value)
     }
 
+    /// Returns the opcode that has just been written, if it is the last code unit in the chunk.
+    pub fn get_last_opcode(&self) -> Option<OpCode> {
+        self.builder.deref().borrow().get_last_opcode()
+    }
+
+    /// Overrides the opcode that has just been written. This is used for peephole optimizations.
+    /// Both the old and the new opcode must not take any indexes.
+    pub fn replace_last_opcode(&mut self, opcode: OpCode) {
+        self.builder
+            .deref()
+            .borrow_mut()
+            .replace_last_opcode(opcode)
+    }
+
     pub fn len(&self) -> usize {
         self.builder.deref().borrow().chunk.len()
     }
@@ -574,6 +1657,7 @@ impl ChunkBuilder {
 
     /// Writes a disassemble of the chunk that's been build so far to stdout.
     /// Name is the name of this chunk.
+    #[allow(dead_code)]
     pub fn print_disassemble(&self, name: &str) -> std::io::Result<()> {
         self.builder.deref().borrow().print_disassemble(name)
     }
@@ -581,8 +1665,14 @@ impl ChunkBuilder {
 
 #[cfg(test)]
 mod tests {
-    use crate::chunk::{ChunkBuilder, OpCode};
+    use crate::chunk::{Chunk, ChunkBuilder, OpCode, FORMAT_VERSION, MAGIC};
+    use crate::compile::Parser;
+    use crate::error::DeserializeError;
+    use crate::function::{Closure, Function, FunctionBuilder, FunctionType};
+    use crate::intern_string::SymbolTable;
+    use crate::scanner::Scanner;
     use crate::value::Value;
+    use crate::vm::VM;
 
     #[test]
     fn disassemble_constant() {
@@ -601,6 +1691,101 @@ mod tests {
         assert_eq!(result, "== test chunk ==\n0000    0 Constant    0 '2'\n")
     }
 
+    #[test]
+    fn decreasing_lines() {
+        let mut chunk_builder = ChunkBuilder::new();
+        chunk_builder.write_opcode(OpCode::Nil, 3);
+        chunk_builder.write_opcode(OpCode::GetLocal, u32::MAX);
+        chunk_builder.write_index(0);
+        chunk_builder.write_opcode(OpCode::Return, 4);
+        let chunk = chunk_builder.build();
+
+        assert_eq!(chunk.get_source_code_line(0), 3);
+        assert_eq!(chunk.get_source_code_line(1), u32::MAX);
+        assert_eq!(chunk.get_source_code_line(2), u32::MAX);
+        assert_eq!(chunk.get_source_code_line(3), 4);
+    }
+
+    #[test]
+    fn disassemble_closure() {
+        let mut symbol_table = SymbolTable::new();
+        let mut function = FunctionBuilder::new(
+            Some(symbol_table.intern_str("f")),
+            0,
+            FunctionType::Function,
+        );
+        function.write_opcode(OpCode::Nil, 1);
+        function.write_opcode(OpCode::Return, 1);
+        function.inc_upvalue_count();
+        function.inc_upvalue_count();
+
+        let mut chunk_builder = ChunkBuilder::new();
+        let index = chunk_builder.add_constant(Value::Function(function.build()));
+        chunk_builder.write_opcode(OpCode::Closure, 0);
+        chunk_builder.write_index(index as u8);
+        // The first upvalue captures local 1, the second upvalue 0 of the enclosing function.
+        chunk_builder.write_index(1);
+        chunk_builder.write_index(1);
+        chunk_builder.write_index(0);
+        chunk_builder.write_index(0);
+        chunk_builder.write_opcode(OpCode::Return, 0);
+
+        let mut buffer: Vec<u8> = Vec::new();
+        chunk_builder
+            .build()
+            .disassemble("test chunk", &mut buffer)
+            .unwrap();
+
+        let result = std::str::from_utf8(&buffer).expect("Just wrote a string into the buffer");
+        assert_eq!(
+            result,
+            "== test chunk ==\n\
+             0000    0 Closure     0 '<fn f>'\n\
+             0002    | upvalue[0] local 1\n\
+             0004    | upvalue[1] upvalue 0\n\
+             0006    | Return\n"
+        )
+    }
+
+    #[test]
+    fn disassemble_long_closure() {
+        let mut symbol_table = SymbolTable::new();
+        let mut function = FunctionBuilder::new(
+            Some(symbol_table.intern_str("f")),
+            0,
+            FunctionType::Function,
+        );
+        function.write_opcode(OpCode::Nil, 1);
+        function.write_opcode(OpCode::Return, 1);
+        function.inc_upvalue_count();
+
+        let mut chunk_builder = ChunkBuilder::new();
+        for i in 0..300 {
+            chunk_builder.add_constant(Value::Double(i as f64));
+        }
+        let index = chunk_builder.add_constant(Value::Function(function.build()));
+        chunk_builder.write_opcode(OpCode::ClosureLong, 0);
+        chunk_builder.write_long_index(index as u32);
+        chunk_builder.write_index(1);
+        chunk_builder.write_index(2);
+        chunk_builder.write_opcode(OpCode::Return, 0);
+
+        let mut buffer: Vec<u8> = Vec::new();
+        chunk_builder
+            .build()
+            .disassemble("test chunk", &mut buffer)
+            .unwrap();
+
+        let result = std::str::from_utf8(&buffer).expect("Just wrote a string into the buffer");
+        assert_eq!(
+            result,
+            "== test chunk ==\n\
+             0000    0 ClosureLong   300 '<fn f>'\n\
+             0004    | upvalue[0] local 2\n\
+             0006    | Return\n"
+        )
+    }
+
     macro_rules! test_stack_only_op {
         ($op:expr) => {{
             let op = $op;
@@ -623,9 +1808,49 @@ mod tests {
         test_stack_only_op!(OpCode::Negate);
         test_stack_only_op!(OpCode::Multiply);
         test_stack_only_op!(OpCode::Divide);
+        test_stack_only_op!(OpCode::Modulo);
         test_stack_only_op!(OpCode::Return);
     }
 
+    #[test]
+    fn emit_instructions() {
+        let mut chunk_builder = ChunkBuilder::new();
+        let index = chunk_builder.add_constant(Value::Double(2.0)) as u8;
+        assert_eq!(chunk_builder.emit(OpCode::Constant, &[index], 1), 0);
+        assert_eq!(chunk_builder.emit(OpCode::Jump, &[0, 1], 1), 2);
+        assert_eq!(chunk_builder.emit(OpCode::Negate, &[], 2), 5);
+        assert_eq!(chunk_builder.emit(OpCode::Return, &[], 2), 6);
+
+        let mut buffer: Vec<u8> = Vec::new();
+        chunk_builder
+            .build()
+            .disassemble("test chunk", &mut buffer)
+            .unwrap();
+        let result = std::str::from_utf8(&buffer).unwrap();
+        assert_eq!(
+            result,
+            "== test chunk ==\n\
+            0000    1 Constant    0 '2'\n\
+            0002    | Jump    2 -> 6\n\
+            0005    2 Negate\n\
+            0006    | Return\n"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Constant takes 1 operands, but 0 were given.")]
+    fn emit_too_few_operands() {
+        let mut chunk_builder = ChunkBuilder::new();
+        chunk_builder.emit(OpCode::Constant, &[], 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Return takes 0 operands, but 1 were given.")]
+    fn emit_too_many_operands() {
+        let mut chunk_builder = ChunkBuilder::new();
+        chunk_builder.emit(OpCode::Return, &[0], 1);
+    }
+
     #[test]
     #[should_panic]
     fn require_opcode_first() {
@@ -672,6 +1897,55 @@ mod tests {
         let _ = chunk_builder.build();
     }
 
+    #[test]
+    fn long_patch() {
+        let mut chunk_builder = ChunkBuilder::new();
+        chunk_builder.write_opcode(OpCode::JumpIfFalse, 0);
+        let short = chunk_builder.write_patch();
+        chunk_builder.write_opcode(OpCode::Jump, 0);
+        let long = chunk_builder.write_patch();
+        unsafe { short.apply(3) };
+
+        // The loop jumps back to the very first instruction using the largest short offset.
+        for _ in 6..65_532 {
+            chunk_builder.write_opcode(OpCode::Nil, 1);
+        }
+        chunk_builder.write_opcode(OpCode::Loop, 1);
+        chunk_builder.write_address(u16::MAX);
+        for _ in 0..5_000 {
+            chunk_builder.write_opcode(OpCode::Nil, 1);
+        }
+
+        let distance = chunk_builder.len() - long.get_own_index() - 2;
+        let end = unsafe { long.apply_long(distance as u32) };
+        assert_eq!(end, 7);
+        chunk_builder.write_opcode(OpCode::Return, 1);
+        let chunk = chunk_builder.build();
+
+        let indexes = |range: std::ops::Range<usize>| {
+            range
+                .map(|i| unsafe { chunk.get_code_unit(i).get_index() })
+                .collect::<Vec<u8>>()
+        };
+
+        unsafe {
+            assert_eq!(chunk.get_code_unit(0).get_opcode(), OpCode::JumpIfFalse);
+            assert_eq!(chunk.get_code_unit(3).get_opcode(), OpCode::JumpLong);
+            assert_eq!(chunk.get_code_unit(65_533).get_opcode(), OpCode::LoopLong);
+            assert_eq!(chunk.get_code_unit(70_537).get_opcode(), OpCode::Return);
+        }
+
+        // The short jump now has to jump over the additional index of the long jump.
+        assert_eq!(indexes(1..3), vec![0, 4]);
+        // The long jump still targets the return at the end: 70_537 - 7 = 0x01_13_82.
+        assert_eq!(indexes(4..7), vec![0x01, 0x13, 0x82]);
+        // The loop no longer fits a short offset and got widened: 65_537 - 0 = 0x01_00_01.
+        assert_eq!(indexes(65_534..65_537), vec![0x01, 0x00, 0x01]);
+
+        assert_eq!(chunk.get_source_code_line(6), 0);
+        assert_eq!(chunk.get_source_code_line(7), 1);
+    }
+
     #[test]
     #[should_panic]
     fn missing_patch() {
@@ -681,4 +1955,284 @@ mod tests {
         chunk_builder.write_opcode(OpCode::Return, 1);
         let _ = chunk_builder.build();
     }
+
+    // A script with number and string constants, jumps, a loop and a nested function that captures
+    // a local.
+    const SCRIPT: &str = "fun outer(a) {
+  var x = a + 0.5;
+  fun inner() { return x; }
+  return inner;
+}
+for (var i = 0; i < 2; i = i + 1) {
+  if (i == 0) print \"first\"; else print outer(i)();
+}";
+
+    fn compile(source: &str) -> (Closure, SymbolTable) {
+        let chars = source.chars().collect::<Vec<char>>();
+        let scanner = Scanner::new(chars.as_slice());
+        match Parser::new(scanner.parse(), std::io::sink()).compile() {
+            Ok((closure, symbol_table, _)) => (closure, symbol_table),
+            Err(_) => panic!("Expected the source to compile."),
+        }
+    }
+
+    // Disassembles the chunk and the chunks of all functions in its constants.
+    fn disassemble_all(chunk: &Chunk, name: &str, buffer: &mut Vec<u8>) {
+        chunk.disassemble(name, buffer).unwrap();
+        for constant in chunk.constants.iter() {
+            if let Value::Function(function) = constant {
+                disassemble_all(function.get_chunk(), &function.to_string(), buffer);
+            }
+        }
+    }
+
+    fn run(chunk: Chunk, symbol_table: SymbolTable) -> String {
+        let function = Function::new(None, 0, chunk, 0, FunctionType::Script);
+        match VM::with_write(Closure::new(function), symbol_table, Vec::new(), Vec::new())
+            .interpret()
+        {
+            Ok((output, _)) => String::from_utf8(output).unwrap(),
+            Err(_) => panic!("Expected the chunk to run."),
+        }
+    }
+
+    #[test]
+    fn serialize_round_trip() {
+        let (closure, _) = compile(SCRIPT);
+        let chunk = closure.get_function().get_chunk();
+        let bytes = chunk.serialize();
+
+        let mut symbol_table = SymbolTable::new();
+        let loaded = Chunk::deserialize(&bytes, &mut symbol_table).unwrap();
+
+        let mut expected = Vec::new();
+        disassemble_all(chunk, "script", &mut expected);
+        let mut actual = Vec::new();
+        disassemble_all(&loaded, "script", &mut actual);
+        assert_eq!(
+            String::from_utf8(actual).unwrap(),
+            String::from_utf8(expected).unwrap()
+        );
+        assert_eq!(loaded.get_source_code_line(loaded.code.len() - 1), 8);
+        assert_eq!(loaded.serialize(), bytes);
+
+        assert_eq!(run(loaded, symbol_table), "first\n1.5\n");
+    }
+
+    #[test]
+    fn constants() {
+        let (closure, _) = compile("var b = 1.5;\nprint \"a\" + \"a\";\nprint b + 1.5;");
+        let constants = closure
+            .get_function()
+            .get_chunk()
+            .get_constants()
+            .iter()
+            .map(|value| value.to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(constants, ["b", "1.5", "a"]);
+    }
+
+    #[test]
+    fn instructions() {
+        let (closure, _) = compile("{\n  var a = 1;\n  fun f() { return a; }\n  print f;\n}");
+        let instructions = closure
+            .get_function()
+            .get_chunk()
+            .get_instructions()
+            .map(|i| (i.get_offset(), i.get_opcode(), i.get_operands().to_vec()))
+            .collect::<Vec<_>>();
+
+        let expected = vec![
+            (0, OpCode::Constant, vec![0]),
+            // The index of the function, followed by is_local and the slot of the upvalue.
+            (2, OpCode::Closure, vec![1, 1, 1]),
+            (6, OpCode::GetLocal, vec![2]),
+            (8, OpCode::Print, vec![]),
+            (9, OpCode::Pop, vec![]),
+            (10, OpCode::CloseUpvalue, vec![]),
+            (11, OpCode::Nil, vec![]),
+            (12, OpCode::Return, vec![]),
+        ];
+        assert_eq!(instructions, expected);
+    }
+
+    #[test]
+    fn deserialize_truncated() {
+        let (closure, _) = compile(SCRIPT);
+        let bytes = closure.get_function().get_chunk().serialize();
+        for len in [0, 3, 5, bytes.len() / 2, bytes.len() - 1] {
+            assert_eq!(
+                Chunk::deserialize(&bytes[..len], &mut SymbolTable::new()).err(),
+                Some(DeserializeError::Truncated)
+            );
+        }
+    }
+
+    #[test]
+    fn deserialize_version_mismatch() {
+        let (closure, _) = compile(SCRIPT);
+        let mut bytes = closure.get_function().get_chunk().serialize();
+        bytes[MAGIC.len()] = FORMAT_VERSION + 1;
+        assert_eq!(
+            Chunk::deserialize(&bytes, &mut SymbolTable::new()).err(),
+            Some(DeserializeError::VersionMismatch(FORMAT_VERSION + 1))
+        );
+
+        bytes[0] = b'x';
+        assert_eq!(
+            Chunk::deserialize(&bytes, &mut SymbolTable::new()).err(),
+            Some(DeserializeError::NotAChunk)
+        );
+    }
+
+    #[test]
+    fn deserialize_unknown_opcode() {
+        let mut chunk_builder = ChunkBuilder::new();
+        chunk_builder.write_opcode(OpCode::Return, 0);
+        let mut bytes = chunk_builder.build().serialize();
+        // The single code unit follows the header and the code length.
+        bytes[MAGIC.len() + 1 + 4] = u8::MAX;
+        assert_eq!(
+            Chunk::deserialize(&bytes, &mut SymbolTable::new()).err(),
+            Some(DeserializeError::Invalid(String::from(
+                "Unknown opcode 255."
+            )))
+        );
+    }
+
+    #[test]
+    fn deserialize_jump_into_operand() {
+        let mut chunk_builder = ChunkBuilder::new();
+        let index = chunk_builder.add_constant(Value::Double(1.0)) as u8;
+        chunk_builder.emit(OpCode::Constant, &[index], 1);
+        // Jumps back to the index of the constant instruction at offset 1.
+        chunk_builder.emit(OpCode::Loop, &[0, 4], 1);
+        chunk_builder.emit(OpCode::Return, &[], 1);
+        let bytes = chunk_builder.build().serialize();
+        assert_eq!(
+            Chunk::deserialize(&bytes, &mut SymbolTable::new()).err(),
+            Some(DeserializeError::Invalid(String::from(
+                "The Loop instruction at offset 2 does not jump to an instruction."
+            )))
+        );
+
+        let mut chunk_builder = ChunkBuilder::new();
+        chunk_builder.emit(OpCode::Jump, &[0, 1], 1);
+        chunk_builder.emit(OpCode::Return, &[], 1);
+        let bytes = chunk_builder.build().serialize();
+        assert_eq!(
+            Chunk::deserialize(&bytes, &mut SymbolTable::new()).err(),
+            Some(DeserializeError::Invalid(String::from(
+                "The Jump instruction at offset 0 does not jump to an instruction."
+            )))
+        );
+    }
+
+    // Serializes the chunk written by the given function and returns the error of loading it again.
+    fn load_error(write: impl FnOnce(&mut ChunkBuilder)) -> String {
+        let mut chunk_builder = ChunkBuilder::new();
+        write(&mut chunk_builder);
+        let bytes = chunk_builder.build().serialize();
+        match Chunk::deserialize(&bytes, &mut SymbolTable::new()) {
+            Err(DeserializeError::Invalid(message)) => message,
+            _ => panic!("Expected the chunk to be rejected."),
+        }
+    }
+
+    #[test]
+    fn deserialize_stack_underflow() {
+        // The frame of the script holds only its closure.
+        let message = load_error(|chunk| {
+            chunk.emit(OpCode::Pop, &[], 1);
+            chunk.emit(OpCode::Pop, &[], 1);
+            chunk.emit(OpCode::Nil, &[], 1);
+            chunk.emit(OpCode::Return, &[], 1);
+        });
+        assert_eq!(
+            message,
+            "The Pop instruction at offset 1 pops more values than there are on the stack."
+        );
+
+        let message = load_error(|chunk| {
+            chunk.emit(OpCode::Nil, &[], 1);
+            chunk.emit(OpCode::Call, &[2], 1);
+            chunk.emit(OpCode::Return, &[], 1);
+        });
+        assert_eq!(
+            message,
+            "The Call instruction at offset 1 pops more values than there are on the stack."
+        );
+    }
+
+    #[test]
+    fn deserialize_inconsistent_stack() {
+        // The jump skips the pop of the condition, so Return is reached with it on the stack.
+        let message = load_error(|chunk| {
+            chunk.emit(OpCode::Nil, &[], 1);
+            chunk.emit(OpCode::True, &[], 1);
+            chunk.emit(OpCode::JumpIfFalse, &[0, 1], 1);
+            chunk.emit(OpCode::Pop, &[], 1);
+            chunk.emit(OpCode::Return, &[], 1);
+        });
+        assert_eq!(
+            message,
+            "The Return instruction at offset 6 is reached with different numbers of values on the \
+             stack."
+        );
+
+        let message = load_error(|chunk| {
+            chunk.emit(OpCode::Nil, &[], 1);
+            chunk.emit(OpCode::Nil, &[], 1);
+            chunk.emit(OpCode::Return, &[], 1);
+        });
+        assert_eq!(
+            message,
+            "The Return instruction at offset 2 leaves values on the stack of the script."
+        );
+
+        let message = load_error(|chunk| {
+            chunk.emit(OpCode::Nil, &[], 1);
+        });
+        assert_eq!(message, "The code ends without returning.");
+    }
+
+    #[test]
+    fn deserialize_invalid_operands() {
+        let message = load_error(|chunk| {
+            chunk.emit(OpCode::GetGlobal, &[0], 1);
+            chunk.emit(OpCode::Return, &[], 1);
+        });
+        assert_eq!(
+            message,
+            "The GetGlobal instruction at offset 0 refers to a missing constant."
+        );
+
+        let message = load_error(|chunk| {
+            let index = chunk.add_constant(Value::Double(1.0)) as u8;
+            chunk.emit(OpCode::GetGlobal, &[index], 1);
+            chunk.emit(OpCode::Return, &[], 1);
+        });
+        assert_eq!(
+            message,
+            "The GetGlobal instruction at offset 0 does not refer to a string constant."
+        );
+
+        let message = load_error(|chunk| {
+            chunk.emit(OpCode::GetLocal, &[1], 1);
+            chunk.emit(OpCode::Return, &[], 1);
+        });
+        assert_eq!(
+            message,
+            "The GetLocal instruction at offset 0 refers to a slot beyond the stack."
+        );
+
+        let message = load_error(|chunk| {
+            chunk.emit(OpCode::GetUpvalue, &[0], 1);
+            chunk.emit(OpCode::Return, &[], 1);
+        });
+        assert_eq!(
+            message,
+            "The GetUpvalue instruction at offset 0 refers to a missing upvalue."
+        );
+    }
 }
@@ -1,54 +1,65 @@
+//! This module contains all the structure need to implement classes, instances and binding methods
+//! to variables.
+
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::ops::Deref;
 use std::rc::Rc;
 
 use crate::function::Closure;
+use crate::gc::{Trace, Tracer};
 use crate::intern_string::Symbol;
 use crate::value::Value;
 
-/// This module contains all the structure need to implement classes, instances and binding methods
-/// to variables.
-
-/// A class has a name and any number of methods.
+/// A class has a name and any number of methods. Static methods are called on the class itself.
 #[derive(Debug)]
 pub struct Clazz {
     name: Symbol,
-    methods: HashMap<Symbol, Rc<Closure>>,
+    methods: HashMap<Symbol, Closure>,
+    static_methods: HashMap<Symbol, Closure>,
 }
 
 impl Clazz {
-    pub fn new(name: Symbol) -> Self {
+    pub(crate) fn new(name: Symbol) -> Self {
         Clazz {
             name,
             methods: HashMap::new(),
+            static_methods: HashMap::new(),
         }
     }
 
-    pub fn get_name(&self) -> &Symbol {
+    pub(crate) fn get_name(&self) -> &Symbol {
         &self.name
     }
 
-    pub fn set_method(&mut self, name: Symbol, value: Closure) {
-        self.methods.insert(name, Rc::new(value));
-    }
-
-    pub fn set_method_ref(&mut self, name: Symbol, value: Rc<Closure>) {
+    pub(crate) fn set_method(&mut self, name: Symbol, value: Closure) {
         self.methods.insert(name, value);
     }
 
-    pub fn get_method(&self, name: &Symbol) -> Option<Rc<Closure>> {
-        self.methods.get(name).map(Rc::clone)
+    pub(crate) fn get_method(&self, name: &Symbol) -> Option<Closure> {
+        self.methods.get(name).cloned()
     }
 
-    pub fn get_methods(&self) -> impl ExactSizeIterator<Item = (&Symbol, &Rc<Closure>)> {
+    pub(crate) fn get_methods(&self) -> impl ExactSizeIterator<Item = (&Symbol, &Closure)> {
         self.methods.iter()
     }
+
+    pub(crate) fn set_static_method(&mut self, name: Symbol, value: Closure) {
+        self.static_methods.insert(name, value);
+    }
+
+    pub(crate) fn get_static_method(&self, name: &Symbol) -> Option<Closure> {
+        self.static_methods.get(name).cloned()
+    }
+
+    pub(crate) fn get_static_methods(&self) -> impl ExactSizeIterator<Item = (&Symbol, &Closure)> {
+        self.static_methods.iter()
+    }
 }
 
 impl std::fmt::Display for Clazz {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
-        f.write_str(self.name.to_string().as_str())
+        f.write_str(self.name.as_str())
     }
 }
 
@@ -61,23 +72,50 @@ pub struct ClazzRef {
 }
 
 impl ClazzRef {
-    pub fn new(clazz: Clazz) -> Self {
+    #[allow(dead_code)]
+    pub(crate) fn new(clazz: Clazz) -> Self {
+        #[cfg(feature = "profile_allocations")]
+        crate::allocations::count_class();
+
         ClazzRef {
             clazz: Rc::new(RefCell::new(clazz)),
         }
     }
 
-    pub fn get_clazz(&self) -> std::cell::Ref<'_, Clazz> {
+    pub(crate) fn get_clazz(&self) -> std::cell::Ref<'_, Clazz> {
         self.clazz.deref().borrow()
     }
 
-    pub fn get_clazz_mut(&mut self) -> std::cell::RefMut<'_, Clazz> {
+    pub(crate) fn get_clazz_mut(&mut self) -> std::cell::RefMut<'_, Clazz> {
         self.clazz.deref().borrow_mut()
     }
+
+    pub(crate) fn traced(&self) -> Rc<dyn Trace> {
+        self.clazz.clone()
+    }
+}
+
+impl Trace for RefCell<Clazz> {
+    fn trace(&self, tracer: &mut Tracer) {
+        let clazz = self.borrow();
+        for method in clazz.methods.values().chain(clazz.static_methods.values()) {
+            tracer.mark_closure(method);
+        }
+    }
+
+    fn clear(&self) {
+        if let Ok(mut clazz) = self.try_borrow_mut() {
+            clazz.methods.clear();
+            clazz.static_methods.clear();
+        }
+    }
 }
 
 impl From<Clazz> for ClazzRef {
     fn from(clazz: Clazz) -> Self {
+        #[cfg(feature = "profile_allocations")]
+        crate::allocations::count_class();
+
         ClazzRef {
             clazz: Rc::new(RefCell::new(clazz)),
         }
@@ -106,22 +144,22 @@ pub struct Instance {
 }
 
 impl Instance {
-    pub fn new(clazz: ClazzRef) -> Self {
+    pub(crate) fn new(clazz: ClazzRef) -> Self {
         Instance {
             clazz,
             fields: HashMap::new(),
         }
     }
 
-    pub fn get_value(&self, property: &Symbol) -> Option<&Value> {
+    pub(crate) fn get_value(&self, property: &Symbol) -> Option<&Value> {
         self.fields.get(property)
     }
 
-    pub fn set_value(&mut self, name: Symbol, value: Value) {
+    pub(crate) fn set_value(&mut self, name: Symbol, value: Value) {
         self.fields.insert(name, value);
     }
 
-    pub fn get_clazz_ref(&self) -> &ClazzRef {
+    pub(crate) fn get_clazz_ref(&self) -> &ClazzRef {
         &self.clazz
     }
 }
@@ -141,23 +179,51 @@ pub struct InstanceRef {
 }
 
 impl InstanceRef {
-    pub fn new(instance: Instance) -> Self {
+    #[allow(dead_code)]
+    pub(crate) fn new(instance: Instance) -> Self {
+        #[cfg(feature = "profile_allocations")]
+        crate::allocations::count_instance();
+
         InstanceRef {
             instance: Rc::new(RefCell::new(instance)),
         }
     }
 
-    pub fn get_instance(&self) -> std::cell::Ref<'_, Instance> {
+    pub(crate) fn get_instance(&self) -> std::cell::Ref<'_, Instance> {
         self.instance.deref().borrow()
     }
 
-    pub fn get_instance_mut(&mut self) -> std::cell::RefMut<'_, Instance> {
+    pub(crate) fn get_instance_mut(&mut self) -> std::cell::RefMut<'_, Instance> {
         self.instance.deref().borrow_mut()
     }
+
+    pub(crate) fn traced(&self) -> Rc<dyn Trace> {
+        self.instance.clone()
+    }
+}
+
+impl Trace for RefCell<Instance> {
+    fn trace(&self, tracer: &mut Tracer) {
+        let instance = self.borrow();
+        tracer.mark_value(&Value::Class(instance.clazz.clone()));
+        for value in instance.fields.values() {
+            tracer.mark_value(value);
+        }
+    }
+
+    // The class is kept, so that the instance still prints as before.
+    fn clear(&self) {
+        if let Ok(mut instance) = self.try_borrow_mut() {
+            instance.fields.clear();
+        }
+    }
 }
 
 impl From<Instance> for InstanceRef {
     fn from(instance: Instance) -> Self {
+        #[cfg(feature = "profile_allocations")]
+        crate::allocations::count_instance();
+
         InstanceRef {
             instance: Rc::new(RefCell::new(instance)),
         }
@@ -166,6 +232,9 @@ impl From<Instance> for InstanceRef {
 
 impl From<ClazzRef> for InstanceRef {
     fn from(clazz: ClazzRef) -> Self {
+        #[cfg(feature = "profile_allocations")]
+        crate::allocations::count_instance();
+
         InstanceRef {
             instance: Rc::new(RefCell::new(Instance::new(clazz))),
         }
@@ -186,43 +255,38 @@ impl std::fmt::Display for InstanceRef {
     }
 }
 
-/// Lox supports reference to methods, capturing the 'this' instance, on which the method should be
-/// invoked.
 #[derive(Debug)]
+struct BoundMethodInner {
+    receiver: Value,
+    method: Closure,
+}
+
+/// Lox supports reference to methods, capturing the 'this' instance, on which the method should be
+/// invoked. Each binding creates a new bound method, which is only equal to its clones.
+#[derive(Clone, Debug)]
 pub struct BoundMethod {
-    receiver: Box<Value>,
-    method: Rc<Closure>,
+    inner: Rc<BoundMethodInner>,
 }
 
 impl BoundMethod {
-    pub fn new(receiver: Value, method: Rc<Closure>) -> Self {
+    pub(crate) fn new(receiver: Value, method: Closure) -> Self {
         BoundMethod {
-            receiver: Box::new(receiver),
-            method: Rc::new(Closure::clone(method.deref())),
+            inner: Rc::new(BoundMethodInner { receiver, method }),
         }
     }
 
-    pub fn get_closure(&self) -> &Closure {
-        self.method.as_ref()
+    pub(crate) fn get_closure(&self) -> &Closure {
+        &self.inner.method
     }
 
-    pub fn get_receiver(&self) -> &Value {
-        self.receiver.as_ref()
-    }
-}
-
-impl Clone for BoundMethod {
-    fn clone(&self) -> Self {
-        BoundMethod {
-            receiver: self.receiver.clone(),
-            method: Rc::clone(&self.method),
-        }
+    pub(crate) fn get_receiver(&self) -> &Value {
+        &self.inner.receiver
     }
 }
 
 impl PartialEq for BoundMethod {
     fn eq(&self, other: &BoundMethod) -> bool {
-        Rc::ptr_eq(&self.method, &other.method) && self.receiver == other.receiver
+        Rc::ptr_eq(&self.inner, &other.inner)
     }
 }
 
@@ -230,6 +294,6 @@ impl Eq for BoundMethod {}
 
 impl std::fmt::Display for BoundMethod {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
-        write!(f, "{}", self.method)
+        write!(f, "{}", self.inner.method)
     }
 }
@@ -1,20 +1,30 @@
+use std::collections::HashSet;
 use std::io::Write;
-use std::ops::DerefMut;
+use std::ops::{DerefMut, Range};
 
-use crate::chunk::{ChunkBuilder, Patch};
+use crate::chunk::{ChunkBuilder, Patch, MAX_LONG_ADDRESS};
+use crate::error::{CompileError, CompileWarning, ErrorLocation};
 use crate::function::{Closure, Function, FunctionBuilder, FunctionType};
 use crate::intern_string::SymbolTable;
 use crate::opcodes::OpCode;
 use crate::tokens::{Token, TokenType};
 use crate::value::Value;
 
+const ANONYMOUS: [char; 9] = ['a', 'n', 'o', 'n', 'y', 'm', 'o', 'u', 's'];
+const INIT: [char; 4] = ['i', 'n', 'i', 't'];
 const SUPER: [char; 5] = ['s', 'u', 'p', 'e', 'r'];
 const THIS: [char; 4] = ['t', 'h', 'i', 's'];
 
-macro_rules! emit_opcodes {
-        ($instance:ident, $($opcode:expr $(,)?),+ $(,)?) => {{
-              $($instance.emit_opcode($opcode);)+
-        }};
+// The symbol table is also returned if compiling fails, so that the REPL can keep using it.
+pub type CompileResult<W> = Result<(Closure, SymbolTable, W), (Vec<CompileError>, SymbolTable, W)>;
+
+/// What a top-level declaration of a script declares. Statements are declarations too.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DeclarationKind {
+    Class,
+    Function,
+    Variable,
+    Statement,
 }
 
 pub struct Parser<'a, I: Iterator<Item = Token<'a>>, W: Write> {
@@ -27,44 +37,142 @@ pub struct Parser<'a, I: Iterator<Item = Token<'a>>, W: Write> {
     symbol_table: SymbolTable,
     compilers: Vec<Compiler<'a>>,
     class_compilers: Vec<ClassCompiler>,
+    errors: Vec<CompileError>,
     error_writer: W,
+    // The names of the globals declared with const. Assigning to them is a compile error.
+    const_globals: HashSet<&'a [char]>,
+    // The range of characters in the source each top-level declaration spans.
+    declarations: Vec<(Range<usize>, DeclarationKind)>,
+    // The names of the locals of the functions compiled so far which are never used.
+    unused_locals: Vec<Token<'a>>,
+    // In the REPL an expression statement at the end of the input does not need a ';' and prints
+    // its value.
+    repl: bool,
+    // Whether compile() writes the warnings to the error writer.
+    report_warnings: bool,
 }
 
 impl<'a, I: Iterator<Item = Token<'a>>, W: Write> Parser<'a, I, W> {
     pub fn new(source: I, error_writer: W) -> Self {
+        Parser::create(source, error_writer, SymbolTable::new(), false)
+    }
+
+    /// Creates a parser which interns names using the given symbol table. This is needed to run
+    /// the compiled script in a VM which already ran other scripts, as the VM would not find the
+    /// globals defined by them otherwise.
+    pub fn with_symbol_table(source: I, error_writer: W, symbol_table: SymbolTable) -> Self {
+        Parser::create(source, error_writer, symbol_table, false)
+    }
+
+    /// Creates a parser for a line entered into the REPL. The symbol table has to be the one of the
+    /// VM the line is run in, so that globals are found by the following lines.
+    pub fn for_repl(source: I, error_writer: W, symbol_table: SymbolTable) -> Self {
+        Parser::create(source, error_writer, symbol_table, true)
+    }
+
+    fn create(source: I, error_writer: W, symbol_table: SymbolTable, repl: bool) -> Self {
         let mut parser = Parser {
             source,
-            current: Token::new(TokenType::Error, &[], 0),
-            previous: Token::new(TokenType::Error, &[], 0),
+            current: Token::new(TokenType::Error, &[], 0, 0, 0),
+            previous: Token::new(TokenType::Error, &[], 0, 0, 0),
             had_error: false,
             panic_mode: false,
             rules: ParseRules::new(),
-            symbol_table: SymbolTable::new(),
+            symbol_table,
             compilers: Vec::new(),
             class_compilers: Vec::new(),
+            errors: Vec::new(),
             error_writer,
+            const_globals: HashSet::new(),
+            declarations: Vec::new(),
+            unused_locals: Vec::new(),
+            repl,
+            report_warnings: false,
         };
         parser.compilers.push(Compiler::new(FunctionType::Script));
         parser.advance();
         parser
     }
 
-    pub fn compile(mut self) -> Result<(Closure, SymbolTable, W), W> {
+    /// Makes compile() write a warning for each local variable which is never read to the error
+    /// writer, after the script compiled without errors.
+    pub fn with_warnings(mut self) -> Self {
+        self.report_warnings = true;
+        self
+    }
+
+    pub fn compile(mut self) -> CompileResult<W> {
         while !self.matches(TokenType::EOF) {
-            self.declaration();
+            self.top_level_declaration();
         }
 
         let function = self.end_compile();
 
         if self.had_error {
-            Err(self.error_writer)
+            Err((self.errors, self.symbol_table, self.error_writer))
         } else {
+            if self.report_warnings {
+                for warning in self.unused_local_warnings() {
+                    writeln!(self.error_writer, "{}", warning).unwrap();
+                }
+            }
             Ok((Closure::new(function), self.symbol_table, self.error_writer))
         }
     }
+
+    /// Parses the whole source and returns the range of characters and the kind of each top-level
+    /// declaration in it. Errors are reported as usual, but the declarations are returned anyway,
+    /// a declaration with an error extends up to where the parser recovered.
+    pub fn declarations(mut self) -> Vec<(Range<usize>, DeclarationKind)> {
+        while !self.matches(TokenType::EOF) {
+            self.top_level_declaration();
+        }
+        self.declarations
+    }
+
+    /// Parses the whole source and returns a warning for each local variable which is never read.
+    /// Parameters are not reported. Errors are reported as usual.
+    pub fn warnings(mut self) -> Vec<CompileWarning> {
+        while !self.matches(TokenType::EOF) {
+            self.top_level_declaration();
+        }
+        self.end_compile();
+        self.unused_local_warnings()
+    }
+
+    fn unused_local_warnings(&mut self) -> Vec<CompileWarning> {
+        let mut unused = std::mem::take(&mut self.unused_locals);
+        unused.sort_by_key(|name| name.get_offset());
+        unused
+            .iter()
+            .map(|name| {
+                CompileWarning::new(
+                    name.get_line(),
+                    name.get_column(),
+                    format!(
+                        "Local variable '{}' is never read.",
+                        name.get_lexeme_string()
+                    ),
+                )
+            })
+            .collect()
+    }
 }
 
 impl<'a, I: Iterator<Item = Token<'a>>, W: Write> Parser<'a, I, W> {
+    fn top_level_declaration(&mut self) {
+        let kind = match self.current.get_token_type() {
+            TokenType::Class => DeclarationKind::Class,
+            TokenType::Fun => DeclarationKind::Function,
+            TokenType::Var | TokenType::Const => DeclarationKind::Variable,
+            _ => DeclarationKind::Statement,
+        };
+        let start = self.current.get_offset();
+        self.declaration();
+        let end = self.previous.get_offset() + self.previous.get_lexeme().len();
+        self.declarations.push((start..end, kind));
+    }
+
     fn declaration(&mut self) {
         if self.matches(TokenType::Class) {
             self.class_declaration();
@@ -72,6 +180,8 @@ impl<'a, I: Iterator<Item = Token<'a>>, W: Write> Parser<'a, I, W> {
             self.function_declaration();
         } else if self.matches(TokenType::Var) {
             self.var_declaration();
+        } else if self.matches(TokenType::Const) {
+            self.const_declaration();
         } else {
             self.statement();
         }
@@ -94,9 +204,11 @@ impl<'a, I: Iterator<Item = Token<'a>>, W: Write> Parser<'a, I, W> {
                 TokenType::Class
                     | TokenType::Fun
                     | TokenType::Var
+                    | TokenType::Const
                     | TokenType::For
                     | TokenType::If
                     | TokenType::While
+                    | TokenType::Switch
                     | TokenType::Print
                     | TokenType::Return
             ) {
@@ -114,10 +226,16 @@ impl<'a, I: Iterator<Item = Token<'a>>, W: Write> Parser<'a, I, W> {
             self.if_statement();
         } else if self.matches(TokenType::While) {
             self.while_statement();
+        } else if self.matches(TokenType::Switch) {
+            self.switch_statement();
         } else if self.matches(TokenType::For) {
             self.for_statement();
         } else if self.matches(TokenType::Return) {
             self.return_statement();
+        } else if self.matches(TokenType::Break) {
+            self.break_statement();
+        } else if self.matches(TokenType::Continue) {
+            self.continue_statement();
         } else if self.matches(TokenType::LeftBrace) {
             self.begin_scope();
             self.block();
@@ -152,15 +270,20 @@ impl<'a, I: Iterator<Item = Token<'a>>, W: Write> Parser<'a, I, W> {
         self.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
 
         // Initializer clause is optional and can be an expression statement or a variable declaration.
+        let mut loop_variable = None;
         if self.matches(TokenType::Semicolon) {
             // No initialization.
         } else if self.matches(TokenType::Var) {
+            let name = self.current.clone();
             self.var_declaration();
+            let slot = self.current_compiler().get_local_count() - 1;
+            loop_variable = Some((name, slot));
         } else {
             self.expression_statement();
         }
 
         let mut loop_start = self.current_chunk().len();
+        self.current_compiler().begin_loop(loop_start);
 
         // The exit condition is optional.
         let exit_jump = if !self.matches(TokenType::Semicolon) {
@@ -176,17 +299,39 @@ impl<'a, I: Iterator<Item = Token<'a>>, W: Write> Parser<'a, I, W> {
         // The increment clause is optional.
         if !self.matches(TokenType::RightParen) {
             let body_jump = self.emit_jump(OpCode::Jump);
-            let inc_start = self.current_chunk().len();
             self.expression();
             self.emit_opcode(OpCode::Pop);
             self.consume(TokenType::RightParen, "Expect ')' after for clause.");
 
             self.emit_loop(loop_start);
-            loop_start = inc_start;
-            self.patch_jump(body_jump);
+            // The increment clause starts right after the jump over it. Patching the jump might
+            // turn it into a long jump, so the position has to be taken after patching.
+            loop_start = self.patch_jump(body_jump);
+            // A continue statement has to run the increment clause before checking the condition.
+            self.current_compiler().set_loop_start(loop_start);
         }
 
+        // Each iteration gets its own copy of the loop variable, so that closures created in the
+        // body capture the value of their iteration. The copy is written back to the loop variable
+        // before the increment clause runs.
+        let copy = loop_variable.map(|(name, outer)| {
+            self.begin_scope();
+            self.emit_get_local(outer);
+            self.add_local(name);
+            self.current_compiler().mark_local_initialized();
+            // The loop variable is reported if it is unused, not its copy.
+            self.current_compiler().mark_local_used();
+            let inner = self.current_compiler().get_local_count() - 1;
+            self.current_compiler().set_loop_copy(outer, inner);
+            (outer, inner)
+        });
+
         self.statement();
+
+        if let Some((outer, inner)) = copy {
+            self.emit_copy_back(outer, inner);
+            self.end_scope();
+        }
         self.emit_loop(loop_start);
 
         if let Some(jump) = exit_jump {
@@ -194,11 +339,13 @@ impl<'a, I: Iterator<Item = Token<'a>>, W: Write> Parser<'a, I, W> {
             self.emit_opcode(OpCode::Pop);
         }
 
+        self.end_loop();
         self.end_scope();
     }
 
     fn while_statement(&mut self) {
         let loop_start = self.current_chunk().len();
+        self.current_compiler().begin_loop(loop_start);
         self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
         self.expression();
         self.consume(TokenType::RightParen, "Expect ')' after condition.");
@@ -209,44 +356,191 @@ impl<'a, I: Iterator<Item = Token<'a>>, W: Write> Parser<'a, I, W> {
         self.emit_loop(loop_start);
         self.patch_jump(exit_jump);
         self.emit_opcode(OpCode::Pop);
+        self.end_loop();
     }
 
-    fn patch_jump(&mut self, patch: Patch) {
-        let distance = self.current_chunk().len() - patch.get_own_index() - 2;
+    fn switch_statement(&mut self) {
+        // The subject is stored in a local without a name, so each case can compare against it.
+        self.begin_scope();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'switch'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after value.");
+        self.add_local(Token::new(TokenType::EOF, &[], 0, 0, 0));
+        self.current_compiler().mark_local_initialized();
+        self.current_compiler().mark_local_used();
+        let subject = self.current_compiler().get_local_count() - 1;
+
+        self.consume(TokenType::LeftBrace, "Expect '{' before switch cases.");
+        let mut end_jumps = Vec::new();
+        let mut has_default = false;
+
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::EOF) {
+            if self.matches(TokenType::Case) {
+                if has_default {
+                    self.error("Can't have a case after the default case.");
+                }
+
+                self.emit_get_local(subject);
+                self.expression();
+                self.consume(TokenType::Colon, "Expect ':' after case value.");
+                self.emit_opcode(OpCode::Equal);
+
+                let next_case = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit_opcode(OpCode::Pop);
+                self.case_body();
+                // There is no fallthrough, each case jumps to the end of the switch.
+                end_jumps.push(self.emit_jump(OpCode::Jump));
+                self.patch_jump(next_case);
+                self.emit_opcode(OpCode::Pop);
+            } else if self.matches(TokenType::Default) {
+                if has_default {
+                    self.error("Can't have more than one default case.");
+                }
 
-        if distance > u16::MAX as usize {
+                has_default = true;
+                self.consume(TokenType::Colon, "Expect ':' after 'default'.");
+                self.case_body();
+            } else {
+                self.error_at_current("Expect 'case' or 'default'.");
+                self.advance();
+            }
+        }
+
+        self.consume(TokenType::RightBrace, "Expect '}' after switch cases.");
+        end_jumps.into_iter().for_each(|jump| {
+            self.patch_jump(jump);
+        });
+        self.end_scope();
+    }
+
+    // Each case has its own scope, because only the locals of one case are ever on the stack.
+    fn case_body(&mut self) {
+        self.begin_scope();
+        while !self.check(TokenType::Case)
+            && !self.check(TokenType::Default)
+            && !self.check(TokenType::RightBrace)
+            && !self.check(TokenType::EOF)
+        {
+            self.declaration();
+        }
+        self.end_scope();
+    }
+
+    fn break_statement(&mut self) {
+        if !self.current_compiler().is_in_loop() {
+            self.error("Can't use 'break' outside of a loop.");
+            self.consume(TokenType::Semicolon, "Expect ';' after 'break'.");
+            return;
+        }
+
+        self.consume(TokenType::Semicolon, "Expect ';' after 'break'.");
+
+        // The locals declared inside the loop are not popped by the end of their scope, because
+        // the jump skips over that code.
+        let is_captured = self.current_compiler().get_loop_locals();
+        self.emit_pops(is_captured);
+        let jump = self.emit_jump(OpCode::Jump);
+        self.current_compiler().add_break(jump);
+    }
+
+    fn continue_statement(&mut self) {
+        if !self.current_compiler().is_in_loop() {
+            self.error("Can't use 'continue' outside of a loop.");
+            self.consume(TokenType::Semicolon, "Expect ';' after 'continue'.");
+            return;
+        }
+
+        self.consume(TokenType::Semicolon, "Expect ';' after 'continue'.");
+
+        if let Some((outer, inner)) = self.current_compiler().get_loop_copy() {
+            self.emit_copy_back(outer, inner);
+        }
+        let is_captured = self.current_compiler().get_loop_locals();
+        self.emit_pops(is_captured);
+        let loop_start = self.current_compiler().get_loop_start();
+        self.emit_loop(loop_start);
+    }
+
+    // Writes the copy of a for-loop variable made for the current iteration back to the loop
+    // variable.
+    fn emit_copy_back(&mut self, outer: usize, inner: usize) {
+        self.emit_get_local(inner);
+        self.emit_set_local(outer);
+        self.emit_opcode(OpCode::Pop);
+    }
+
+    // Break statements jump right behind the loop, which is the current position.
+    fn end_loop(&mut self) {
+        let breaks = self.current_compiler().end_loop();
+        breaks.into_iter().for_each(|jump| {
+            self.patch_jump(jump);
+        });
+    }
+
+    // Returns the position of the first code unit after the patched jump.
+    fn patch_jump(&mut self, patch: Patch) -> usize {
+        let end = patch.get_own_index() + 2;
+        let distance = self.current_chunk().len() - end;
+
+        let end = if distance <= u16::MAX as usize {
+            // Safety: Distance points to the current position which always is an opcode when this
+            // function is called.
+            unsafe { patch.apply(distance as u16) };
+            end
+        } else if distance <= MAX_LONG_ADDRESS as usize {
+            // Safety: Same as above. Turning the jump into a long jump moves the current position
+            // back by one, which the long jump accounts for.
+            unsafe { patch.apply_long(distance as u32) }
+        } else {
             self.error("Too much code to jump over.");
             // Safety: There is an error and the resulting chunk will not be valid code and thus not
             //   be executed. So just writing 0 is fine.
             unsafe { patch.apply(0) };
-        } else {
-            // Safety: Distance points to the current position which always is an opcode when this
-            // function is called.
-            unsafe { patch.apply(distance as u16) }
-        }
+            end
+        };
+
+        let target = self.current_chunk().len();
+        self.current_compiler().set_jump_target(target);
+        end
     }
 
     fn function_declaration(&mut self) {
         let global = self.parse_variable("Expect function name.");
         self.current_compiler().mark_local_initialized();
-        self.function(FunctionType::Function);
+        self.function(FunctionType::Function, self.previous.get_lexeme());
         self.define_variable(global);
     }
 
-    fn function(&mut self, kind: FunctionType) {
+    // A function expression like 'fun (x) { return x; }', which leaves the closure on the stack.
+    fn anonymous_function(&mut self) {
+        // A named function is a declaration, which is not allowed where an expression is expected,
+        // e.g. as the body of an if statement.
+        if self.check(TokenType::Identifier) {
+            self.error("Expect expression.");
+            return;
+        }
+        self.function(FunctionType::Function, &ANONYMOUS);
+    }
+
+    fn function(&mut self, kind: FunctionType, name: &[char]) {
         self.compilers.push(Compiler::new(kind));
         self.current_compiler()
             .get_function_builder()
             .set_kind(kind);
         if kind != FunctionType::Script {
-            let name = self.previous.get_lexeme_string();
-            let intern = self.symbol_table.intern(name);
+            let intern = self.symbol_table.intern_chars(name);
             self.current_compiler()
                 .get_function_builder()
                 .set_name(intern);
         }
 
         self.begin_scope();
+        if kind == FunctionType::Getter {
+            self.consume(TokenType::LeftBrace, "Expect '{' before getter body.");
+            self.block();
+            self.end_function();
+            return;
+        }
         self.consume(TokenType::LeftParen, "Expect '(' after function name.");
 
         if !self.check(TokenType::RightParen) {
@@ -260,8 +554,10 @@ impl<'a, I: Iterator<Item = Token<'a>>, W: Write> Parser<'a, I, W> {
 
                 let constant = self.parse_variable("Expect parameter name.");
                 self.define_variable(constant);
+                self.current_compiler().mark_local_used();
 
-                if !self.matches(TokenType::Comma) {
+                // A comma may follow the last parameter.
+                if !self.matches(TokenType::Comma) || self.check(TokenType::RightParen) {
                     break;
                 }
             }
@@ -271,7 +567,11 @@ impl<'a, I: Iterator<Item = Token<'a>>, W: Write> Parser<'a, I, W> {
         self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
 
         self.block();
+        self.end_function();
+    }
 
+    // Ends compiling the current function and emits the closure for it.
+    fn end_function(&mut self) {
         let upvalues = self
             .current_compiler()
             .get_upvalues()
@@ -280,9 +580,8 @@ impl<'a, I: Iterator<Item = Token<'a>>, W: Write> Parser<'a, I, W> {
             .collect::<Vec<(u8, u8)>>();
 
         let function = self.end_compile();
-        self.emit_opcode(OpCode::Closure);
         let index = self.make_constant(Value::Function(function));
-        self.emit_index(index);
+        self.emit_with_constant(OpCode::Closure, index);
 
         upvalues.iter().for_each(|(l, i)| {
             self.emit_index(*l);
@@ -293,13 +592,12 @@ impl<'a, I: Iterator<Item = Token<'a>>, W: Write> Parser<'a, I, W> {
     fn class_declaration(&mut self) {
         self.consume(TokenType::Identifier, "Expect class name.");
         let class_name = self.previous.clone();
-        let name = self.identifier_constant(self.previous.get_lexeme_string());
+        let name = self.identifier_constant(self.previous.get_lexeme());
         self.declare_variable();
 
         self.class_compilers.push(ClassCompiler::new());
 
-        self.emit_opcode(OpCode::Class);
-        self.emit_index(name);
+        self.emit_with_constant(OpCode::Class, name);
         self.define_variable(name);
 
         if self.matches(TokenType::Less) {
@@ -314,6 +612,7 @@ impl<'a, I: Iterator<Item = Token<'a>>, W: Write> Parser<'a, I, W> {
             let dummy_token = self.synthetic_token(TokenType::Identifier, &SUPER);
             self.add_local(dummy_token);
             self.define_variable(0);
+            self.current_compiler().mark_local_used();
 
             self.named_variable(class_name.clone(), false);
             self.emit_opcode(OpCode::Inherit);
@@ -336,15 +635,26 @@ impl<'a, I: Iterator<Item = Token<'a>>, W: Write> Parser<'a, I, W> {
     }
 
     fn method(&mut self) {
+        // A method declared with a leading 'class' is called on the class instead of an instance.
+        let is_static = self.matches(TokenType::Class);
         self.consume(TokenType::Identifier, "Expect method name.");
-        let constant = self.identifier_constant(self.previous.get_lexeme_string());
-        let kind = match self.previous.get_lexeme_string() == "init" {
-            true => FunctionType::Initializer,
-            false => FunctionType::Method,
+        let constant = self.identifier_constant(self.previous.get_lexeme());
+        if is_static {
+            self.function(FunctionType::StaticMethod, self.previous.get_lexeme());
+            self.emit_with_constant(OpCode::StaticMethod, constant);
+            return;
+        }
+
+        // A method without a parameter list is a getter, which runs when the property is read.
+        let kind = if self.previous.get_lexeme() == INIT {
+            FunctionType::Initializer
+        } else if !self.check(TokenType::LeftParen) {
+            FunctionType::Getter
+        } else {
+            FunctionType::Method
         };
-        self.function(kind);
-        self.emit_opcode(OpCode::Method);
-        self.emit_index(constant);
+        self.function(kind, self.previous.get_lexeme());
+        self.emit_with_constant(OpCode::Method, constant);
     }
 
     fn call(&mut self) {
@@ -355,21 +665,82 @@ impl<'a, I: Iterator<Item = Token<'a>>, W: Write> Parser<'a, I, W> {
 
     fn dot(&mut self, can_assign: bool) {
         self.consume(TokenType::Identifier, "Expect property name after '.'.");
-        let name = self.identifier_constant(self.previous.get_lexeme_string());
+        let name = self.identifier_constant(self.previous.get_lexeme());
 
         if can_assign && self.matches(TokenType::Equal) {
             self.expression();
-            self.emit_opcode(OpCode::SetProperty);
-            self.emit_index(name);
+            self.emit_with_constant(OpCode::SetProperty, name);
         } else if self.matches(TokenType::LeftParen) {
             let arg_count = self.argument_list();
-            self.emit_opcode(OpCode::Invoke);
-            self.emit_index(name);
+            self.emit_with_constant(OpCode::Invoke, name);
             self.emit_index(arg_count);
         } else {
-            self.emit_opcode(OpCode::GetProperty);
-            self.emit_index(name);
+            self.emit_with_constant(OpCode::GetProperty, name);
+        }
+    }
+
+    fn index(&mut self, can_assign: bool) {
+        self.expression();
+        self.consume(TokenType::RightBracket, "Expect ']' after index.");
+
+        if can_assign && self.matches(TokenType::Equal) {
+            self.expression();
+            self.emit_opcode(OpCode::IndexSet);
+        } else {
+            self.emit_opcode(OpCode::IndexGet);
+        }
+    }
+
+    fn list(&mut self) {
+        let mut element_count: u8 = 0;
+        if !self.check(TokenType::RightBracket) {
+            loop {
+                self.expression();
+                if element_count == 255 {
+                    self.error("Can't have more than 255 elements in a list literal.");
+                } else {
+                    element_count += 1;
+                }
+
+                if !self.matches(TokenType::Comma) || self.check(TokenType::RightBracket) {
+                    break;
+                }
+            }
         }
+
+        self.consume(TokenType::RightBracket, "Expect ']' after list elements.");
+        self.emit_opcode(OpCode::BuildList);
+        self.emit_index(element_count);
+    }
+
+    fn map(&mut self) {
+        // An empty block is not an expression, so the empty map is written as {:} instead.
+        if self.check(TokenType::RightBrace) {
+            self.error("Expect expression.");
+            return;
+        }
+
+        let mut entry_count: u8 = 0;
+        if !self.matches(TokenType::Colon) {
+            loop {
+                self.expression();
+                self.consume(TokenType::Colon, "Expect ':' after map key.");
+                self.expression();
+                if entry_count == 255 {
+                    self.error("Can't have more than 255 entries in a map literal.");
+                } else {
+                    entry_count += 1;
+                }
+
+                if !self.matches(TokenType::Comma) || self.check(TokenType::RightBrace) {
+                    break;
+                }
+            }
+        }
+
+        self.consume(TokenType::RightBrace, "Expect '}' after map entries.");
+        self.emit_opcode(OpCode::BuildMap);
+        self.emit_index(entry_count);
     }
 
     fn argument_list(&mut self) -> u8 {
@@ -384,7 +755,8 @@ impl<'a, I: Iterator<Item = Token<'a>>, W: Write> Parser<'a, I, W> {
                     arg_count += 1;
                 }
 
-                if !self.matches(TokenType::Comma) {
+                // A comma may follow the last argument.
+                if !self.matches(TokenType::Comma) || self.check(TokenType::RightParen) {
                     break;
                 }
             }
@@ -414,6 +786,11 @@ impl<'a, I: Iterator<Item = Token<'a>>, W: Write> Parser<'a, I, W> {
     }
 
     fn var_declaration(&mut self) {
+        if self.matches(TokenType::LeftBracket) {
+            self.destructuring_declaration();
+            return;
+        }
+
         let global = self.parse_variable("Expect variable name.");
         if self.matches(TokenType::Equal) {
             self.expression();
@@ -428,19 +805,81 @@ impl<'a, I: Iterator<Item = Token<'a>>, W: Write> Parser<'a, I, W> {
         self.define_variable(global);
     }
 
-    fn parse_variable(&mut self, error_message: &str) -> u8 {
+    // 'var [a, b] = list;' declares a variable for each element of the list. The list has to have
+    // exactly as many elements as there are names, otherwise it is a runtime error.
+    fn destructuring_declaration(&mut self) {
+        let first_local = self.current_compiler().get_local_count();
+        let mut globals: Vec<usize> = Vec::new();
+        let mut name_count: u8 = 0;
+        loop {
+            let global = self.parse_variable("Expect variable name.");
+            globals.push(global);
+            if name_count == 255 {
+                self.error("Can't destructure more than 255 variables.");
+            } else {
+                name_count += 1;
+            }
+
+            if !self.matches(TokenType::Comma) || self.check(TokenType::RightBracket) {
+                break;
+            }
+        }
+
+        self.consume(TokenType::RightBracket, "Expect ']' after variable names.");
+        self.consume(TokenType::Equal, "Expect '=' after variable names.");
+        self.expression();
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after variable declaration.",
+        );
+        self.emit_opcode(OpCode::Destructure);
+        self.emit_index(name_count);
+
+        if self.current_compiler().get_scope_depth() > 0 {
+            // The elements are left on the stack in order, which are the slots of the locals.
+            self.current_compiler().mark_locals_initialized(first_local);
+        } else {
+            // DefineGlobal pops the last element first.
+            for global in globals.into_iter().rev() {
+                self.define_variable(global);
+            }
+        }
+    }
+
+    fn const_declaration(&mut self) {
+        let global = self.parse_variable("Expect constant name.");
+        if self.current_compiler().get_scope_depth() > 0 {
+            self.current_compiler().mark_local_const();
+        } else {
+            self.const_globals.insert(self.previous.get_lexeme());
+        }
+
+        self.consume(TokenType::Equal, "Expect '=' after constant name.");
+        self.expression();
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after constant declaration.",
+        );
+        if self.current_compiler().get_scope_depth() > 0 {
+            self.current_compiler().mark_local_initialized();
+        } else {
+            self.emit_with_constant(OpCode::DefineConstGlobal, global);
+        }
+    }
+
+    fn parse_variable(&mut self, error_message: &str) -> usize {
         self.consume(TokenType::Identifier, error_message);
 
         self.declare_variable();
         if self.current_compiler().get_scope_depth() > 0 {
             0
         } else {
-            self.identifier_constant(self.previous.get_lexeme_string())
+            self.identifier_constant(self.previous.get_lexeme())
         }
     }
 
-    fn identifier_constant(&mut self, name: String) -> u8 {
-        let intern = self.symbol_table.intern(name);
+    fn identifier_constant(&mut self, name: &[char]) -> usize {
+        let intern = self.symbol_table.intern_chars(name);
         self.make_constant(Value::String(intern))
     }
 
@@ -455,20 +894,22 @@ impl<'a, I: Iterator<Item = Token<'a>>, W: Write> Parser<'a, I, W> {
             } else {
                 self.error("Already a variable with this name in this scope.");
             }
+        } else {
+            // Redeclaring a global constant with anything but const makes it a variable again.
+            self.const_globals.remove(self.previous.get_lexeme());
         }
     }
 
-    fn define_variable(&mut self, global: u8) {
+    fn define_variable(&mut self, global: usize) {
         if self.current_compiler().get_scope_depth() == 0 {
-            self.emit_opcode(OpCode::DefineGlobal);
-            self.emit_index(global);
+            self.emit_with_constant(OpCode::DefineGlobal, global);
         } else {
             self.current_compiler().mark_local_initialized();
         }
     }
 
     fn add_local(&mut self, name: Token<'a>) {
-        if self.current_compiler().get_local_count() <= (u8::MAX as usize) {
+        if self.current_compiler().get_local_count() <= (u16::MAX as usize) {
             let local = Local::new(name, -1);
             self.current_compiler().push_local(local);
         } else {
@@ -476,10 +917,29 @@ impl<'a, I: Iterator<Item = Token<'a>>, W: Write> Parser<'a, I, W> {
         }
     }
 
+    // Several values are separated by commas. They are printed on one line, separated by spaces.
     fn print_statement(&mut self) {
-        self.expression();
+        let mut value_count: u8 = 0;
+        loop {
+            self.expression();
+            if value_count == 255 {
+                self.error("Can't print more than 255 values.");
+            } else {
+                value_count += 1;
+            }
+
+            if !self.matches(TokenType::Comma) {
+                break;
+            }
+        }
+
         self.consume(TokenType::Semicolon, "Expect ';' after value.");
-        self.emit_opcode(OpCode::Print);
+        if value_count == 1 {
+            self.emit_opcode(OpCode::Print);
+        } else {
+            self.emit_opcode(OpCode::PrintN);
+            self.emit_index(value_count);
+        }
     }
 
     fn block(&mut self) {
@@ -497,6 +957,10 @@ impl<'a, I: Iterator<Item = Token<'a>>, W: Write> Parser<'a, I, W> {
     fn end_scope(&mut self) {
         self.current_compiler().dec_scope_depth();
         let is_captured = self.current_compiler().remove_out_of_scope_locals();
+        self.emit_pops(is_captured);
+    }
+
+    fn emit_pops(&mut self, is_captured: Vec<bool>) {
         is_captured
             .iter()
             .map(|c| {
@@ -511,6 +975,11 @@ impl<'a, I: Iterator<Item = Token<'a>>, W: Write> Parser<'a, I, W> {
 
     fn expression_statement(&mut self) {
         self.expression();
+        if self.repl && self.compilers.len() == 1 && self.check(TokenType::EOF) {
+            self.emit_opcode(OpCode::Print);
+            return;
+        }
+
         self.consume(TokenType::Semicolon, "Expect ';' after expression.");
         self.emit_opcode(OpCode::Pop);
     }
@@ -526,16 +995,17 @@ impl<'a, I: Iterator<Item = Token<'a>>, W: Write> Parser<'a, I, W> {
         self.parse_precedence(precedence);
 
         match &operator {
-            TokenType::BangEqual => emit_opcodes!(self, OpCode::Equal, OpCode::Not),
+            TokenType::BangEqual => self.emit_opcode(OpCode::NotEqual),
             TokenType::EqualEqual => self.emit_opcode(OpCode::Equal),
             TokenType::Greater => self.emit_opcode(OpCode::Greater),
-            TokenType::GreaterEqual => emit_opcodes!(self, OpCode::Less, OpCode::Not),
+            TokenType::GreaterEqual => self.emit_opcode(OpCode::GreaterEqual),
             TokenType::Less => self.emit_opcode(OpCode::Less),
-            TokenType::LessEqual => emit_opcodes!(self, OpCode::Greater, OpCode::Not),
+            TokenType::LessEqual => self.emit_opcode(OpCode::LessEqual),
             TokenType::Plus => self.emit_opcode(OpCode::Add),
             TokenType::Minus => self.emit_opcode(OpCode::Subtract),
             TokenType::Star => self.emit_opcode(OpCode::Multiply),
             TokenType::Slash => self.emit_opcode(OpCode::Divide),
+            TokenType::Percent => self.emit_opcode(OpCode::Modulo),
             _ => unreachable!(),
         }
     }
@@ -544,7 +1014,7 @@ impl<'a, I: Iterator<Item = Token<'a>>, W: Write> Parser<'a, I, W> {
         let operator_type = self.previous.get_token_type();
         self.parse_precedence(Precedence::Unary);
         match operator_type {
-            TokenType::Bang => self.emit_opcode(OpCode::Not),
+            TokenType::Bang => self.emit_not(),
             TokenType::Minus => self.emit_opcode(OpCode::Negate),
             _ => unreachable!(),
         }
@@ -559,8 +1029,16 @@ impl<'a, I: Iterator<Item = Token<'a>>, W: Write> Parser<'a, I, W> {
         self.named_variable(self.previous.clone(), can_assign);
     }
 
+    // Synthetic tokens are attributed to the position of the token before them, so that any line
+    // derived from them is a real line of the source.
     fn synthetic_token(&mut self, token_type: TokenType, text: &'static [char]) -> Token<'static> {
-        Token::new(token_type, text, u32::MAX)
+        Token::new(
+            token_type,
+            text,
+            self.previous.get_line(),
+            self.previous.get_column(),
+            self.previous.get_offset(),
+        )
     }
 
     fn super_(&mut self) {
@@ -568,11 +1046,13 @@ impl<'a, I: Iterator<Item = Token<'a>>, W: Write> Parser<'a, I, W> {
             self.error("Can't use 'super' outside of a class.");
         } else if !self.current_class_compiler().get_has_superclass() {
             self.error("Can't use 'super' in a class with no superclass.");
+        } else if self.in_static_method() {
+            self.error("Can't use 'super' in a static method.");
         }
 
         self.consume(TokenType::Dot, "Expect '.' after 'super'.");
         self.consume(TokenType::Identifier, "Expect superclass method name.");
-        let name = self.identifier_constant(self.previous.get_lexeme_string());
+        let name = self.identifier_constant(self.previous.get_lexeme());
         let this_dummy_token = self.synthetic_token(TokenType::Identifier, &THIS);
         self.named_variable(this_dummy_token, false);
 
@@ -580,51 +1060,90 @@ impl<'a, I: Iterator<Item = Token<'a>>, W: Write> Parser<'a, I, W> {
             let arg_count = self.argument_list();
             let super_dummy_token = self.synthetic_token(TokenType::Identifier, &SUPER);
             self.named_variable(super_dummy_token, false);
-            self.emit_opcode(OpCode::SuperInvoke);
-            self.emit_index(name);
+            self.emit_with_constant(OpCode::SuperInvoke, name);
             self.emit_index(arg_count);
         } else {
             let super_dummy_token = self.synthetic_token(TokenType::Identifier, &SUPER);
             self.named_variable(super_dummy_token, false);
-            self.emit_opcode(OpCode::GetSuper);
-            self.emit_index(name);
+            self.emit_with_constant(OpCode::GetSuper, name);
         }
     }
 
     fn this(&mut self) {
         if self.class_compilers.is_empty() {
             self.error("Can't use 'this' outside of a class.");
+        } else if self.in_static_method() {
+            self.error("Can't use 'this' in a static method.");
         } else {
             self.variable(false);
         }
     }
 
+    // Whether the innermost method around the code being compiled is a static method, which has no
+    // instance to refer to.
+    fn in_static_method(&self) -> bool {
+        self.compilers
+            .iter()
+            .rev()
+            .map(|compiler| compiler.get_kind())
+            .find(|kind| *kind != FunctionType::Function && *kind != FunctionType::Script)
+            == Some(FunctionType::StaticMethod)
+    }
+
     fn named_variable(&mut self, name: Token<'a>, can_assign: bool) {
         let (mut arg, uninitialized) = self.current_compiler().resolve(&name);
         if uninitialized {
             self.error("Can't read local variable in its own initializer.");
         }
 
-        let (get, set) = if arg != -1 {
-            (OpCode::GetLocal, OpCode::SetLocal)
-        } else {
+        if arg != -1 {
+            let slot = arg as usize;
+            if can_assign && self.matches(TokenType::Equal) {
+                if self.current_compiler().get_local_at_mut(slot).is_const() {
+                    self.const_assignment_error(&name);
+                }
+                self.expression();
+                self.emit_set_local(slot);
+            } else {
+                self.current_compiler().get_local_at_mut(slot).set_used();
+                self.emit_get_local(slot);
+            }
+            return;
+        }
+
+        let (get, set, is_const, is_global) = {
             arg = self.resolve_upvalue(self.compilers.len() - 1, &name);
             if arg != -1 {
-                (OpCode::GetUpvalue, OpCode::SetUpvalue)
+                let is_const = self.current_compiler().get_upvalues()[arg as usize].is_const();
+                (OpCode::GetUpvalue, OpCode::SetUpvalue, is_const, false)
             } else {
-                arg = self.identifier_constant(name.get_lexeme_string()) as isize;
-                (OpCode::GetGlobal, OpCode::SetGlobal)
+                arg = self.identifier_constant(name.get_lexeme()) as isize;
+                let is_const = self.const_globals.contains(name.get_lexeme());
+                (OpCode::GetGlobal, OpCode::SetGlobal, is_const, true)
             }
         };
 
-        if can_assign && self.matches(TokenType::Equal) {
+        let opcode = if can_assign && self.matches(TokenType::Equal) {
+            if is_const {
+                self.const_assignment_error(&name);
+            }
             self.expression();
-            self.emit_opcode(set);
+            set
+        } else {
+            get
+        };
+
+        if is_global {
+            self.emit_with_constant(opcode, arg as usize);
         } else {
-            self.emit_opcode(get);
+            self.emit_opcode(opcode);
+            self.emit_index(arg as u8);
         }
+    }
 
-        self.emit_index(arg as u8);
+    fn const_assignment_error(&mut self, name: &Token<'a>) {
+        let message = format!("Cannot assign to constant '{}'.", name.get_lexeme_string());
+        self.error_at(name, &message);
     }
 
     fn resolve_upvalue(&mut self, depth: usize, token: &Token) -> isize {
@@ -632,13 +1151,20 @@ impl<'a, I: Iterator<Item = Token<'a>>, W: Write> Parser<'a, I, W> {
             let next = depth - 1;
             let c = &mut self.compilers[next];
             let (local, _) = c.resolve(token);
-            if local != -1 {
-                c.get_local_at_mut(local as usize).set_captured(true);
-                self.add_upvalue(depth, local as u8, true)
+            if local > u8::MAX as isize {
+                self.error("Can't capture a local variable beyond the first 256 of a function.");
+                0
+            } else if local != -1 {
+                let captured = c.get_local_at_mut(local as usize);
+                captured.set_captured(true);
+                captured.set_used();
+                let is_const = captured.is_const();
+                self.add_upvalue(depth, local as u8, true, is_const)
             } else {
                 let upvalue = self.resolve_upvalue(next, token);
                 if upvalue != -1 {
-                    self.add_upvalue(depth, upvalue as u8, false)
+                    let is_const = self.compilers[next].get_upvalues()[upvalue as usize].is_const();
+                    self.add_upvalue(depth, upvalue as u8, false, is_const)
                 } else {
                     -1
                 }
@@ -648,8 +1174,14 @@ impl<'a, I: Iterator<Item = Token<'a>>, W: Write> Parser<'a, I, W> {
         }
     }
 
-    fn add_upvalue(&mut self, compiler_index: usize, index: u8, is_local: bool) -> isize {
-        let upvalue = Upvalue::new(index, is_local);
+    fn add_upvalue(
+        &mut self,
+        compiler_index: usize,
+        index: u8,
+        is_local: bool,
+        is_const: bool,
+    ) -> isize {
+        let upvalue = Upvalue::new(index, is_local, is_const);
         match self.compilers[compiler_index].add_upvalue(upvalue) {
             Some(i) => i as isize,
             None => {
@@ -662,7 +1194,10 @@ impl<'a, I: Iterator<Item = Token<'a>>, W: Write> Parser<'a, I, W> {
     fn number(&mut self) {
         let value = self
             .previous
-            .get_lexeme_string()
+            .get_lexeme()
+            .iter()
+            .filter(|c| **c != '_')
+            .collect::<String>()
             .parse::<f64>()
             .expect("Expect the lexeme to be a number.");
         self.emit_constant(Value::Double(value));
@@ -679,11 +1214,41 @@ impl<'a, I: Iterator<Item = Token<'a>>, W: Write> Parser<'a, I, W> {
 
     fn string(&mut self) {
         let lexeme = self.previous.get_lexeme();
-        let string = lexeme[1..lexeme.len() - 1].iter().collect::<String>();
-        let intern = self.symbol_table.intern(string);
+        let content = &lexeme[1..lexeme.len() - 1];
+        let intern = if content.contains(&'\\') {
+            let string = self.unescape(content);
+            self.symbol_table.intern(string)
+        } else {
+            self.symbol_table.intern_chars(content)
+        };
         self.emit_constant(Value::String(intern));
     }
 
+    fn unescape(&mut self, content: &[char]) -> String {
+        let mut string = String::with_capacity(content.len());
+        let mut chars = content.iter();
+        while let Some(c) = chars.next() {
+            if *c != '\\' {
+                string.push(*c);
+                continue;
+            }
+
+            // The scanner ensures that a backslash is always followed by another character.
+            match chars.next() {
+                Some('n') => string.push('\n'),
+                Some('t') => string.push('\t'),
+                Some('r') => string.push('\r'),
+                Some('\\') => string.push('\\'),
+                Some('"') => string.push('"'),
+                Some('0') => string.push('\0'),
+                Some(c) => self.error(format!("Invalid escape sequence '\\{}'.", c).as_str()),
+                None => unreachable!(),
+            }
+        }
+
+        string
+    }
+
     fn and(&mut self) {
         let end_jump = self.emit_jump(OpCode::JumpIfFalse);
         self.emit_opcode(OpCode::Pop);
@@ -733,18 +1298,48 @@ impl<'a, I: Iterator<Item = Token<'a>>, W: Write> Parser<'a, I, W> {
     }
 
     fn emit_constant(&mut self, value: Value) {
-        self.emit_opcode(OpCode::Constant);
         let index = self.make_constant(value);
-        self.emit_index(index);
+        self.emit_with_constant(OpCode::Constant, index);
     }
 
-    fn make_constant(&mut self, value: Value) -> u8 {
+    fn make_constant(&mut self, value: Value) -> usize {
         let index = self.current_chunk().add_constant(value);
-        if index > u8::MAX as usize {
+        if index > MAX_LONG_ADDRESS as usize {
             self.error("Too many constants in one chunk.");
             0
         } else {
-            index as u8
+            index
+        }
+    }
+
+    // Constants beyond the range of a single index are referred to by the long form of the
+    // instruction, which takes three indexes for the index of the constant.
+    fn emit_with_constant(&mut self, opcode: OpCode, index: usize) {
+        if index <= u8::MAX as usize {
+            self.emit_opcode(opcode);
+            self.emit_index(index as u8);
+        } else {
+            self.emit_opcode(opcode.with_long_constant_index());
+            self.current_chunk().write_long_index(index as u32);
+        }
+    }
+
+    fn emit_get_local(&mut self, slot: usize) {
+        self.emit_local(OpCode::GetLocal, OpCode::GetLocalLong, slot);
+    }
+
+    fn emit_set_local(&mut self, slot: usize) {
+        self.emit_local(OpCode::SetLocal, OpCode::SetLocalLong, slot);
+    }
+
+    // Slots beyond the range of a single index are accessed with the long form of the instruction.
+    fn emit_local(&mut self, short: OpCode, long: OpCode, slot: usize) {
+        if slot <= u8::MAX as usize {
+            self.emit_opcode(short);
+            self.emit_index(slot as u8);
+        } else {
+            self.emit_opcode(long);
+            self.current_chunk().write_short_index(slot as u16);
         }
     }
 
@@ -773,6 +1368,29 @@ impl<'a, I: Iterator<Item = Token<'a>>, W: Write> Parser<'a, I, W> {
         self.current_chunk().write_address(position);
     }
 
+    fn emit_long_address(&mut self, position: u32) {
+        self.current_chunk().write_long_address(position);
+    }
+
+    // A negation directly following a comparison is folded into the negated comparison, e.g.
+    // OpCode::Equal followed by OpCode::Not becomes OpCode::NotEqual. This is not possible if
+    // some jump targets the negation, because then the negation is not always preceded by the
+    // comparison.
+    fn emit_not(&mut self) {
+        let position = self.current_chunk().len();
+        let negated = self
+            .current_chunk()
+            .get_last_opcode()
+            .and_then(|opcode| opcode.negated_comparison());
+
+        match negated {
+            Some(opcode) if !self.current_compiler().is_jump_target(position) => {
+                self.current_chunk().replace_last_opcode(opcode)
+            }
+            _ => self.emit_opcode(OpCode::Not),
+        }
+    }
+
     fn emit_jump(&mut self, opcode: OpCode) -> Patch {
         assert!(matches!(opcode, OpCode::Jump | OpCode::JumpIfFalse));
         self.emit_opcode(opcode);
@@ -780,15 +1398,23 @@ impl<'a, I: Iterator<Item = Token<'a>>, W: Write> Parser<'a, I, W> {
     }
 
     fn emit_loop(&mut self, loop_start: usize) {
-        self.emit_opcode(OpCode::Loop);
+        // The offset is relative to the end of the loop instruction, so it has to account for the
+        // opcode and its indexes as well.
+        let offset = self.current_chunk().len() - loop_start + 3;
 
-        let offset = self.current_chunk().len() - loop_start + 2;
+        // The long loop instruction has one more index, which its offset has to skip as well.
+        let long_offset = offset + 1;
 
-        if offset > u16::MAX as usize {
+        if offset <= u16::MAX as usize {
+            self.emit_opcode(OpCode::Loop);
+            self.emit_address(offset as u16);
+        } else if long_offset <= MAX_LONG_ADDRESS as usize {
+            self.emit_opcode(OpCode::LoopLong);
+            self.emit_long_address(long_offset as u32);
+        } else {
             self.error("Loop body too large.");
+            self.emit_opcode(OpCode::Loop);
             self.emit_address(0);
-        } else {
-            self.emit_address(offset as u16);
         }
     }
 
@@ -815,6 +1441,8 @@ impl<'a, I: Iterator<Item = Token<'a>>, W: Write> Parser<'a, I, W> {
         if !self.had_error {
             self.debug_print_chunk();
         }
+        let unused = self.current_compiler().take_unused_locals();
+        self.unused_locals.extend(unused);
         self.compilers.pop().unwrap().compile()
     }
 
@@ -873,7 +1501,8 @@ impl<'a, I: Iterator<Item = Token<'a>>, W: Write> Parser<'a, I, W> {
         if !self.panic_mode {
             self.panic_mode = true;
             self.had_error = true;
-            error_at(&self.previous, message, &mut self.error_writer);
+            let error = error_at(&self.previous, message, &mut self.error_writer);
+            self.errors.push(error);
         }
     }
 
@@ -881,7 +1510,8 @@ impl<'a, I: Iterator<Item = Token<'a>>, W: Write> Parser<'a, I, W> {
         if !self.panic_mode {
             self.panic_mode = true;
             self.had_error = true;
-            error_at(&self.current, message, &mut self.error_writer);
+            let error = error_at(&self.current, message, &mut self.error_writer);
+            self.errors.push(error);
         }
     }
 
@@ -889,20 +1519,26 @@ impl<'a, I: Iterator<Item = Token<'a>>, W: Write> Parser<'a, I, W> {
         if !self.panic_mode {
             self.panic_mode = true;
             self.had_error = true;
-            error_at(token, message, &mut self.error_writer);
+            let error = error_at(token, message, &mut self.error_writer);
+            self.errors.push(error);
         }
     }
 }
 
-fn error_at<'a, W: Write>(token: &Token<'a>, message: &str, write: &mut W) {
-    let mut msg = format!("[line {}] Error", token.get_line());
-    if token.get_token_type() == TokenType::EOF {
-        msg.push_str(" at end");
-    } else if token.get_token_type() != TokenType::Error {
-        msg.push_str(format!(" at '{}'", token.get_lexeme_string()).as_str());
+fn error_at<'a, W: Write>(token: &Token<'a>, message: &str, write: &mut W) -> CompileError {
+    let location = match token.get_token_type() {
+        TokenType::EOF => ErrorLocation::End,
+        TokenType::Error => ErrorLocation::None,
+        _ => ErrorLocation::Token(token.get_lexeme_string()),
     };
-    msg.push_str(format!(": {}", message).as_str());
-    writeln!(write, "{}", msg).unwrap();
+    let error = CompileError::new(
+        token.get_line(),
+        token.get_column(),
+        location,
+        String::from(message),
+    );
+    writeln!(write, "{}", error).unwrap();
+    error
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug, PartialOrd, Ord)]
@@ -987,11 +1623,15 @@ impl<'a, I: Iterator<Item = Token<'a>>, W: Write> ParseRules<'a, I, W> {
         let rules = enum_map::enum_map! {
         TokenType::LeftParen    => ParseRule::new(Some(|c, _| c.grouping()), Some(|c, _| c.call()), Precedence::Call),
             TokenType::RightParen   => ParseRule::new(None, None, Precedence::None),
-            TokenType::LeftBrace    => ParseRule::new(None, None, Precedence::None),
+            TokenType::LeftBrace    => ParseRule::new(Some(|c, _| c.map()), None, Precedence::None),
             TokenType::RightBrace   => ParseRule::new(None, None, Precedence::None),
+            TokenType::LeftBracket  => ParseRule::new(Some(|c, _| c.list()), Some(|c, can_assign| c.index(can_assign)), Precedence::Call),
+            TokenType::RightBracket => ParseRule::new(None, None, Precedence::None),
+            TokenType::Colon        => ParseRule::new(None, None, Precedence::None),
             TokenType::Comma        => ParseRule::new(None, None, Precedence::None),
             TokenType::Dot          => ParseRule::new(None, Some(|c, can_assign| c.dot(can_assign)),Precedence::Call),
             TokenType::Minus        => ParseRule::new(Some(|c, _| c.unary()), Some(|c, _| c.binary()), Precedence::Term),
+            TokenType::Percent      => ParseRule::new(None, Some(|c, _| c.binary()), Precedence::Factor),
             TokenType::Plus         => ParseRule::new(None, Some(|c, _| c.binary()), Precedence::Term),
             TokenType::Semicolon    => ParseRule::new(None, None, Precedence::None),
             TokenType::Slash        => ParseRule::new(None, Some(|c, _| c.binary()), Precedence::Factor),
@@ -1008,10 +1648,15 @@ impl<'a, I: Iterator<Item = Token<'a>>, W: Write> ParseRules<'a, I, W> {
             TokenType::String       => ParseRule::new(Some(|c, _| c.string()), None, Precedence::None),
             TokenType::Number       => ParseRule::new(Some(|c, _| {c.number()}), None, Precedence::None),
             TokenType::And          => ParseRule::new(None, Some(|c, _| c.and()), Precedence::And),
+            TokenType::Break        => ParseRule::new(None, None, Precedence::None),
+            TokenType::Case         => ParseRule::new(None, None, Precedence::None),
             TokenType::Class        => ParseRule::new(None, None, Precedence::None),
+            TokenType::Const        => ParseRule::new(None, None, Precedence::None),
+            TokenType::Continue     => ParseRule::new(None, None, Precedence::None),
+            TokenType::Default      => ParseRule::new(None, None, Precedence::None),
             TokenType::Else         => ParseRule::new(None, None, Precedence::None),
             TokenType::False        => ParseRule::new(Some(|c, _| c.literal()), None, Precedence::None),
-            TokenType::Fun          => ParseRule::new(None, None, Precedence::None),
+            TokenType::Fun          => ParseRule::new(Some(|c, _| c.anonymous_function()), None, Precedence::None),
             TokenType::For          => ParseRule::new(None, None, Precedence::None),
             TokenType::If           => ParseRule::new(None, None, Precedence::None),
             TokenType::Nil          => ParseRule::new(Some(|c, _| c.literal()), None, Precedence::None),
@@ -1019,6 +1664,7 @@ impl<'a, I: Iterator<Item = Token<'a>>, W: Write> ParseRules<'a, I, W> {
             TokenType::Print        => ParseRule::new(None, None, Precedence::None),
             TokenType::Return       => ParseRule::new(None, None, Precedence::None),
             TokenType::Super        => ParseRule::new(Some(|c, _| c.super_()), None, Precedence::None),
+            TokenType::Switch       => ParseRule::new(None, None, Precedence::None),
             TokenType::This         => ParseRule::new(Some(|c, _| c.this()), None, Precedence::None),
             TokenType::True         => ParseRule::new(Some(|c, _| c.literal()), None, Precedence::None),
             TokenType::Var          => ParseRule::new(None, None, Precedence::None),
@@ -1040,14 +1686,17 @@ struct Compiler<'a> {
     locals: Vec<Local<'a>>,
     upvalues: Vec<Upvalue>,
     scope_depth: usize,
+    loops: Vec<Loop>,
+    jump_target: Option<usize>,
+    unused_locals: Vec<Token<'a>>,
 }
 
 impl<'a> Compiler<'a> {
     fn new(kind: FunctionType) -> Self {
-        let token = if kind != FunctionType::Function {
-            Token::new(TokenType::EOF, &['t', 'h', 'i', 's'], 0)
+        let token = if kind != FunctionType::Function && kind != FunctionType::StaticMethod {
+            Token::new(TokenType::EOF, &['t', 'h', 'i', 's'], 0, 0, 0)
         } else {
-            Token::new(TokenType::EOF, &[], 0)
+            Token::new(TokenType::EOF, &[], 0, 0, 0)
         };
 
         // We reserve the fist locals entry for internal use.
@@ -1058,6 +1707,9 @@ impl<'a> Compiler<'a> {
             locals: vec![local],
             upvalues: Vec::new(),
             scope_depth: 0,
+            loops: Vec::new(),
+            jump_target: None,
+            unused_locals: Vec::new(),
         }
     }
 
@@ -1094,6 +1746,18 @@ impl<'a> Compiler<'a> {
         }
     }
 
+    // Marks the locals from the given slot onwards as initialized.
+    fn mark_locals_initialized(&mut self, first: usize) {
+        let depth = self.scope_depth as isize;
+        for local in &mut self.locals[first..] {
+            local.set_depth(depth);
+        }
+    }
+
+    fn mark_local_const(&mut self) {
+        self.locals.last_mut().unwrap().set_const(true);
+    }
+
     fn check_variable_declared_in_current_scope(&self, name: &Token<'a>) -> bool {
         self.locals
             .iter()
@@ -1108,16 +1772,90 @@ impl<'a> Compiler<'a> {
         while self
             .locals
             .last()
-            .map_or(false, |l| l.get_depth() > self.scope_depth as isize)
+            .is_some_and(|l| l.get_depth() > self.scope_depth as isize)
         {
-            let close_upvalue = self.locals.last().unwrap().is_captured();
-            is_captured.push(close_upvalue);
-            self.locals.pop();
+            let local = self.locals.pop().unwrap();
+            is_captured.push(local.is_captured());
+            if !local.is_used() {
+                self.unused_locals.push(local.name);
+            }
         }
 
         is_captured
     }
 
+    fn mark_local_used(&mut self) {
+        self.locals.last_mut().unwrap().set_used();
+    }
+
+    // Returns the names of the locals that went out of scope without being used, including the
+    // ones still in scope at the end of the function.
+    fn take_unused_locals(&mut self) -> Vec<Token<'a>> {
+        let still_in_scope = self
+            .locals
+            .iter()
+            .filter(|l| l.get_depth() > 0 && !l.is_used())
+            .map(|l| l.get_name().clone());
+        let mut unused = std::mem::take(&mut self.unused_locals);
+        unused.extend(still_in_scope);
+        unused
+    }
+
+    fn begin_loop(&mut self, start: usize) {
+        self.loops.push(Loop::new(start, self.scope_depth));
+        self.set_jump_target(start);
+    }
+
+    // Only the most recent jump target needs to be remembered, because it is only checked whether
+    // the current position is a jump target.
+    fn set_jump_target(&mut self, position: usize) {
+        self.jump_target = Some(position);
+    }
+
+    fn is_jump_target(&self, position: usize) -> bool {
+        self.jump_target == Some(position)
+    }
+
+    fn get_loop_start(&self) -> usize {
+        self.loops.last().unwrap().get_start()
+    }
+
+    fn set_loop_start(&mut self, start: usize) {
+        self.loops.last_mut().unwrap().set_start(start);
+    }
+
+    fn end_loop(&mut self) -> Vec<Patch> {
+        self.loops.pop().unwrap().take_breaks()
+    }
+
+    fn set_loop_copy(&mut self, outer: usize, inner: usize) {
+        self.loops.last_mut().unwrap().set_copy(outer, inner);
+    }
+
+    fn get_loop_copy(&self) -> Option<(usize, usize)> {
+        self.loops.last().unwrap().get_copy()
+    }
+
+    fn is_in_loop(&self) -> bool {
+        !self.loops.is_empty()
+    }
+
+    fn add_break(&mut self, jump: Patch) {
+        self.loops.last_mut().unwrap().add_break(jump);
+    }
+
+    // Returns whether each local declared inside of the innermost loop is captured, starting with
+    // the last local declared. The locals stay in scope.
+    fn get_loop_locals(&self) -> Vec<bool> {
+        let depth = self.loops.last().unwrap().get_scope_depth() as isize;
+        self.locals
+            .iter()
+            .rev()
+            .take_while(|l| l.get_depth() > depth)
+            .map(|l| l.is_captured())
+            .collect()
+    }
+
     fn resolve(&self, name: &Token<'a>) -> (isize, bool) {
         self.locals
             .iter()
@@ -1127,6 +1865,10 @@ impl<'a> Compiler<'a> {
             .map_or((-1, false), |(i, l)| (i as isize, l.get_depth() == -1))
     }
 
+    fn get_kind(&self) -> FunctionType {
+        self.function_builder.get_kind()
+    }
+
     fn get_function_builder(&mut self) -> &mut FunctionBuilder {
         &mut self.function_builder
     }
@@ -1160,6 +1902,11 @@ struct Local<'a> {
     name: Token<'a>,
     depth: isize,
     is_captured: bool,
+    is_const: bool,
+    // Whether the local is read or captured anywhere. Parameters and the locals made by the
+    // compiler count as used from the start, so that only unused locals of the program are
+    // reported.
+    is_used: bool,
 }
 
 impl<'a> Local<'a> {
@@ -1168,6 +1915,8 @@ impl<'a> Local<'a> {
             name,
             depth,
             is_captured: false,
+            is_const: false,
+            is_used: false,
         }
     }
 
@@ -1190,16 +1939,38 @@ impl<'a> Local<'a> {
     fn is_captured(&self) -> bool {
         self.is_captured
     }
+
+    fn set_const(&mut self, is_const: bool) {
+        self.is_const = is_const;
+    }
+
+    fn is_const(&self) -> bool {
+        self.is_const
+    }
+
+    fn set_used(&mut self) {
+        self.is_used = true;
+    }
+
+    fn is_used(&self) -> bool {
+        self.is_used
+    }
 }
 
 pub struct Upvalue {
     index: u8,
     is_local: bool,
+    // Whether the captured variable was declared with const.
+    is_const: bool,
 }
 
 impl Upvalue {
-    pub fn new(index: u8, is_local: bool) -> Self {
-        Upvalue { index, is_local }
+    pub fn new(index: u8, is_local: bool, is_const: bool) -> Self {
+        Upvalue {
+            index,
+            is_local,
+            is_const,
+        }
     }
 
     pub fn get_index(&self) -> u8 {
@@ -1209,6 +1980,10 @@ impl Upvalue {
     pub fn is_local(&self) -> bool {
         self.is_local
     }
+
+    pub fn is_const(&self) -> bool {
+        self.is_const
+    }
 }
 
 struct ClassCompiler {
@@ -1230,3 +2005,292 @@ impl ClassCompiler {
         self.has_superclass = has_superclass;
     }
 }
+
+/// Tracks a loop while its body is being compiled. Break and continue statements inside the loop
+/// need to know which locals to pop before jumping. Continue statements jump back to the start of
+/// the loop, while break statements are patched once the end of the loop is known.
+/// The body of a for-loop works on a copy of the loop variable, given as the slots of the loop
+/// variable and the copy. Continue statements write the copy back before jumping.
+struct Loop {
+    start: usize,
+    scope_depth: usize,
+    breaks: Vec<Patch>,
+    copy: Option<(usize, usize)>,
+}
+
+impl Loop {
+    fn new(start: usize, scope_depth: usize) -> Self {
+        Loop {
+            start,
+            scope_depth,
+            breaks: Vec::new(),
+            copy: None,
+        }
+    }
+
+    fn set_copy(&mut self, outer: usize, inner: usize) {
+        self.copy = Some((outer, inner));
+    }
+
+    fn get_copy(&self) -> Option<(usize, usize)> {
+        self.copy
+    }
+
+    fn get_start(&self) -> usize {
+        self.start
+    }
+
+    fn set_start(&mut self, start: usize) {
+        self.start = start;
+    }
+
+    fn get_scope_depth(&self) -> usize {
+        self.scope_depth
+    }
+
+    fn add_break(&mut self, jump: Patch) {
+        self.breaks.push(jump);
+    }
+
+    fn take_breaks(self) -> Vec<Patch> {
+        self.breaks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compile::{DeclarationKind, Parser};
+    use crate::error::ErrorLocation;
+    use crate::scanner::Scanner;
+
+    fn disassemble(source: &str) -> String {
+        let chars = source.chars().collect::<Vec<char>>();
+        let scanner = Scanner::new(chars.as_slice());
+        let parser = Parser::new(scanner.parse(), std::io::sink());
+        let closure = match parser.compile() {
+            Ok((closure, _, _)) => closure,
+            Err(_) => panic!("Expected the program to compile."),
+        };
+
+        let mut buffer: Vec<u8> = Vec::new();
+        closure
+            .get_function()
+            .get_chunk()
+            .disassemble("test chunk", &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    #[test]
+    fn print_too_many_values() {
+        let values = vec!["nil"; 256].join(", ");
+        let source = format!("print {};", values);
+        let chars = source.chars().collect::<Vec<char>>();
+        let scanner = Scanner::new(chars.as_slice());
+        let parser = Parser::new(scanner.parse(), std::io::sink());
+        let (errors, _, _) = match parser.compile() {
+            Ok(_) => panic!("Expected the program not to compile."),
+            Err(result) => result,
+        };
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].get_message(), "Can't print more than 255 values.");
+    }
+
+    #[test]
+    fn declarations() {
+        let source = "class A {\n  f() {}\n}\nfun g() { return 1; }\nvar a = g();\nprint a;\n";
+        let chars = source.chars().collect::<Vec<char>>();
+        let scanner = Scanner::new(chars.as_slice());
+        let parser = Parser::new(scanner.parse(), std::io::sink());
+        let declarations = parser.declarations();
+
+        let spans = declarations
+            .iter()
+            .map(|(range, kind)| (&source[range.clone()], *kind))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            spans,
+            vec![
+                ("class A {\n  f() {}\n}", DeclarationKind::Class),
+                ("fun g() { return 1; }", DeclarationKind::Function),
+                ("var a = g();", DeclarationKind::Variable),
+                ("print a;", DeclarationKind::Statement),
+            ]
+        );
+    }
+
+    #[test]
+    fn declarations_with_errors() {
+        let source = "var a = ;\nprint a\nfun f() {}";
+        let chars = source.chars().collect::<Vec<char>>();
+        let scanner = Scanner::new(chars.as_slice());
+        let parser = Parser::new(scanner.parse(), std::io::sink());
+        let declarations = parser.declarations();
+
+        assert_eq!(
+            declarations,
+            vec![
+                (0..9, DeclarationKind::Variable),
+                (10..17, DeclarationKind::Statement),
+                (18..28, DeclarationKind::Function),
+            ]
+        );
+    }
+
+    fn unused_locals(source: &str) -> Vec<String> {
+        let chars = source.chars().collect::<Vec<char>>();
+        let scanner = Scanner::new(chars.as_slice());
+        let parser = Parser::new(scanner.parse(), std::io::sink());
+        parser
+            .warnings()
+            .iter()
+            .map(|w| w.get_message().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn warn_about_unused_locals() {
+        let source = "{\n  var a = 1;\n  var b = 2;\n  print b;\n}\nfun f() {\n  var c;\n}\n";
+        assert_eq!(
+            unused_locals(source),
+            vec![
+                "Local variable 'a' is never read.",
+                "Local variable 'c' is never read.",
+            ]
+        );
+    }
+
+    #[test]
+    fn no_warning_for_used_locals() {
+        let source = "\
+            fun f(unused) {\n\
+              var a = 1;\n\
+              fun g() { return a; }\n\
+              return g;\n\
+            }\n\
+            for (var i = 0; i < 1; i = i + 1) {}\n\
+            class A {}\n\
+            class B < A { f() { return super.f; } }\n\
+            var x = 1;\n\
+            switch (x) { case 1: print x; }\n";
+        assert_eq!(unused_locals(source), Vec::<String>::new());
+    }
+
+    #[test]
+    fn assigning_does_not_count_as_reading() {
+        let source = "{\n  var a;\n  a = 1;\n}\n";
+        assert_eq!(
+            unused_locals(source),
+            vec!["Local variable 'a' is never read."]
+        );
+    }
+
+    #[test]
+    fn compile_errors() {
+        let source = "var a = 1;\nprint a\nvar b = ;";
+        let chars = source.chars().collect::<Vec<char>>();
+        let scanner = Scanner::new(chars.as_slice());
+        let parser = Parser::new(scanner.parse(), Vec::new());
+        let (errors, _, output) = match parser.compile() {
+            Ok(_) => panic!("Expected the program not to compile."),
+            Err(result) => result,
+        };
+
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].get_line(), 3);
+        assert_eq!(errors[0].get_column(), 1);
+        assert_eq!(
+            errors[0].get_location(),
+            &ErrorLocation::Token(String::from("var"))
+        );
+        assert_eq!(errors[0].get_message(), "Expect ';' after value.");
+        assert_eq!(errors[1].get_line(), 3);
+        assert_eq!(errors[1].get_column(), 9);
+        assert_eq!(errors[1].get_message(), "Expect expression.");
+        assert_eq!(
+            errors[1].to_string(),
+            "[line 3] Error at ';': Expect expression."
+        );
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "[line 3] Error at 'var': Expect ';' after value.\n\
+             [line 3] Error at ';': Expect expression.\n"
+        );
+    }
+
+    #[test]
+    fn fold_negated_comparison() {
+        let expected = "== test chunk ==
+0000    1 GetGlobal    0 'a'
+0002    | GetGlobal    1 'b'
+0004    | NotEqual
+0005    | Print
+0006    | Nil
+0007    | Return
+";
+        assert_eq!(disassemble("print !(a == b);"), expected);
+    }
+
+    #[test]
+    fn keep_negation_of_ordering_comparison() {
+        // !(a < b) is true if either operand is NaN, but a >= b is false then.
+        let expected = "== test chunk ==
+0000    1 GetGlobal    0 'a'
+0002    | GetGlobal    1 'b'
+0004    | Less
+0005    | Not
+0006    | Print
+0007    | Nil
+0008    | Return
+";
+        assert_eq!(disassemble("print !(a < b);"), expected);
+    }
+
+    #[test]
+    fn keep_negation_at_jump_target() {
+        // The short-circuit jump lands on the negation, so it cannot be folded into the comparison.
+        let expected = "== test chunk ==
+0000    1 GetGlobal    0 'a'
+0002    | JumpIfFalse    2 -> 11
+0005    | Pop
+0006    | GetGlobal    1 'b'
+0008    | GetGlobal    2 'c'
+0010    | Equal
+0011    | Not
+0012    | Print
+0013    | Nil
+0014    | Return
+";
+        assert_eq!(disassemble("print !(a and b == c);"), expected);
+    }
+
+    #[test]
+    fn capture_local_beyond_256() {
+        // Closures refer to captured locals with a single index, so only the first 256 locals of a
+        // function can be captured.
+        let declarations = (0..300)
+            .map(|i| format!("var l{};", i))
+            .collect::<Vec<String>>()
+            .join("\n");
+        let source = format!(
+            "fun f() {{ {} fun g() {{ return l1 + l299; }} }}",
+            declarations
+        );
+        let chars = source.chars().collect::<Vec<char>>();
+        let scanner = Scanner::new(chars.as_slice());
+        let parser = Parser::new(scanner.parse(), std::io::sink());
+        let errors = match parser.compile() {
+            Ok(_) => panic!("Expected the program not to compile."),
+            Err((errors, _, _)) => errors,
+        };
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].get_message(),
+            "Can't capture a local variable beyond the first 256 of a function."
+        );
+    }
+}
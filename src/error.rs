@@ -0,0 +1,175 @@
+//! This module contains the errors that are reported for Lox programs, which fail to compile or
+//! fail at run time. They allow users of this crate to act on errors without having to parse the
+//! error output.
+
+/// The token a compile error was found at.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ErrorLocation {
+    /// The lexeme of the token.
+    Token(String),
+    /// The end of the source.
+    End,
+    /// The scanner could not make a token, e.g. for an unterminated string.
+    None,
+}
+
+/// An error found by the compiler. Compiling a program can result in several of them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompileError {
+    line: u32,
+    column: u32,
+    location: ErrorLocation,
+    message: String,
+}
+
+impl CompileError {
+    pub fn new(line: u32, column: u32, location: ErrorLocation, message: String) -> Self {
+        CompileError {
+            line,
+            column,
+            location,
+            message,
+        }
+    }
+
+    pub fn get_line(&self) -> u32 {
+        self.line
+    }
+
+    /// The column of the token the error was found at, starting at 1. It is not part of the
+    /// printed error, which follows the format of clox.
+    pub fn get_column(&self) -> u32 {
+        self.column
+    }
+
+    pub fn get_location(&self) -> &ErrorLocation {
+        &self.location
+    }
+
+    pub fn get_message(&self) -> &str {
+        self.message.as_str()
+    }
+}
+
+/// Formats the error the same way the compiler reports it, e.g. "[line 1] Error at 'x': message".
+impl std::fmt::Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "[line {}] Error", self.line)?;
+        match &self.location {
+            ErrorLocation::Token(lexeme) => write!(f, " at '{}'", lexeme)?,
+            ErrorLocation::End => f.write_str(" at end")?,
+            ErrorLocation::None => {}
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+/// A problem the compiler found in a program which compiles anyway, like a local variable that
+/// is never read. Warnings are returned by rlox::warnings() and written to the compiler output by
+/// rlox::run_program_with_warnings() and rlox::run_repl().
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompileWarning {
+    line: u32,
+    column: u32,
+    message: String,
+}
+
+impl CompileWarning {
+    pub fn new(line: u32, column: u32, message: String) -> Self {
+        CompileWarning {
+            line,
+            column,
+            message,
+        }
+    }
+
+    pub fn get_line(&self) -> u32 {
+        self.line
+    }
+
+    /// The column of the token the warning is about, starting at 1.
+    pub fn get_column(&self) -> u32 {
+        self.column
+    }
+
+    pub fn get_message(&self) -> &str {
+        self.message.as_str()
+    }
+}
+
+/// Formats the warning like a compile error, e.g. "[line 1] Warning: message".
+impl std::fmt::Display for CompileWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "[line {}] Warning: {}", self.line, self.message)
+    }
+}
+
+/// An error that stopped the virtual machine. The stack trace holds the line and the name of the
+/// function of each call frame, starting with the innermost one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RuntimeError {
+    message: String,
+    stack_trace: Vec<(u32, String)>,
+}
+
+impl RuntimeError {
+    pub fn new(message: String, stack_trace: Vec<(u32, String)>) -> Self {
+        RuntimeError {
+            message,
+            stack_trace,
+        }
+    }
+
+    pub fn get_message(&self) -> &str {
+        self.message.as_str()
+    }
+
+    pub fn get_stack_trace(&self) -> &[(u32, String)] {
+        self.stack_trace.as_slice()
+    }
+}
+
+impl std::fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        for (line, name) in self.stack_trace.iter() {
+            writeln!(f, "[line {}] in {}(): {}", line, name, self.message)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// An error found while loading a chunk that was serialized with Chunk::serialize().
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeserializeError {
+    /// The bytes end before the chunk does.
+    Truncated,
+    /// The bytes do not start with the header of a serialized chunk.
+    NotAChunk,
+    /// The chunk was serialized with another version of the format, which is given.
+    VersionMismatch(u8),
+    /// The bytes do not describe a valid chunk, e.g. they contain an unknown opcode.
+    Invalid(String),
+}
+
+impl std::fmt::Display for DeserializeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            DeserializeError::Truncated => f.write_str("The serialized chunk is truncated."),
+            DeserializeError::NotAChunk => f.write_str("The bytes are not a serialized chunk."),
+            DeserializeError::VersionMismatch(version) => write!(
+                f,
+                "The chunk was serialized with format version {}, expected version {}.",
+                version,
+                crate::chunk::FORMAT_VERSION
+            ),
+            DeserializeError::Invalid(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for DeserializeError {}
@@ -1,10 +1,13 @@
 use std::cell::RefCell;
 use std::fmt::{Debug, Display, Formatter};
+use std::io::{BufRead, Write};
 use std::ops::{Deref, DerefMut};
 use std::rc::Rc;
 
 use crate::chunk::{Chunk, ChunkBuilder};
+use crate::gc::{Trace, Tracer};
 use crate::intern_string::Symbol;
+use crate::string_builder::StringBuilderRef;
 use crate::value::Value;
 
 pub struct Function {
@@ -12,7 +15,7 @@ pub struct Function {
 }
 
 impl Function {
-    fn new(
+    pub(crate) fn new(
         name: Option<Symbol>,
         arity: usize,
         chunk: Chunk,
@@ -25,25 +28,41 @@ impl Function {
         }
     }
 
-    pub fn get_name(&self) -> Option<&Symbol> {
+    pub(crate) fn get_name(&self) -> Option<&Symbol> {
         self.inner.get_name()
     }
 
-    pub fn get_arity(&self) -> usize {
+    pub(crate) fn get_arity(&self) -> usize {
         self.inner.get_arity()
     }
 
+    /// Returns the chunk holding the bytecode of this function.
     pub fn get_chunk(&self) -> &Chunk {
         self.inner.get_chunk()
     }
 
-    pub fn get_upvalue_count(&self) -> usize {
+    pub(crate) fn get_upvalue_count(&self) -> usize {
         self.inner.get_upvalue_count()
     }
 
-    pub fn get_kind(&self) -> FunctionType {
+    pub(crate) fn get_kind(&self) -> FunctionType {
         self.inner.get_kind()
     }
+
+    /// Writes a disassemble of the chunk of this function to the given writer, followed by those
+    /// of the functions declared in it, recursively.
+    pub fn disassemble(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        let chunk = self.get_chunk();
+        let name = self.get_name().map_or("<script>", |s| s.as_str());
+        chunk.disassemble(name, writer)?;
+
+        for constant in chunk.get_constants() {
+            if let Value::Function(function) = constant {
+                function.disassemble(writer)?;
+            }
+        }
+        Ok(())
+    }
 }
 
 impl Clone for Function {
@@ -139,7 +158,7 @@ pub struct FunctionBuilder {
 }
 
 impl FunctionBuilder {
-    pub fn new(name: Option<Symbol>, arity: usize, kind: FunctionType) -> Self {
+    pub(crate) fn new(name: Option<Symbol>, arity: usize, kind: FunctionType) -> Self {
         FunctionBuilder {
             name,
             arity,
@@ -149,39 +168,41 @@ impl FunctionBuilder {
         }
     }
 
-    pub fn get_name(&self) -> Option<&Symbol> {
+    #[allow(dead_code)]
+    pub(crate) fn get_name(&self) -> Option<&Symbol> {
         self.name.as_ref()
     }
 
-    pub fn get_arity(&self) -> usize {
+    pub(crate) fn get_arity(&self) -> usize {
         self.arity
     }
 
-    pub fn get_kind(&self) -> FunctionType {
+    pub(crate) fn get_kind(&self) -> FunctionType {
         self.kind
     }
 
-    pub fn set_kind(&mut self, kind: FunctionType) {
+    pub(crate) fn set_kind(&mut self, kind: FunctionType) {
         self.kind = kind;
     }
 
-    pub fn inc_arity(&mut self, amount: usize) {
+    pub(crate) fn inc_arity(&mut self, amount: usize) {
         self.arity += amount;
     }
 
-    pub fn set_name(&mut self, name: Symbol) {
+    pub(crate) fn set_name(&mut self, name: Symbol) {
         self.name = Some(name);
     }
 
-    pub fn get_upvalue_count(&self) -> usize {
+    #[allow(dead_code)]
+    pub(crate) fn get_upvalue_count(&self) -> usize {
         self.upvalue_count
     }
 
-    pub fn inc_upvalue_count(&mut self) {
+    pub(crate) fn inc_upvalue_count(&mut self) {
         self.upvalue_count += 1;
     }
 
-    pub fn build(self) -> Function {
+    pub(crate) fn build(self) -> Function {
         Function::new(
             self.name,
             self.arity,
@@ -212,6 +233,8 @@ pub enum FunctionType {
     Script,
     Method,
     Initializer,
+    Getter,
+    StaticMethod,
 }
 
 impl Display for FunctionType {
@@ -220,18 +243,61 @@ impl Display for FunctionType {
     }
 }
 
-#[derive(Copy, Clone)]
-pub struct NativeFunction {
-    function: fn(args: &[Value]) -> Value,
+/// The result of a native function. An error message is reported as a runtime error.
+pub type NativeResult = Result<Value, String>;
+
+/// The signature of native functions, which get the arguments they are called with.
+pub type NativeFn = fn(&[Value]) -> NativeResult;
+
+/// The signature of natives that read from the input of the VM, like read_line().
+pub(crate) type InputNativeFn = fn(&mut dyn BufRead, &[Value]) -> NativeResult;
+
+/// The signature of natives that get the number of call frames of the VM, like depth().
+pub(crate) type DepthNativeFn = fn(usize, &[Value]) -> NativeResult;
+
+/// The signature of natives that print, like write(). They pass the values to print to the
+/// function they get, which prints them like a print statement, but without a line break.
+pub(crate) type WriteNativeFn = fn(&mut dyn FnMut(&Value), &[Value]) -> NativeResult;
+
+#[derive(Clone, Copy)]
+enum NativeBody {
+    Function(NativeFn),
+    ReadsInput(InputNativeFn),
+    ReadsDepth(DepthNativeFn),
+    Writes(WriteNativeFn),
+}
+
+struct NativeFunctionInner {
+    body: NativeBody,
     arity: usize,
+    // A variadic native takes at least arity arguments.
+    variadic: bool,
+}
+
+/// Like the other objects a Value can hold, natives are referenced through an Rc, which keeps
+/// Value small.
+#[derive(Clone)]
+pub struct NativeFunction {
+    inner: Rc<NativeFunctionInner>,
 }
 
 impl PartialEq for NativeFunction {
     fn eq(&self, other: &Self) -> bool {
-        std::ptr::eq(
-            self.function as *const fn(&[Value]) -> Value,
-            other.function as *const _,
-        )
+        match (self.inner.body, other.inner.body) {
+            (NativeBody::Function(a), NativeBody::Function(b)) => {
+                std::ptr::eq(a as *const NativeFn, b as *const _)
+            }
+            (NativeBody::ReadsInput(a), NativeBody::ReadsInput(b)) => {
+                std::ptr::eq(a as *const InputNativeFn, b as *const _)
+            }
+            (NativeBody::ReadsDepth(a), NativeBody::ReadsDepth(b)) => {
+                std::ptr::eq(a as *const DepthNativeFn, b as *const _)
+            }
+            (NativeBody::Writes(a), NativeBody::Writes(b)) => {
+                std::ptr::eq(a as *const WriteNativeFn, b as *const _)
+            }
+            _ => false,
+        }
     }
 }
 
@@ -244,65 +310,358 @@ impl Debug for NativeFunction {
 }
 
 impl NativeFunction {
-    pub fn new(function: fn(&[Value]) -> Value, arity: usize) -> Self {
-        NativeFunction { function, arity }
+    pub fn new(function: NativeFn, arity: usize) -> Self {
+        NativeFunction {
+            inner: Rc::new(NativeFunctionInner {
+                body: NativeBody::Function(function),
+                arity,
+                variadic: false,
+            }),
+        }
+    }
+
+    pub(crate) fn reading_input(function: InputNativeFn, arity: usize) -> Self {
+        NativeFunction {
+            inner: Rc::new(NativeFunctionInner {
+                body: NativeBody::ReadsInput(function),
+                arity,
+                variadic: false,
+            }),
+        }
+    }
+
+    pub(crate) fn reading_depth(function: DepthNativeFn, arity: usize) -> Self {
+        NativeFunction {
+            inner: Rc::new(NativeFunctionInner {
+                body: NativeBody::ReadsDepth(function),
+                arity,
+                variadic: false,
+            }),
+        }
+    }
+
+    /// Creates a native that prints. If it is variadic, it takes at least arity arguments.
+    pub(crate) fn writing(function: WriteNativeFn, arity: usize, variadic: bool) -> Self {
+        NativeFunction {
+            inner: Rc::new(NativeFunctionInner {
+                body: NativeBody::Writes(function),
+                arity,
+                variadic,
+            }),
+        }
     }
 
-    pub fn call(&self, args: &[Value]) -> Value {
-        (self.function)(args)
+    pub(crate) fn reads_input(&self) -> bool {
+        matches!(self.inner.body, NativeBody::ReadsInput(_))
     }
 
+    /// The input, the call depth and the output are only used by natives that need them.
+    pub(crate) fn call(
+        &self,
+        args: &[Value],
+        input: &mut dyn BufRead,
+        depth: usize,
+        output: &mut dyn FnMut(&Value),
+    ) -> NativeResult {
+        match self.inner.body {
+            NativeBody::Function(function) => function(args),
+            NativeBody::ReadsInput(function) => function(input, args),
+            NativeBody::ReadsDepth(function) => function(depth, args),
+            NativeBody::Writes(function) => function(output, args),
+        }
+    }
+
+    /// The number of arguments the native takes, or the least number if it is variadic.
     pub fn get_arity(&self) -> usize {
-        self.arity
+        self.inner.arity
+    }
+
+    pub fn is_variadic(&self) -> bool {
+        self.inner.variadic
+    }
+
+    pub(crate) fn accepts(&self, arg_count: usize) -> bool {
+        arg_count == self.inner.arity || (self.inner.variadic && arg_count > self.inner.arity)
     }
 }
 
-pub fn clock(_: &[Value]) -> Value {
+pub fn clock(_: &[Value]) -> NativeResult {
     let start = std::time::SystemTime::now();
     let since_the_epoch = start
         .duration_since(std::time::UNIX_EPOCH)
         .expect("Time went backwards");
-    Value::Double(since_the_epoch.as_secs_f64())
+    Ok(Value::Double(since_the_epoch.as_secs_f64()))
 }
 
-#[derive(Clone, PartialEq, Eq, Debug)]
-pub struct Closure {
+/// Returns the number of characters of a string or the number of elements of a list.
+pub fn len(args: &[Value]) -> NativeResult {
+    match &args[0] {
+        Value::String(string) => Ok(Value::Double(string.chars().count() as f64)),
+        Value::List(list) => Ok(Value::Double(list.len() as f64)),
+        _ => Err(String::from("Argument must be a string or a list.")),
+    }
+}
+
+/// Returns the character at the given index of a string as a string of its own.
+pub fn char_at(args: &[Value]) -> NativeResult {
+    let string = match &args[0] {
+        Value::String(string) => string,
+        _ => return Err(String::from("First argument must be a string.")),
+    };
+    let index = match args[1] {
+        Value::Double(index) if index.fract() == 0.0 && index >= 0.0 => index as usize,
+        Value::Double(_) => return Err(String::from("Index must be a non-negative integer.")),
+        _ => return Err(String::from("Index must be a number.")),
+    };
+    match string.chars().nth(index) {
+        Some(c) => Ok(Value::string(c.encode_utf8(&mut [0; 4]))),
+        None => Err(String::from("Index out of range.")),
+    }
+}
+
+/// Returns the text print would show for the value.
+pub fn str(args: &[Value]) -> NativeResult {
+    Ok(Value::string(&args[0].to_string()))
+}
+
+/// Parses a string into a number. Surrounding whitespace is ignored.
+pub fn num(args: &[Value]) -> NativeResult {
+    let string = match &args[0] {
+        Value::String(string) => string,
+        _ => return Err(String::from("Argument must be a string.")),
+    };
+    match string.trim().parse::<f64>() {
+        Ok(number) if number.is_finite() => Ok(Value::Double(number)),
+        _ => Err(format!("Can't convert '{}' to a number.", string.as_str())),
+    }
+}
+
+/// Returns the class of an instance.
+pub fn classof(args: &[Value]) -> NativeResult {
+    match &args[0] {
+        Value::Instance(instance) => Ok(Value::Class(
+            instance.get_instance().get_clazz_ref().clone(),
+        )),
+        _ => Err(String::from("Argument must be an instance.")),
+    }
+}
+
+/// Returns the name of a class.
+pub fn classname(args: &[Value]) -> NativeResult {
+    match &args[0] {
+        // The name is interned already, so it is returned as is.
+        Value::Class(class) => Ok(Value::String(class.get_clazz().get_name().clone())),
+        _ => Err(String::from("Argument must be a class.")),
+    }
+}
+
+/// Returns the number of parameters of a callable. The arity of a native is the number of
+/// arguments it takes, or the least number for a variadic native like printf(). Calling a class
+/// passes the arguments to its initializer, so a class has the arity of its init() method, or 0
+/// if it has none.
+pub fn arity(args: &[Value]) -> NativeResult {
+    let arity = match &args[0] {
+        Value::Closure(closure) => closure.get_function().get_arity(),
+        Value::BoundMethod(bound) => bound.get_closure().get_function().get_arity(),
+        Value::NativeFunction(native) => native.get_arity(),
+        Value::Class(class) => class
+            .get_clazz()
+            .get_methods()
+            .find(|(name, _)| name.as_str() == "init")
+            .map_or(0, |(_, init)| init.get_function().get_arity()),
+        _ => return Err(String::from("Argument must be callable.")),
+    };
+    Ok(Value::Double(arity as f64))
+}
+
+/// Reads a line from the input without its line break. Returns nil at the end of the input.
+pub fn read_line(input: &mut dyn BufRead, _: &[Value]) -> NativeResult {
+    let mut line = String::new();
+    match input.read_line(&mut line) {
+        Ok(0) => Ok(Value::Nil),
+        Ok(_) => {
+            if line.ends_with('\n') {
+                line.pop();
+                if line.ends_with('\r') {
+                    line.pop();
+                }
+            }
+            Ok(Value::string(&line))
+        }
+        Err(_) => Err(String::from("Could not read from the input.")),
+    }
+}
+
+/// Returns the number of call frames, which is 1 at the top level of a script.
+pub fn depth(depth: usize, _: &[Value]) -> NativeResult {
+    Ok(Value::Double(depth as f64))
+}
+
+/// Creates an empty string builder.
+pub fn string_builder(_: &[Value]) -> NativeResult {
+    Ok(Value::StringBuilder(StringBuilderRef::new()))
+}
+
+/// Appends the text print would show for the value to a string builder.
+pub fn append(args: &[Value]) -> NativeResult {
+    match &args[0] {
+        Value::StringBuilder(builder) => {
+            match &args[1] {
+                Value::String(s) => builder.append(s),
+                value => builder.append(&value.to_string()),
+            }
+            Ok(Value::Nil)
+        }
+        _ => Err(String::from("First argument must be a string builder.")),
+    }
+}
+
+/// Returns the text a string builder has built so far.
+pub fn build(args: &[Value]) -> NativeResult {
+    match &args[0] {
+        Value::StringBuilder(builder) => Ok(Value::string(&builder.build())),
+        _ => Err(String::from("Argument must be a string builder.")),
+    }
+}
+
+/// Prints the value without a line break.
+pub fn write(output: &mut dyn FnMut(&Value), args: &[Value]) -> NativeResult {
+    output(&args[0]);
+    Ok(Value::Nil)
+}
+
+/// Prints the format string without a line break, with each {} replaced by the text print would
+/// show for the next argument. {{ and }} print a single brace.
+pub fn printf(output: &mut dyn FnMut(&Value), args: &[Value]) -> NativeResult {
+    let format = match &args[0] {
+        Value::String(format) => format,
+        _ => return Err(String::from("First argument must be a string.")),
+    };
+
+    let mut text = String::new();
+    let mut values = args[1..].iter();
+    let mut chars = format.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('{', Some('}')) => {
+                chars.next();
+                match values.next() {
+                    Some(value) => text.push_str(&value.to_string()),
+                    None => return Err(String::from("Too few arguments for the format string.")),
+                }
+            }
+            ('{', Some('{')) | ('}', Some('}')) => {
+                chars.next();
+                text.push(c);
+            }
+            _ => text.push(c),
+        }
+    }
+    if values.next().is_some() {
+        return Err(String::from("Too many arguments for the format string."));
+    }
+
+    output(&Value::string(&text));
+    Ok(Value::Nil)
+}
+
+// Returns the arguments of a math native as numbers.
+fn numbers<const N: usize>(args: &[Value]) -> Result<[f64; N], String> {
+    let mut numbers = [0.0; N];
+    for (number, arg) in numbers.iter_mut().zip(args) {
+        match arg {
+            Value::Double(d) => *number = *d,
+            _ if N == 1 => return Err(String::from("Argument must be a number.")),
+            _ => return Err(String::from("Arguments must be numbers.")),
+        }
+    }
+    Ok(numbers)
+}
+
+pub fn sqrt(args: &[Value]) -> NativeResult {
+    let [x] = numbers(args)?;
+    Ok(Value::Double(x.sqrt()))
+}
+
+pub fn floor(args: &[Value]) -> NativeResult {
+    let [x] = numbers(args)?;
+    Ok(Value::Double(x.floor()))
+}
+
+pub fn ceil(args: &[Value]) -> NativeResult {
+    let [x] = numbers(args)?;
+    Ok(Value::Double(x.ceil()))
+}
+
+pub fn abs(args: &[Value]) -> NativeResult {
+    let [x] = numbers(args)?;
+    Ok(Value::Double(x.abs()))
+}
+
+pub fn pow(args: &[Value]) -> NativeResult {
+    let [x, y] = numbers(args)?;
+    Ok(Value::Double(x.powf(y)))
+}
+
+pub fn min(args: &[Value]) -> NativeResult {
+    let [x, y] = numbers(args)?;
+    Ok(Value::Double(x.min(y)))
+}
+
+pub fn max(args: &[Value]) -> NativeResult {
+    let [x, y] = numbers(args)?;
+    Ok(Value::Double(x.max(y)))
+}
+
+#[derive(PartialEq, Eq, Debug)]
+struct ClosureInner {
     function: Function,
     upvalues: Vec<ObjUpvalue>,
 }
 
+/// Closures are copied onto the stack and into call frames all the time, so they are shared
+/// through an Rc instead of cloning their upvalues each time.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Closure {
+    inner: Rc<ClosureInner>,
+}
+
 impl Closure {
-    pub fn new(function: Function) -> Self {
-        Closure {
-            function,
-            upvalues: Vec::new(),
-        }
+    pub(crate) fn new(function: Function) -> Self {
+        Closure::with_upvalues(function, Vec::new())
     }
 
-    pub fn get_function(&self) -> &Function {
-        &self.function
+    pub(crate) fn with_upvalues(function: Function, upvalues: Vec<ObjUpvalue>) -> Self {
+        #[cfg(feature = "profile_allocations")]
+        crate::allocations::count_closure();
+
+        Closure {
+            inner: Rc::new(ClosureInner { function, upvalues }),
+        }
     }
 
-    pub fn push_upvalue(&mut self, value: ObjUpvalue) {
-        self.upvalues.push(value);
+    pub(crate) fn get_function(&self) -> &Function {
+        &self.inner.function
     }
 
-    pub fn get_upvalue_at(&self, index: usize) -> &ObjUpvalue {
-        &self.upvalues[index]
+    pub(crate) fn get_upvalue_at(&self, index: usize) -> &ObjUpvalue {
+        &self.inner.upvalues[index]
     }
 
-    pub fn get_upvalue_at_mut(&mut self, index: usize) -> &mut ObjUpvalue {
-        &mut self.upvalues[index]
+    pub(crate) fn get_upvalues(&self) -> &[ObjUpvalue] {
+        &self.inner.upvalues
     }
 
-    pub fn upvalue_count(&self) -> usize {
-        self.function.get_upvalue_count()
+    /// Writes a disassemble of the function of this closure and all functions declared in it to
+    /// the given writer.
+    pub fn disassemble(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        self.get_function().disassemble(writer)
     }
 }
 
 impl Display for Closure {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.function)
+        write!(f, "{}", self.inner.function)
     }
 }
 
@@ -355,17 +714,17 @@ pub struct ObjUpvalue {
 }
 
 impl ObjUpvalue {
-    pub fn new(location: UpvalueLocation) -> Self {
+    pub(crate) fn new(location: UpvalueLocation) -> Self {
         ObjUpvalue {
             inner: Rc::new(RefCell::new(ObjUpvalueInner::new(location))),
         }
     }
 
-    pub fn get_location(&self) -> UpvalueLocation {
+    pub(crate) fn get_location(&self) -> UpvalueLocation {
         self.inner.deref().borrow().get_location().clone()
     }
 
-    pub fn set_location_value(&mut self, value: Value) {
+    pub(crate) fn set_location_value(&self, value: Value) {
         let mut borrow = self.inner.deref().borrow_mut();
         let location = borrow.get_location_mut();
         match location {
@@ -374,7 +733,28 @@ impl ObjUpvalue {
         }
     }
 
-    pub fn set_location(&mut self, location: UpvalueLocation) {
+    pub(crate) fn set_location(&self, location: UpvalueLocation) {
         self.inner.deref().borrow_mut().set_location(location);
     }
+
+    pub(crate) fn traced(&self) -> Rc<dyn Trace> {
+        self.inner.clone()
+    }
+}
+
+// An open upvalue refers to a slot of the stack, which is marked by the VM.
+impl Trace for RefCell<ObjUpvalueInner> {
+    fn trace(&self, tracer: &mut Tracer) {
+        if let UpvalueLocation::Heap(value) = self.borrow().get_location() {
+            tracer.mark_value(value);
+        }
+    }
+
+    fn clear(&self) {
+        if let Ok(mut upvalue) = self.try_borrow_mut() {
+            if let UpvalueLocation::Heap(_) = upvalue.get_location() {
+                upvalue.set_location(UpvalueLocation::Heap(Rc::new(Value::Nil)));
+            }
+        }
+    }
 }
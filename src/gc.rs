@@ -0,0 +1,229 @@
+//! This module reclaims objects which are only kept alive by reference cycles.
+//!
+//! Objects are reference counted, so they are freed as soon as nothing refers to them any more,
+//! except if they refer to each other, e.g. an instance holding itself in a field or a local
+//! function capturing itself. Every cycle passes through an object which can be changed after it
+//! has been created: an instance, a class, a list, a map or an upvalue. The VM tracks these
+//! objects in a Heap and collects them once enough of them have been allocated: Starting from the
+//! values the VM holds, every reachable object is marked. Each tracked object that has not been
+//! marked drops the values it refers to, which breaks the cycles it is part of, so reference
+//! counting frees it.
+
+use std::collections::HashSet;
+use std::rc::{Rc, Weak};
+
+use crate::function::{Closure, ObjUpvalue};
+use crate::value::Value;
+
+/// The number of tracked objects at which the first collection happens. After each collection the
+/// next one happens once twice as many objects as survived it are tracked, but not before this
+/// many are.
+const INITIAL_COLLECTION_THRESHOLD: usize = 16 * 1024;
+
+/// An object which can be part of a reference cycle.
+pub(crate) trait Trace {
+    /// Marks the values this object refers to.
+    fn trace(&self, tracer: &mut Tracer);
+
+    /// Drops the values this object refers to. It is only called on unreachable objects. An object
+    /// which is still borrowed is in use by the VM, so it is left alone.
+    fn clear(&self);
+}
+
+/// Marks the objects reachable from the values it is given.
+pub(crate) struct Tracer {
+    marked: HashSet<*const ()>,
+    // The objects which have been marked, but whose values have not been marked yet.
+    gray: Vec<Rc<dyn Trace>>,
+}
+
+impl Tracer {
+    pub(crate) fn new() -> Self {
+        Tracer {
+            marked: HashSet::new(),
+            gray: Vec::new(),
+        }
+    }
+
+    pub(crate) fn mark_value(&mut self, value: &Value) {
+        match value {
+            Value::Closure(closure) => self.mark_closure(closure),
+            Value::Class(clazz) => self.mark_object(clazz.traced()),
+            Value::Instance(instance) => self.mark_object(instance.traced()),
+            Value::BoundMethod(bound) => {
+                self.mark_value(bound.get_receiver());
+                self.mark_closure(bound.get_closure());
+            }
+            Value::List(list) => self.mark_object(list.traced()),
+            Value::Map(map) => self.mark_object(map.traced()),
+            // Functions only hold constants and string builders only hold text, neither of which
+            // are tracked objects.
+            Value::Bool(_)
+            | Value::Double(_)
+            | Value::String(_)
+            | Value::Function(_)
+            | Value::NativeFunction(_)
+            | Value::StringBuilder(_)
+            | Value::Nil => {}
+        }
+    }
+
+    pub(crate) fn mark_closure(&mut self, closure: &Closure) {
+        for upvalue in closure.get_upvalues() {
+            self.mark_upvalue(upvalue);
+        }
+    }
+
+    pub(crate) fn mark_upvalue(&mut self, upvalue: &ObjUpvalue) {
+        self.mark_object(upvalue.traced());
+    }
+
+    fn mark_object(&mut self, object: Rc<dyn Trace>) {
+        if self.marked.insert(Rc::as_ptr(&object) as *const ()) {
+            self.gray.push(object);
+        }
+    }
+
+    fn is_marked(&self, object: &Weak<dyn Trace>) -> bool {
+        self.marked.contains(&(Weak::as_ptr(object) as *const ()))
+    }
+
+    // Marks everything reachable from the objects marked so far.
+    fn trace_references(&mut self) {
+        while let Some(object) = self.gray.pop() {
+            object.trace(self);
+        }
+    }
+}
+
+/// The objects allocated by a VM which can be part of reference cycles.
+pub(crate) struct Heap {
+    objects: Vec<Weak<dyn Trace>>,
+    next_collection: usize,
+}
+
+impl Heap {
+    pub(crate) fn new() -> Self {
+        Heap {
+            objects: Vec::new(),
+            next_collection: INITIAL_COLLECTION_THRESHOLD,
+        }
+    }
+
+    pub(crate) fn track(&mut self, object: Rc<dyn Trace>) {
+        self.objects.push(Rc::downgrade(&object));
+    }
+
+    pub(crate) fn needs_collection(&self) -> bool {
+        self.objects.len() >= self.next_collection
+    }
+
+    /// Frees the tracked objects the tracer has not reached from its roots. Returns the number of
+    /// objects freed, which includes the objects freed by reference counting since the last
+    /// collection.
+    pub(crate) fn collect(&mut self, mut tracer: Tracer) -> usize {
+        tracer.trace_references();
+
+        let unreachable = self
+            .objects
+            .iter()
+            .filter(|object| !tracer.is_marked(object))
+            .filter_map(|object| object.upgrade())
+            .collect::<Vec<Rc<dyn Trace>>>();
+        for object in unreachable.iter() {
+            object.clear();
+        }
+        drop(unreachable);
+
+        let before = self.objects.len();
+        self.objects.retain(|object| object.strong_count() > 0);
+        self.next_collection = INITIAL_COLLECTION_THRESHOLD.max(2 * self.objects.len());
+        before - self.objects.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::VM;
+
+    // Runs the sources one after another in the same VM and returns how many objects a collection
+    // afterwards frees, along with the printed output.
+    fn collect_after(sources: &[&str]) -> (usize, String) {
+        let mut vm = VM::builder().build(Vec::new(), Vec::new());
+        for source in sources {
+            assert!(crate::run_source(&mut vm, source, std::io::sink()).is_ok());
+        }
+        let freed = vm.collect_garbage();
+        let (output, _) = vm.into_output();
+        (freed, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn collect_instance_cycles() {
+        let (freed, _) = collect_after(&["class Node {}
+            for (var i = 0; i < 100; i = i + 1) {
+                var a = Node();
+                var b = Node();
+                a.next = b;
+                b.next = a;
+            }"]);
+
+        assert_eq!(freed, 200);
+    }
+
+    #[test]
+    fn collect_closure_capturing_itself() {
+        let (freed, _) = collect_after(&["for (var i = 0; i < 10; i = i + 1) {
+                fun f() { return f; }
+            }"]);
+
+        // Each function holds the upvalue it is captured in.
+        assert_eq!(freed, 10);
+    }
+
+    #[test]
+    fn collect_list_and_map_cycles() {
+        let (freed, _) = collect_after(&["{
+                var list = [nil];
+                list[0] = list;
+                var map = {\"self\": nil};
+                map[\"self\"] = map;
+                var outer = [{\"list\": nil}];
+                outer[0][\"list\"] = outer;
+            }"]);
+
+        assert_eq!(freed, 4);
+    }
+
+    #[test]
+    fn keep_reachable_cycles() {
+        let (freed, output) = collect_after(&[
+            "class Node {}
+            var a = Node();
+            a.me = a;
+            var list = [a];
+            list[0].list = list;",
+            "print a.me == a;
+            print a.list[0] == a;",
+        ]);
+
+        assert_eq!(freed, 0);
+        assert_eq!(output, "true\ntrue\n");
+    }
+
+    #[test]
+    fn collect_while_running() {
+        // More cycles than fit below the threshold, so some are collected before the end.
+        let (freed, output) = collect_after(&["class Node {}
+            var kept = Node();
+            kept.me = kept;
+            for (var i = 0; i < 20000; i = i + 1) {
+                var a = Node();
+                a.me = a;
+            }
+            print kept.me == kept;"]);
+
+        assert!(freed < 20000);
+        assert_eq!(output, "true\n");
+    }
+}
@@ -1,30 +1,96 @@
+use std::borrow::Borrow;
 use std::fmt::Display;
 use std::hash::{Hash, Hasher};
 use std::ops::Deref;
 use std::rc::{Rc, Weak};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use ::weak_table::WeakHashSet;
 
 /// A simple string interning facility.
 ///
 /// Interned strings are turned into symbols which are immutable, but can be compared for equality
-/// in constant time, regardless of the length of a string.
+/// in constant time, regardless of the length of a string. Symbols which have not been interned,
+/// like the results of concatenations, are compared by their characters instead, so that they do
+/// not have to be hashed when they are created. So are symbols of different symbol tables.
 /// Strings are only stored as long are there any Symbols associated with the string.
 /// Strings to which no Symbol refers to any more are lazily dropped.
 #[derive(Clone, Debug)]
 pub struct Symbol {
-    intern: Rc<String>,
+    intern: Rc<SymbolString>,
+}
+
+/// The string of a symbol, together with the id of the symbol table it is interned in.
+/// It is kept next to the string, so that a symbol stays the size of a pointer.
+#[derive(Debug)]
+struct SymbolString {
+    string: String,
+    table: usize,
+}
+
+/// The table id of symbols which have not been interned.
+const NOT_INTERNED: usize = 0;
+
+// The id of the next symbol table.
+static NEXT_TABLE: AtomicUsize = AtomicUsize::new(NOT_INTERNED + 1);
+
+// A SymbolString is looked up in a symbol table by its string, so it is compared and hashed like
+// its string.
+impl PartialEq for SymbolString {
+    fn eq(&self, other: &Self) -> bool {
+        self.string == other.string
+    }
+}
+
+impl Eq for SymbolString {}
+
+impl Hash for SymbolString {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.string.hash(state);
+    }
+}
+
+impl Borrow<str> for SymbolString {
+    fn borrow(&self) -> &str {
+        &self.string
+    }
 }
 
 impl Symbol {
-    fn new(intern: Rc<String>) -> Self {
-        Symbol { intern }
+    fn new(string: String, table: usize) -> Self {
+        Symbol {
+            intern: Rc::new(SymbolString { string, table }),
+        }
+    }
+
+    /// Creates a symbol which is not part of any symbol table.
+    pub(crate) fn uninterned(name: &str) -> Self {
+        Symbol::new(String::from(name), NOT_INTERNED)
+    }
+
+    /// Creates a symbol for the concatenation of both symbols. It is not interned, because building
+    /// a long string piece by piece would hash the whole string for every piece.
+    /// The result is allocated with its final size, but both strings are still copied, so building
+    /// a string of length n by appending one character at a time takes O(n^2) time overall.
+    pub(crate) fn concat(&self, other: &Symbol) -> Self {
+        #[cfg(feature = "profile_allocations")]
+        crate::allocations::count_string();
+
+        let mut concat = String::with_capacity(self.len() + other.len());
+        concat.push_str(self);
+        concat.push_str(other);
+        Symbol::new(concat, NOT_INTERNED)
     }
 }
 
 impl PartialEq for Symbol {
     fn eq(&self, other: &Symbol) -> bool {
+        // Two symbols interned in the same table are only equal if they share their string, so the
+        // characters are only compared if one of them has not been interned or if they have been
+        // interned in different tables.
         Rc::ptr_eq(&self.intern, &other.intern)
+            || ((self.intern.table == NOT_INTERNED || self.intern.table != other.intern.table)
+                && self.intern.string == other.intern.string)
     }
 }
 
@@ -32,26 +98,39 @@ impl Eq for Symbol {}
 
 impl Hash for Symbol {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        self.intern.hash(state);
+        self.intern.string.hash(state);
     }
 }
 
 impl Deref for Symbol {
     type Target = String;
     fn deref(&self) -> &Self::Target {
-        self.intern.deref()
+        &self.intern.string
     }
 }
 
 impl Display for Symbol {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
-        f.write_str(self.intern.deref())
+        f.write_str(&self.intern.string)
     }
 }
 
-#[derive(Default)]
 pub struct SymbolTable {
-    pool: WeakHashSet<Weak<String>>,
+    pool: WeakHashSet<Weak<SymbolString>>,
+    // Reused for looking up char slices, so that only strings not yet interned need an allocation.
+    buffer: String,
+    // Tells the symbols of this table apart from those of other tables.
+    id: usize,
+}
+
+impl Default for SymbolTable {
+    fn default() -> Self {
+        SymbolTable {
+            pool: WeakHashSet::new(),
+            buffer: String::new(),
+            id: NEXT_TABLE.fetch_add(1, Ordering::Relaxed),
+        }
+    }
 }
 
 impl SymbolTable {
@@ -60,14 +139,34 @@ impl SymbolTable {
     }
 
     pub fn intern(&mut self, name: String) -> Symbol {
-        if let Some(rc) = self.pool.get(&name) {
-            Symbol::new(rc)
+        if let Some(intern) = self.pool.get(name.as_str()) {
+            Symbol { intern }
+        } else {
+            #[cfg(feature = "profile_allocations")]
+            crate::allocations::count_string();
+
+            let symbol = Symbol::new(name, self.id);
+            self.pool.insert(symbol.intern.clone());
+            symbol
+        }
+    }
+
+    pub fn intern_str(&mut self, name: &str) -> Symbol {
+        if let Some(intern) = self.pool.get(name) {
+            Symbol { intern }
         } else {
-            let rc = Rc::new(name);
-            self.pool.insert(rc.clone());
-            Symbol::new(rc)
+            self.intern(String::from(name))
         }
     }
+
+    pub fn intern_chars(&mut self, name: &[char]) -> Symbol {
+        let mut buffer = std::mem::take(&mut self.buffer);
+        buffer.clear();
+        buffer.extend(name);
+        let symbol = self.intern_str(buffer.as_str());
+        self.buffer = buffer;
+        symbol
+    }
 }
 
 #[cfg(test)]
@@ -85,10 +184,48 @@ mod tests {
         assert_eq!(msg, *symbol);
     }
 
+    #[test]
+    fn intern_str_and_chars() {
+        let mut table = SymbolTable::new();
+        let symbol = table.intern(String::from("Hello"));
+        assert_eq!(table.intern_str("Hello"), symbol);
+
+        let chars = "Hello".chars().collect::<Vec<char>>();
+        assert_eq!(table.intern_chars(chars.as_slice()), symbol);
+
+        let other = "World".chars().collect::<Vec<char>>();
+        let interned = table.intern_chars(other.as_slice());
+        assert_eq!("World", *interned);
+        assert_eq!(table.intern_str("World"), interned);
+        assert_ne!(interned, symbol);
+    }
+
+    #[test]
+    fn uninterned_symbols() {
+        let mut table = SymbolTable::new();
+        let interned = table.intern_str("Hello World");
+        let concat = Symbol::uninterned("Hello ").concat(&table.intern_str("World"));
+        assert_eq!(concat, interned);
+        assert_eq!(Symbol::uninterned("Hello World"), concat);
+        assert_ne!(Symbol::uninterned("Hello"), concat);
+
+        let mut set = HashSet::new();
+        set.insert(interned);
+        assert!(set.contains(&concat));
+    }
+
+    #[test]
+    fn symbols_of_different_tables() {
+        let mut table = SymbolTable::new();
+        let mut other_table = SymbolTable::new();
+        assert_eq!(table.intern_str("Hello"), other_table.intern_str("Hello"));
+        assert_ne!(table.intern_str("Hello"), other_table.intern_str("World"));
+    }
+
     #[test]
     fn more_strings() {
         let mut table = SymbolTable::new();
-        let literals = vec!["Hello", "42", "1337", "\"'$$%&\"", "World"];
+        let literals = ["Hello", "42", "1337", "\"'$$%&\"", "World"];
         let strings = literals
             .iter()
             .map(|s| String::from(*s))
@@ -1,16 +1,52 @@
-use std::io::Write;
+use std::io::{BufRead, Write};
+use std::ops::Range;
 
 use crate::compile::Parser;
+use crate::function::FunctionType;
+use crate::intern_string::SymbolTable;
 use crate::scanner::Scanner;
-use crate::vm::VM;
+use crate::tokens::Token;
 
+pub use crate::error::{
+    CompileError, CompileWarning, DeserializeError, ErrorLocation, RuntimeError,
+};
+// The types of the objects a Value can hold. They can be named and printed, but only the
+// interpreter can create or inspect them.
+pub use crate::chunk::{Chunk, Instruction, Instructions};
+pub use crate::classes::{BoundMethod, ClazzRef, InstanceRef};
+pub use crate::compile::DeclarationKind;
+pub use crate::function::{Closure, Function, NativeFn, NativeFunction, NativeResult};
+pub use crate::intern_string::Symbol;
+pub use crate::list::ListRef;
+pub use crate::map::MapRef;
+pub use crate::opcodes::OpCode;
+pub use crate::output::OutputSink;
+pub use crate::string_builder::StringBuilderRef;
+pub use crate::value::Value;
+pub use crate::vm::{VMBuilder, VM};
+
+#[cfg(feature = "profile_allocations")]
+pub use crate::allocations::Allocations;
+#[cfg(feature = "profile_loops")]
+pub use crate::loop_counts::{LoopCount, LoopCounts};
+
+#[cfg(feature = "profile_allocations")]
+mod allocations;
 mod chunk;
 mod classes;
 mod compile;
+mod error;
 mod function;
+mod gc;
 mod intern_string;
+mod list;
+#[cfg(feature = "profile_loops")]
+mod loop_counts;
+mod map;
 mod opcodes;
+mod output;
 mod scanner;
+mod string_builder;
 mod tokens;
 mod value;
 mod vm;
@@ -18,20 +54,45 @@ mod vm;
 #[derive(Debug)]
 pub enum Error {
     IO,
-    Compile,
-    Run,
+    Compile(Vec<CompileError>),
+    Run(RuntimeError),
+    /// The bytes passed to run_bytes() are not a program compiled by this version of rlox.
+    Load(DeserializeError),
 }
 
 impl Error {
     pub fn get_error_code(&self) -> u8 {
         match self {
             Error::IO => 74,
-            Error::Compile => 65,
-            Error::Run => 70,
+            Error::Compile(_) | Error::Load(_) => 65,
+            Error::Run(_) => 70,
         }
     }
 }
 
+/// Returns the objects allocated by programs run on this thread since the last reset.
+#[cfg(feature = "profile_allocations")]
+pub fn get_allocations() -> Allocations {
+    allocations::get()
+}
+
+#[cfg(feature = "profile_allocations")]
+pub fn reset_allocations() {
+    allocations::reset()
+}
+
+/// Returns how often the back edge of each loop was taken by programs run on this thread since
+/// the last reset.
+#[cfg(feature = "profile_loops")]
+pub fn get_loop_counts() -> LoopCounts {
+    loop_counts::get()
+}
+
+#[cfg(feature = "profile_loops")]
+pub fn reset_loop_counts() {
+    loop_counts::reset()
+}
+
 fn read_file(path: &str) -> Result<String, Error> {
     std::fs::read_to_string(path).map_err(|_| Error::IO)
 }
@@ -56,29 +117,77 @@ impl<C: Write, VO: Write, VE: Write> Output<C, VO, VE> {
     }
 }
 
+/// Compiles and runs the program in the file at path. Natives like read_line() read from stdin.
 pub fn run_program<C: Write, VO: Write, VE: Write>(
     path: &str,
     compiler_output: C,
     vm_output: VO,
     vm_err: VE,
+) -> (Result<(), Error>, Output<C, VO, VE>) {
+    run_program_from(path, None, false, compiler_output, vm_output, vm_err)
+}
+
+/// Like run_program(), but also writes a warning for each local variable which is never read to
+/// the compiler output, as rlox does when it runs a file.
+pub fn run_program_with_warnings<C: Write, VO: Write, VE: Write>(
+    path: &str,
+    compiler_output: C,
+    vm_output: VO,
+    vm_err: VE,
+) -> (Result<(), Error>, Output<C, VO, VE>) {
+    run_program_from(path, None, true, compiler_output, vm_output, vm_err)
+}
+
+/// Like run_program(), but natives like read_line() read from the given input instead of stdin.
+pub fn run_program_with_input<I, C, VO, VE>(
+    path: &str,
+    input: I,
+    compiler_output: C,
+    vm_output: VO,
+    vm_err: VE,
+) -> (Result<(), Error>, Output<C, VO, VE>)
+where
+    I: BufRead + 'static,
+    C: Write,
+    VO: Write,
+    VE: Write,
+{
+    let input: Box<dyn BufRead> = Box::new(input);
+    run_program_from(path, Some(input), false, compiler_output, vm_output, vm_err)
+}
+
+fn run_program_from<C: Write, VO: Write, VE: Write>(
+    path: &str,
+    input: Option<Box<dyn BufRead>>,
+    report_warnings: bool,
+    compiler_output: C,
+    vm_output: VO,
+    vm_err: VE,
 ) -> (Result<(), Error>, Output<C, VO, VE>) {
     if let Ok(file) = read_file(path) {
         let chars = file.chars().collect::<Vec<char>>();
         let scanner = Scanner::new(chars.as_slice());
-        let compiler = Parser::new(scanner.parse(), compiler_output);
+        let mut compiler = Parser::new(scanner.parse(), compiler_output);
+        if report_warnings {
+            compiler = compiler.with_warnings();
+        }
         let compiler_res = compiler.compile();
         match compiler_res {
             Ok((function, symbol_table, compiler_out)) => {
-                let vm = VM::with_write(function, symbol_table, vm_output, vm_err);
+                let mut vm = VM::with_write(function, symbol_table, vm_output, vm_err);
+                if let Some(input) = input {
+                    vm.set_input(input);
+                }
                 match vm.interpret() {
                     Ok((vm_out, vm_err)) => (Ok(()), Output::new(compiler_out, vm_out, vm_err)),
-                    Err((_, vm_out, vm_err)) => {
-                        (Err(Error::Run), Output::new(compiler_out, vm_out, vm_err))
-                    }
+                    Err((error, vm_out, vm_err)) => (
+                        Err(Error::Run(error)),
+                        Output::new(compiler_out, vm_out, vm_err),
+                    ),
                 }
             }
-            Err(compiler_out) => (
-                Err(Error::Compile),
+            Err((errors, _, compiler_out)) => (
+                Err(Error::Compile(errors)),
                 Output::new(compiler_out, vm_output, vm_err),
             ),
         }
@@ -89,3 +198,195 @@ pub fn run_program<C: Write, VO: Write, VE: Write>(
         )
     }
 }
+
+/// Returns the byte range and the kind of each top-level declaration in the source, e.g. for an
+/// editor to map a declaration back to its text. The source does not have to compile, compile
+/// errors are written to the compiler output.
+pub fn declarations<C: Write>(
+    source: &str,
+    compiler_output: C,
+) -> Vec<(Range<usize>, DeclarationKind)> {
+    let chars = source.chars().collect::<Vec<char>>();
+    let scanner = Scanner::new(chars.as_slice());
+    let parser = Parser::new(scanner.parse(), compiler_output);
+    let declarations = parser.declarations();
+
+    // The parser counts characters, so the byte offset of each character is looked up.
+    let byte_offsets = source
+        .char_indices()
+        .map(|(offset, _)| offset)
+        .chain(std::iter::once(source.len()))
+        .collect::<Vec<usize>>();
+    declarations
+        .into_iter()
+        .map(|(range, kind)| (byte_offsets[range.start]..byte_offsets[range.end], kind))
+        .collect()
+}
+
+/// Returns a warning for each local variable in the source which is never read, in the order they
+/// are declared. Parameters are not reported. The source does not have to compile, compile errors
+/// are written to the compiler output.
+pub fn warnings<C: Write>(source: &str, compiler_output: C) -> Vec<CompileWarning> {
+    let chars = source.chars().collect::<Vec<char>>();
+    let scanner = Scanner::new(chars.as_slice());
+    let parser = Parser::new(scanner.parse(), compiler_output);
+    parser.warnings()
+}
+
+/// Compiles the source and writes a disassemble of the bytecode of the script and every function
+/// declared in it to the writer.
+pub fn disassemble<C: Write, W: Write>(
+    source: &str,
+    compiler_output: C,
+    writer: &mut W,
+) -> Result<(), Error> {
+    let chars = source.chars().collect::<Vec<char>>();
+    let scanner = Scanner::new(chars.as_slice());
+    let parser = Parser::new(scanner.parse(), compiler_output);
+    match parser.compile() {
+        Ok((closure, _, _)) => closure.disassemble(writer).map_err(|_| Error::IO),
+        Err((errors, _, _)) => Err(Error::Compile(errors)),
+    }
+}
+
+/// Compiles the source into the function of the script without running it, e.g. for tools to
+/// inspect its bytecode through Function::get_chunk().
+pub fn compile<C: Write>(source: &str, compiler_output: C) -> Result<Function, Error> {
+    let chars = source.chars().collect::<Vec<char>>();
+    let scanner = Scanner::new(chars.as_slice());
+    let parser = Parser::new(scanner.parse(), compiler_output);
+    match parser.compile() {
+        Ok((closure, _, _)) => Ok(closure.get_function().clone()),
+        Err((errors, _, _)) => Err(Error::Compile(errors)),
+    }
+}
+
+/// Compiles the source into bytes, which rlox::run_bytes() runs later without compiling the source
+/// again. The bytes can only be run by the same version of rlox.
+pub fn compile_to_bytes<C: Write>(source: &str, compiler_output: C) -> Result<Vec<u8>, Error> {
+    compile(source, compiler_output).map(|script| script.get_chunk().serialize())
+}
+
+/// Runs a program compiled by rlox::compile_to_bytes(). Fails with Error::Load if the bytes are not
+/// such a program, in which case nothing is run.
+pub fn run_bytes<O: OutputSink, E: OutputSink>(
+    bytes: &[u8],
+    vm_output: O,
+    vm_err: E,
+) -> (Result<(), Error>, (O, E)) {
+    let mut symbol_table = SymbolTable::new();
+    let chunk = match Chunk::deserialize(bytes, &mut symbol_table) {
+        Ok(chunk) => chunk,
+        Err(error) => return (Err(Error::Load(error)), (vm_output, vm_err)),
+    };
+
+    let script = Function::new(None, 0, chunk, 0, FunctionType::Script);
+    let vm = VM::with_write(Closure::new(script), symbol_table, vm_output, vm_err);
+    match vm.interpret() {
+        Ok(output) => (Ok(()), output),
+        Err((error, vm_out, vm_err)) => (Err(Error::Run(error)), (vm_out, vm_err)),
+    }
+}
+
+/// Scans the source and writes its tokens to the writer as a JSON array, with one token per line:
+///
+/// [
+/// {"type": "Print", "lexeme": "print", "line": 1, "column": 1},
+/// {"type": "Number", "lexeme": "1", "line": 1, "column": 7},
+/// {"type": "Semicolon", "lexeme": ";", "line": 1, "column": 8},
+/// {"type": "EOF", "lexeme": "", "line": 1, "column": 9}
+/// ]
+///
+/// The type is the name of the token type and the column counts characters, starting at 1. The
+/// lexeme of a string includes its quotes. Characters the scanner does not accept become tokens of
+/// type Error, whose lexeme is the error message. The last token always has the type EOF.
+pub fn dump_tokens_json<W: Write>(source: &str, writer: &mut W) -> Result<(), Error> {
+    let chars = source.chars().collect::<Vec<char>>();
+    let scanner = Scanner::new(chars.as_slice());
+    let tokens = scanner
+        .parse()
+        .map(|token| token.to_json())
+        .collect::<Vec<String>>();
+    writeln!(writer, "[\n{}\n]", tokens.join(",\n")).map_err(|_| Error::IO)
+}
+
+/// Compiles the source and runs it in the VM. Globals defined by scripts run before in the same VM,
+/// including the native functions registered through VM::builder(), are available to it.
+pub fn run_source<C: Write, O: OutputSink, E: OutputSink>(
+    vm: &mut VM<O, E>,
+    source: &str,
+    compiler_output: C,
+) -> Result<(), Error> {
+    let chars = source.chars().collect::<Vec<char>>();
+    let scanner = Scanner::new(chars.as_slice());
+    let compiler =
+        Parser::with_symbol_table(scanner.parse(), compiler_output, vm.take_symbol_table());
+    run_compiled(vm, compiler)
+}
+
+fn run_compiled<'a, I, C, O, E>(vm: &mut VM<O, E>, compiler: Parser<'a, I, C>) -> Result<(), Error>
+where
+    I: Iterator<Item = Token<'a>>,
+    C: Write,
+    O: OutputSink,
+    E: OutputSink,
+{
+    match compiler.compile() {
+        Ok((function, symbol_table, _)) => {
+            vm.set_symbol_table(symbol_table);
+            vm.interpret_more(function).map_err(Error::Run)
+        }
+        Err((errors, symbol_table, _)) => {
+            vm.set_symbol_table(symbol_table);
+            Err(Error::Compile(errors))
+        }
+    }
+}
+
+/// Reads lines from the input and runs each of them as soon as it is read, until the input ends.
+/// Globals defined by a line stay available to the following ones. Errors are reported, but do
+/// not end the session. Locals which are never read are reported as warnings.
+pub fn run_repl<I: BufRead, C: Write, VO: Write, VE: Write>(
+    mut input: I,
+    mut compiler_output: C,
+    vm_output: VO,
+    vm_err: VE,
+) -> (Result<(), Error>, Output<C, VO, VE>) {
+    // The input holds the program, so read_line() gets an empty input instead of competing for it.
+    let mut vm = VM::builder()
+        .input(std::io::empty())
+        .build(vm_output, vm_err);
+    let mut line = String::new();
+
+    let result = loop {
+        let prompt = write!(vm.get_print_output(), "> ");
+        if prompt.and_then(|_| vm.get_print_output().flush()).is_err() {
+            break Err(Error::IO);
+        }
+
+        line.clear();
+        match input.read_line(&mut line) {
+            Ok(0) => {
+                // End of input, e.g. the user pressed Ctrl-D.
+                let _ = writeln!(vm.get_print_output());
+                break Ok(());
+            }
+            Ok(_) => {}
+            Err(_) => break Err(Error::IO),
+        }
+
+        let chars = line.chars().collect::<Vec<char>>();
+        let scanner = Scanner::new(chars.as_slice());
+        let compiler = Parser::for_repl(
+            scanner.parse(),
+            &mut compiler_output,
+            vm.take_symbol_table(),
+        )
+        .with_warnings();
+        // Errors have already been reported, so they are ignored to keep the session going.
+        let _ = run_compiled(&mut vm, compiler);
+    };
+
+    let (vm_out, vm_err) = vm.into_output();
+    (result, Output::new(compiler_output, vm_out, vm_err))
+}
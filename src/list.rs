@@ -0,0 +1,103 @@
+//! This module contains the list values of Lox, which are created by list literals like [1, 2, 3].
+
+use std::cell::RefCell;
+use std::ops::Deref;
+use std::rc::Rc;
+
+use crate::gc::{Trace, Tracer};
+use crate::value::Value;
+
+thread_local! {
+    // The lists which are being printed, so that a list which contains itself is not printed
+    // forever.
+    static PRINTING: RefCell<Vec<*const RefCell<Vec<Value>>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A list can be changed through any value referring to it, so like InstanceRef, ListRef shares
+/// the elements and checks the borrows at run time. Two lists are only equal if they are the same
+/// list.
+#[derive(Clone, Debug)]
+pub struct ListRef {
+    list: Rc<RefCell<Vec<Value>>>,
+}
+
+impl ListRef {
+    pub(crate) fn new(elements: Vec<Value>) -> Self {
+        #[cfg(feature = "profile_allocations")]
+        crate::allocations::count_list();
+
+        ListRef {
+            list: Rc::new(RefCell::new(elements)),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.list.deref().borrow().len()
+    }
+
+    pub(crate) fn get(&self, index: usize) -> Option<Value> {
+        self.list.deref().borrow().get(index).cloned()
+    }
+
+    /// Returns false if the index is out of range.
+    pub(crate) fn set(&mut self, index: usize, value: Value) -> bool {
+        match self.list.deref().borrow_mut().get_mut(index) {
+            Some(element) => {
+                *element = value;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub(crate) fn traced(&self) -> Rc<dyn Trace> {
+        self.list.clone()
+    }
+}
+
+impl Trace for RefCell<Vec<Value>> {
+    fn trace(&self, tracer: &mut Tracer) {
+        for element in self.borrow().iter() {
+            tracer.mark_value(element);
+        }
+    }
+
+    fn clear(&self) {
+        if let Ok(mut list) = self.try_borrow_mut() {
+            list.clear();
+        }
+    }
+}
+
+impl PartialEq for ListRef {
+    fn eq(&self, other: &ListRef) -> bool {
+        Rc::ptr_eq(&self.list, &other.list)
+    }
+}
+
+impl Eq for ListRef {}
+
+impl std::fmt::Display for ListRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        let pointer = Rc::as_ptr(&self.list);
+        if PRINTING.with(|printing| printing.borrow().contains(&pointer)) {
+            return f.write_str("[...]");
+        }
+
+        PRINTING.with(|printing| printing.borrow_mut().push(pointer));
+        let result = write_elements(f, &self.list.deref().borrow());
+        PRINTING.with(|printing| printing.borrow_mut().pop());
+        result
+    }
+}
+
+fn write_elements(f: &mut std::fmt::Formatter<'_>, elements: &[Value]) -> std::fmt::Result {
+    f.write_str("[")?;
+    for (i, element) in elements.iter().enumerate() {
+        if i > 0 {
+            f.write_str(", ")?;
+        }
+        write!(f, "{}", element)?;
+    }
+    f.write_str("]")
+}
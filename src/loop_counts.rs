@@ -0,0 +1,205 @@
+//! This module counts how often the back edge of each loop is taken while running a program, which
+//! is the number of times the loop ran its body again. It is only compiled if the profile_loops
+//! feature is enabled, so that counting does not cost anything otherwise. The counts are reported
+//! by the binary when it exits.
+//! Like the allocations, the counts are kept per thread.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::function::Function;
+
+/// How often the back edge of a loop, that is its Loop instruction, was taken.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LoopCount {
+    function: String,
+    line: u32,
+    count: usize,
+}
+
+impl LoopCount {
+    /// The name of the function containing the loop.
+    pub fn get_function(&self) -> &str {
+        &self.function
+    }
+
+    /// The line of the back edge, which is the end of the loop body.
+    pub fn get_line(&self) -> u32 {
+        self.line
+    }
+
+    pub fn get_count(&self) -> usize {
+        self.count
+    }
+}
+
+/// The back edges that were taken, the most frequent first.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LoopCounts {
+    loops: Vec<LoopCount>,
+}
+
+impl LoopCounts {
+    pub fn get_loops(&self) -> &[LoopCount] {
+        &self.loops
+    }
+}
+
+impl std::fmt::Display for LoopCounts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        writeln!(f, "== loops ==")?;
+        for count in self.loops.iter() {
+            writeln!(
+                f,
+                "[line {}] in {}(): {}",
+                count.line, count.function, count.count
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// A back edge is identified by the chunk and the offset of its Loop instruction. The function is
+// kept, so that its chunk is not freed and its address reused by another chunk.
+struct BackEdge {
+    function: Function,
+    offset: usize,
+    count: usize,
+}
+
+thread_local! {
+    static BACK_EDGES: RefCell<HashMap<(usize, usize), BackEdge>> = RefCell::new(HashMap::new());
+}
+
+/// Counts the back edge at the given offset of the function's chunk.
+pub fn count_back_edge(function: &Function, offset: usize) {
+    let chunk = function.get_chunk() as *const _ as usize;
+    BACK_EDGES.with(|back_edges| {
+        back_edges
+            .borrow_mut()
+            .entry((chunk, offset))
+            .or_insert_with(|| BackEdge {
+                function: function.clone(),
+                offset,
+                count: 0,
+            })
+            .count += 1;
+    });
+}
+
+/// Returns the back edges taken on this thread since the last reset.
+pub fn get() -> LoopCounts {
+    let mut loops = BACK_EDGES.with(|back_edges| {
+        back_edges
+            .borrow()
+            .values()
+            .map(|back_edge| LoopCount {
+                function: back_edge
+                    .function
+                    .get_name()
+                    .map_or(String::from("script"), |name| name.to_string()),
+                line: back_edge
+                    .function
+                    .get_chunk()
+                    .get_source_code_line(back_edge.offset),
+                count: back_edge.count,
+            })
+            .collect::<Vec<LoopCount>>()
+    });
+    loops.sort_by(|a, b| {
+        b.count
+            .cmp(&a.count)
+            .then(a.line.cmp(&b.line))
+            .then(a.function.cmp(&b.function))
+    });
+    LoopCounts { loops }
+}
+
+pub fn reset() {
+    BACK_EDGES.with(|back_edges| back_edges.borrow_mut().clear());
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compile::Parser;
+    use crate::loop_counts::{self, LoopCounts};
+    use crate::scanner::Scanner;
+    use crate::vm::VM;
+
+    fn count(source: &str) -> LoopCounts {
+        loop_counts::reset();
+        let chars = source.chars().collect::<Vec<char>>();
+        let scanner = Scanner::new(chars.as_slice());
+        let parser = Parser::new(scanner.parse(), std::io::sink());
+        let (closure, symbol_table) = match parser.compile() {
+            Ok((closure, symbol_table, _)) => (closure, symbol_table),
+            Err(_) => panic!("Expected the program to compile."),
+        };
+
+        let vm = VM::with_write(closure, symbol_table, std::io::sink(), std::io::sink());
+        assert!(vm.interpret().is_ok());
+        loop_counts::get()
+    }
+
+    #[test]
+    fn count_while_loop() {
+        let counts = count(
+            "var i = 0;
+            while (i < 25) {
+                i = i + 1;
+            }",
+        );
+
+        let loops = counts.get_loops();
+        assert_eq!(loops.len(), 1);
+        assert_eq!(loops[0].get_function(), "script");
+        assert_eq!(loops[0].get_line(), 4);
+        assert_eq!(loops[0].get_count(), 25);
+    }
+
+    #[test]
+    fn count_for_loop() {
+        // A for loop with an increment has two back edges: one from the end of the body to the
+        // increment and one from the increment to the condition.
+        let counts = count(
+            "var sum = 0;
+            for (var i = 0; i < 10; i = i + 1) {
+                sum = sum + i;
+            }",
+        );
+
+        let loops = counts.get_loops();
+        assert_eq!(loops.len(), 2);
+        assert_eq!(loops[0].get_line(), 2);
+        assert_eq!(loops[0].get_count(), 10);
+        assert_eq!(loops[1].get_line(), 4);
+        assert_eq!(loops[1].get_count(), 10);
+    }
+
+    #[test]
+    fn count_loops_in_functions() {
+        let counts = count(
+            "fun inner(n) {
+                var i = 0;
+                while (i < n) i = i + 1;
+            }
+            var j = 0;
+            while (j < 3) {
+                inner(10);
+                j = j + 1;
+            }",
+        );
+
+        let loops = counts.get_loops();
+        assert_eq!(loops.len(), 2);
+        assert_eq!(loops[0].get_function(), "inner");
+        assert_eq!(loops[0].get_line(), 3);
+        assert_eq!(loops[0].get_count(), 30);
+        assert_eq!(loops[1].get_function(), "script");
+        assert_eq!(loops[1].get_count(), 3);
+        assert_eq!(
+            counts.to_string(),
+            "== loops ==\n[line 3] in inner(): 30\n[line 9] in script(): 3\n"
+        );
+    }
+}
@@ -1,32 +1,59 @@
 use std::process::ExitCode;
 
-mod chunk;
-mod classes;
-mod compile;
-mod function;
-mod intern_string;
-mod opcodes;
-mod scanner;
-mod tokens;
-mod value;
-mod vm;
+// Exit code used if the interpreter itself fails, which is always a bug in rlox rather than in the
+// Lox program.
+const INTERNAL_ERROR: i32 = 101;
+
+// No Lox program is known to make the interpreter panic. Setting this environment variable makes
+// debug builds panic on purpose, so that the panic hook can be tested.
+const FORCE_PANIC: &str = "RLOX_FORCE_PANIC";
 
 fn main() -> ExitCode {
-    let mut args = std::env::args();
-    args.next();
-    if let Some(path) = args.next() {
-        match run(&path) {
-            Ok(_) => ExitCode::SUCCESS,
-            Err(error) => ExitCode::from(error.get_error_code()),
+    install_panic_hook();
+    if cfg!(debug_assertions) && std::env::var_os(FORCE_PANIC).is_some() {
+        panic!("Panic forced by {}.", FORCE_PANIC);
+    }
+
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    let result = match args.as_slice() {
+        [] => repl(),
+        [flag, path] if flag == "--dump-tokens-json" => dump_tokens_json(path),
+        [flag, path, output] if flag == "--compile" => compile(path, output),
+        [flag, path] if flag == "--run-compiled" => run_compiled(path),
+        [path] => run(path),
+        _ => {
+            println!("Usage: rlox [path-to-lox-file]");
+            println!("       rlox --dump-tokens-json <path-to-lox-file>");
+            println!("       rlox --compile <path-to-lox-file> <output-file>");
+            println!("       rlox --run-compiled <compiled-file>");
+            return ExitCode::from(64);
         }
-    } else {
-        println!("Usage: rlox <path-to-lox-file>");
-        ExitCode::from(64)
+    };
+
+    #[cfg(feature = "profile_allocations")]
+    eprint!("{}", rlox::get_allocations());
+
+    #[cfg(feature = "profile_loops")]
+    eprint!("{}", rlox::get_loop_counts());
+
+    match result {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(error) => ExitCode::from(error.get_error_code()),
     }
 }
 
+fn repl() -> Result<(), rlox::Error> {
+    rlox::run_repl(
+        std::io::stdin().lock(),
+        std::io::stderr(),
+        std::io::stdout(),
+        std::io::stderr(),
+    )
+    .0
+}
+
 fn run(path: &str) -> Result<(), rlox::Error> {
-    rlox::run_program(
+    rlox::run_program_with_warnings(
         path,
         std::io::stderr(),
         std::io::stdout(),
@@ -34,3 +61,39 @@ fn run(path: &str) -> Result<(), rlox::Error> {
     )
     .0
 }
+
+// Writes the tokens of the file to stdout instead of running it, for tools like syntax
+// highlighters.
+fn dump_tokens_json(path: &str) -> Result<(), rlox::Error> {
+    let source = std::fs::read_to_string(path).map_err(|_| rlox::Error::IO)?;
+    rlox::dump_tokens_json(&source, &mut std::io::stdout().lock())
+}
+
+// Writes the compiled program to the output file instead of running it, so that it can be run later
+// with --run-compiled without compiling it again.
+fn compile(path: &str, output: &str) -> Result<(), rlox::Error> {
+    let source = std::fs::read_to_string(path).map_err(|_| rlox::Error::IO)?;
+    let bytes = rlox::compile_to_bytes(&source, std::io::stderr())?;
+    std::fs::write(output, bytes).map_err(|_| rlox::Error::IO)
+}
+
+fn run_compiled(path: &str) -> Result<(), rlox::Error> {
+    let bytes = std::fs::read(path).map_err(|_| rlox::Error::IO)?;
+    let result = rlox::run_bytes(&bytes, std::io::stdout(), std::io::stderr()).0;
+    if let Err(rlox::Error::Load(error)) = &result {
+        eprintln!("{}", error);
+    }
+    result
+}
+
+// Replaces the default panic message, which is confusing for users, by a note that the interpreter
+// failed. The details are only printed in debug builds.
+fn install_panic_hook() {
+    std::panic::set_hook(Box::new(|info| {
+        eprintln!("Internal interpreter error. This is a bug in rlox, please report it.");
+        if cfg!(debug_assertions) {
+            eprintln!("{}", info);
+        }
+        std::process::exit(INTERNAL_ERROR);
+    }));
+}
@@ -0,0 +1,113 @@
+//! This module contains the map values of Lox, which are created by map literals like
+//! {"a": 1, 2: "b"}.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::rc::Rc;
+
+use crate::gc::{Trace, Tracer};
+use crate::value::Value;
+
+thread_local! {
+    // The maps which are being printed, so that a map which contains itself is not printed
+    // forever.
+    static PRINTING: RefCell<Vec<*const RefCell<Entries>>> = const { RefCell::new(Vec::new()) };
+}
+
+// The entries are kept in the order their keys were first inserted, so that a map always prints
+// the same way. The index maps each key to the position of its entry.
+#[derive(Debug, Default)]
+struct Entries {
+    entries: Vec<(Value, Value)>,
+    index: HashMap<Value, usize>,
+}
+
+/// Like ListRef, MapRef shares its entries between all values referring to the map. The keys have
+/// to be hashable, see Value::is_hashable(). Two maps are only equal if they are the same map.
+#[derive(Clone, Debug)]
+pub struct MapRef {
+    map: Rc<RefCell<Entries>>,
+}
+
+impl MapRef {
+    pub(crate) fn new() -> Self {
+        #[cfg(feature = "profile_allocations")]
+        crate::allocations::count_map();
+
+        MapRef {
+            map: Rc::new(RefCell::new(Entries::default())),
+        }
+    }
+
+    pub(crate) fn get(&self, key: &Value) -> Option<Value> {
+        let map = self.map.deref().borrow();
+        map.index.get(key).map(|&i| map.entries[i].1.clone())
+    }
+
+    /// Inserts the value under the key, replacing the value already stored under the key.
+    pub(crate) fn set(&mut self, key: Value, value: Value) {
+        let mut map = self.map.deref().borrow_mut();
+        match map.index.get(&key) {
+            Some(&i) => map.entries[i].1 = value,
+            None => {
+                let i = map.entries.len();
+                map.index.insert(key.clone(), i);
+                map.entries.push((key, value));
+            }
+        }
+    }
+
+    pub(crate) fn traced(&self) -> Rc<dyn Trace> {
+        self.map.clone()
+    }
+}
+
+impl Trace for RefCell<Entries> {
+    fn trace(&self, tracer: &mut Tracer) {
+        for (key, value) in self.borrow().entries.iter() {
+            tracer.mark_value(key);
+            tracer.mark_value(value);
+        }
+    }
+
+    fn clear(&self) {
+        if let Ok(mut map) = self.try_borrow_mut() {
+            map.entries.clear();
+            map.index.clear();
+        }
+    }
+}
+
+impl PartialEq for MapRef {
+    fn eq(&self, other: &MapRef) -> bool {
+        Rc::ptr_eq(&self.map, &other.map)
+    }
+}
+
+impl Eq for MapRef {}
+
+impl std::fmt::Display for MapRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        let pointer = Rc::as_ptr(&self.map);
+        if PRINTING.with(|printing| printing.borrow().contains(&pointer)) {
+            return f.write_str("{...}");
+        }
+
+        PRINTING.with(|printing| printing.borrow_mut().push(pointer));
+        let result = write_entries(f, &self.map.deref().borrow().entries);
+        PRINTING.with(|printing| printing.borrow_mut().pop());
+        result
+    }
+}
+
+fn write_entries(f: &mut std::fmt::Formatter<'_>, entries: &[(Value, Value)]) -> std::fmt::Result {
+    f.write_str("{")?;
+    for (i, (key, value)) in entries.iter().enumerate() {
+        if i > 0 {
+            f.write_str(", ")?;
+        }
+        write!(f, "{}: {}", key, value)?;
+    }
+    f.write_str("}")
+}
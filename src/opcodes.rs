@@ -12,10 +12,14 @@ pub enum OpCode {
     Subtract,
     Multiply,
     Divide,
+    Modulo,
     Not,
     Equal,
     Greater,
     Less,
+    NotEqual,
+    GreaterEqual,
+    LessEqual,
     Return,
     Print,
     Pop,
@@ -40,6 +44,33 @@ pub enum OpCode {
     Inherit,
     GetSuper,
     SuperInvoke,
+    JumpLong,
+    JumpIfFalseLong,
+    LoopLong,
+    ConstantLong,
+    GetLocalLong,
+    SetLocalLong,
+    PrintN,
+    StaticMethod,
+    BuildList,
+    IndexGet,
+    IndexSet,
+    BuildMap,
+    Destructure,
+    DefineGlobalLong,
+    GetGlobalLong,
+    SetGlobalLong,
+    ClassLong,
+    GetPropertyLong,
+    SetPropertyLong,
+    MethodLong,
+    StaticMethodLong,
+    GetSuperLong,
+    InvokeLong,
+    SuperInvokeLong,
+    ClosureLong,
+    DefineConstGlobal,
+    DefineConstGlobalLong,
 }
 
 pub struct IndexesPerOpCode {
@@ -62,10 +93,14 @@ impl IndexesPerOpCode {
             OpCode::Subtract => 0,
             OpCode::Multiply => 0,
             OpCode::Divide => 0,
+            OpCode::Modulo => 0,
             OpCode::Not => 0,
             OpCode::Equal => 0,
             OpCode::Greater => 0,
             OpCode::Less => 0,
+            OpCode::NotEqual => 0,
+            OpCode::GreaterEqual => 0,
+            OpCode::LessEqual => 0,
             OpCode::Return => 0,
             OpCode::Print => 0,
             OpCode::Pop => 0,
@@ -90,6 +125,33 @@ impl IndexesPerOpCode {
             OpCode::Inherit => 0,
             OpCode::GetSuper => 1,
             OpCode::SuperInvoke => 2,
+            OpCode::JumpLong => 3,
+            OpCode::JumpIfFalseLong => 3,
+            OpCode::LoopLong => 3,
+            OpCode::ConstantLong => 3,
+            OpCode::GetLocalLong => 2,
+            OpCode::SetLocalLong => 2,
+            OpCode::PrintN => 1,
+            OpCode::StaticMethod => 1,
+            OpCode::BuildList => 1,
+            OpCode::IndexGet => 0,
+            OpCode::IndexSet => 0,
+            OpCode::BuildMap => 1,
+            OpCode::Destructure => 1,
+            OpCode::DefineGlobalLong => 3,
+            OpCode::GetGlobalLong => 3,
+            OpCode::SetGlobalLong => 3,
+            OpCode::ClassLong => 3,
+            OpCode::GetPropertyLong => 3,
+            OpCode::SetPropertyLong => 3,
+            OpCode::MethodLong => 3,
+            OpCode::StaticMethodLong => 3,
+            OpCode::GetSuperLong => 3,
+            OpCode::InvokeLong => 4,
+            OpCode::SuperInvokeLong => 4,
+            OpCode::ClosureLong => u8::MAX,
+            OpCode::DefineConstGlobal => 1,
+            OpCode::DefineConstGlobalLong => 3,
         };
 
         IndexesPerOpCode { map }
@@ -100,6 +162,66 @@ impl IndexesPerOpCode {
     }
 }
 
+impl OpCode {
+    /// Returns the comparison which always yields the negated result of this comparison or None if
+    /// there is none.
+    /// The ordering comparisons follow IEEE 754, so e.g. both a < b and a >= b are false if any
+    /// operand is NaN. Only OpCode::Equal and OpCode::NotEqual are the negations of each other.
+    pub(crate) fn negated_comparison(&self) -> Option<OpCode> {
+        match self {
+            OpCode::Equal => Some(OpCode::NotEqual),
+            OpCode::NotEqual => Some(OpCode::Equal),
+            _ => None,
+        }
+    }
+
+    /// Returns the long form of an instruction whose first operand is the index of a constant.
+    /// The long form takes three indexes for it instead of one, so that it can refer to any
+    /// constant of a chunk.
+    /// Panics if the opcode does not take the index of a constant.
+    pub(crate) fn with_long_constant_index(self) -> OpCode {
+        match self {
+            OpCode::Constant => OpCode::ConstantLong,
+            OpCode::DefineGlobal => OpCode::DefineGlobalLong,
+            OpCode::GetGlobal => OpCode::GetGlobalLong,
+            OpCode::SetGlobal => OpCode::SetGlobalLong,
+            OpCode::Class => OpCode::ClassLong,
+            OpCode::GetProperty => OpCode::GetPropertyLong,
+            OpCode::SetProperty => OpCode::SetPropertyLong,
+            OpCode::Method => OpCode::MethodLong,
+            OpCode::StaticMethod => OpCode::StaticMethodLong,
+            OpCode::GetSuper => OpCode::GetSuperLong,
+            OpCode::Invoke => OpCode::InvokeLong,
+            OpCode::SuperInvoke => OpCode::SuperInvokeLong,
+            OpCode::Closure => OpCode::ClosureLong,
+            OpCode::DefineConstGlobal => OpCode::DefineConstGlobalLong,
+            _ => panic!("{} does not take the index of a constant.", self),
+        }
+    }
+
+    /// Whether this is the long form of an instruction whose first operand is the index of a
+    /// constant, which takes three indexes.
+    pub(crate) fn has_long_constant_index(self) -> bool {
+        matches!(
+            self,
+            OpCode::ConstantLong
+                | OpCode::DefineGlobalLong
+                | OpCode::GetGlobalLong
+                | OpCode::SetGlobalLong
+                | OpCode::ClassLong
+                | OpCode::GetPropertyLong
+                | OpCode::SetPropertyLong
+                | OpCode::MethodLong
+                | OpCode::StaticMethodLong
+                | OpCode::GetSuperLong
+                | OpCode::InvokeLong
+                | OpCode::SuperInvokeLong
+                | OpCode::ClosureLong
+                | OpCode::DefineConstGlobalLong
+        )
+    }
+}
+
 impl std::fmt::Display for OpCode {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
         write!(f, "{:?}", self)
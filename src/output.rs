@@ -0,0 +1,18 @@
+//! This module contains the trait the VM writes printed values and runtime errors to. It keeps the
+//! VM from depending on std::io::Write, so programs embedding rlox can collect the output without
+//! going through io.
+
+use std::fmt::Arguments;
+use std::io::Write;
+
+/// Receives the output of a VM. Every std::io::Write is a sink, the output of failed writes is
+/// lost, just like print statements do not fail when stdout is closed.
+pub trait OutputSink {
+    fn write_output(&mut self, args: Arguments);
+}
+
+impl<W: Write> OutputSink for W {
+    fn write_output(&mut self, args: Arguments) {
+        let _ = self.write_fmt(args);
+    }
+}
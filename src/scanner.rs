@@ -27,23 +27,80 @@ macro_rules! chars {
 
 // Error messages.
 chars! {UNEXPECTED_CHAR 'U' 'n' 'e' 'x' 'p' 'e' 'c' 't' 'e' 'd' ' ' 'c' 'h' 'a' 'r' 'a' 'c' 't' 'e' 'r' '.'}
+chars! {INVALID_DIGIT_SEPARATOR 'I' 'n' 'v' 'a' 'l' 'i' 'd' ' ' 'd' 'i' 'g' 'i' 't' ' ' 's' 'e' 'p' 'a' 'r' 'a' 't' 'o' 'r' '.'}
+chars! {MULTIPLE_DECIMAL_POINTS 'N' 'u' 'm' 'b' 'e' 'r' ' ' 'h' 'a' 's' ' ' 'm' 'o' 'r' 'e' ' ' 't' 'h' 'a' 'n' ' ' 'o' 'n' 'e' ' ' 'd' 'e' 'c' 'i' 'm' 'a' 'l' ' ' 'p' 'o' 'i' 'n' 't' '.'}
+chars! {UNTERMINATED_BLOCK_COMMENT 'U' 'n' 't' 'e' 'r' 'm' 'i' 'n' 'a' 't' 'e' 'd' ' ' 'b' 'l' 'o' 'c' 'k' ' ' 'c' 'o' 'm' 'm' 'e' 'n' 't' '.'}
 chars! {UNTERMINATED_STRING 'U' 'n' 't' 'e' 'r' 'm' 'i' 'n' 'a' 't' 'e' 'd' ' ' 's' 't' 'r' 'i' 'n' 'g' '.'}
 
-// Used to check for keywords.
-chars! {AR 'a' 'r'}
-chars! {ETURN 'e' 't' 'u' 'r' 'n'}
-chars! {F 'f'}
-chars! {HILE 'h' 'i' 'l' 'e'}
-chars! {IL 'i' 'l'}
-chars! {IS 'i' 's'}
-chars! {LASS 'l' 'a' 's' 's'}
-chars! {LSE 'l' 's' 'e'}
-chars! {N 'n'}
-chars! {ND 'n' 'd'}
-chars! {R 'r'}
-chars! {RINT 'r' 'i' 'n' 't'}
-chars! {UE 'u' 'e'}
-chars! {UPER 'u' 'p' 'e' 'r'}
+// Turns an ASCII string into an array of its chars at compile time.
+const fn ascii_chars<const N: usize>(text: &str) -> [char; N] {
+    let bytes = text.as_bytes();
+    assert!(bytes.len() == N, "The length has to match the text.");
+    let mut chars = ['\0'; N];
+    let mut i = 0;
+    while i < N {
+        assert!(bytes[i].is_ascii(), "The text has to be ASCII.");
+        chars[i] = bytes[i] as char;
+        i += 1;
+    }
+    chars
+}
+
+macro_rules! keyword {
+    ($text:literal, $token_type:ident) => {
+        (
+            &ascii_chars::<{ $text.len() }>($text) as &[char],
+            TokenType::$token_type,
+        )
+    };
+}
+
+// Identifiers are looked up in this table to find out whether they are keywords. It is sorted, so
+// that the keywords starting with the same letter are next to each other.
+const KEYWORDS: [(&[char], TokenType); 22] = [
+    keyword!("and", And),
+    keyword!("break", Break),
+    keyword!("case", Case),
+    keyword!("class", Class),
+    keyword!("const", Const),
+    keyword!("continue", Continue),
+    keyword!("default", Default),
+    keyword!("else", Else),
+    keyword!("false", False),
+    keyword!("for", For),
+    keyword!("fun", Fun),
+    keyword!("if", If),
+    keyword!("nil", Nil),
+    keyword!("or", Or),
+    keyword!("print", Print),
+    keyword!("return", Return),
+    keyword!("super", Super),
+    keyword!("switch", Switch),
+    keyword!("this", This),
+    keyword!("true", True),
+    keyword!("var", Var),
+    keyword!("while", While),
+];
+
+// The range of KEYWORDS starting with each lowercase letter. An identifier is only compared to the
+// keywords with its first letter.
+const KEYWORD_RANGES: [(usize, usize); 26] = keyword_ranges();
+
+const fn keyword_ranges() -> [(usize, usize); 26] {
+    let mut ranges = [(0, 0); 26];
+    let mut i = 0;
+    while i < KEYWORDS.len() {
+        let letter = KEYWORDS[i].0[0] as usize - 'a' as usize;
+        if ranges[letter].1 == 0 {
+            ranges[letter].0 = i;
+        } else {
+            assert!(ranges[letter].1 == i, "KEYWORDS has to be sorted.");
+        }
+        ranges[letter].1 = i + 1;
+        i += 1;
+    }
+    ranges
+}
 
 /// The Scanner is used to parse the input in form of a &[char] into a token stream.
 /// This is done lazily by using an iterator.
@@ -71,6 +128,9 @@ struct ScannerImpl<'a> {
     start: usize,
     current: usize,
     line: u32,
+    // The index of the first character of the current line and the column of self.start.
+    line_start: usize,
+    column: u32,
     returned_eof: bool,
 }
 
@@ -81,13 +141,17 @@ impl<'a> ScannerImpl<'a> {
             start: 0,
             current: 0,
             line: 1,
+            line_start: 0,
+            column: 1,
             returned_eof: false,
         }
     }
 
     fn scan_token(&mut self) -> Option<Token<'a>> {
-        self.skip_whitespace();
-        self.start = self.current;
+        if let Some(error) = self.skip_whitespace() {
+            return Some(error);
+        }
+        self.mark_start();
 
         if self.is_at_end() {
             return if self.returned_eof {
@@ -113,11 +177,15 @@ impl<'a> ScannerImpl<'a> {
             ')' => self.make_token(TokenType::RightParen),
             '{' => self.make_token(TokenType::LeftBrace),
             '}' => self.make_token(TokenType::RightBrace),
+            '[' => self.make_token(TokenType::LeftBracket),
+            ']' => self.make_token(TokenType::RightBracket),
             ';' => self.make_token(TokenType::Semicolon),
             ',' => self.make_token(TokenType::Comma),
+            ':' => self.make_token(TokenType::Colon),
             '.' => self.make_token(TokenType::Dot),
             '-' => self.make_token(TokenType::Minus),
             '+' => self.make_token(TokenType::Plus),
+            '%' => self.make_token(TokenType::Percent),
             '/' => self.make_token(TokenType::Slash),
             '*' => self.make_token(TokenType::Star),
             '!' => {
@@ -168,63 +236,31 @@ impl<'a> ScannerImpl<'a> {
         self.make_token(ident_type)
     }
 
-    fn identifier_type(&mut self) -> TokenType {
-        match self.source[self.start] {
-            'a' => self.check_keyword(1, ND.as_slice(), TokenType::And),
-            'c' => self.check_keyword(1, LASS.as_slice(), TokenType::Class),
-            'e' => self.check_keyword(1, LSE.as_slice(), TokenType::Else),
-            'f' => {
-                if self.current - self.start > 1 {
-                    match self.source[self.start + 1] {
-                        'a' => self.check_keyword(2, LSE.as_slice(), TokenType::False),
-                        'o' => self.check_keyword(2, R.as_slice(), TokenType::For),
-                        'u' => self.check_keyword(2, N.as_slice(), TokenType::Fun),
-                        _ => TokenType::Identifier,
-                    }
-                } else {
-                    TokenType::Identifier
-                }
-            }
-            'i' => self.check_keyword(1, F.as_slice(), TokenType::If),
-            'n' => self.check_keyword(1, IL.as_slice(), TokenType::Nil),
-            'o' => self.check_keyword(1, R.as_slice(), TokenType::Or),
-            'p' => self.check_keyword(1, RINT.as_slice(), TokenType::Print),
-            'r' => self.check_keyword(1, ETURN.as_slice(), TokenType::Return),
-            's' => self.check_keyword(1, UPER.as_slice(), TokenType::Super),
-            't' => {
-                if self.current - self.start > 1 {
-                    match self.source[self.start + 1] {
-                        'h' => self.check_keyword(2, IS.as_slice(), TokenType::This),
-                        'r' => self.check_keyword(2, UE.as_slice(), TokenType::True),
-                        _ => TokenType::Identifier,
-                    }
-                } else {
-                    TokenType::Identifier
-                }
-            }
-            'v' => self.check_keyword(1, AR.as_slice(), TokenType::Var),
-            'w' => self.check_keyword(1, HILE.as_slice(), TokenType::While),
-            _ => TokenType::Identifier,
-        }
-    }
-
-    fn check_keyword(
-        &self,
-        start: usize,
-        rest: &'static [char],
-        token_type: TokenType,
-    ) -> TokenType {
-        if &self.source[(self.start + start)..self.current] == rest {
-            token_type
-        } else {
-            TokenType::Identifier
-        }
+    fn identifier_type(&self) -> TokenType {
+        let lexeme = &self.source[self.start..self.current];
+        let (start, end) = match lexeme[0] {
+            c @ 'a'..='z' => KEYWORD_RANGES[c as usize - 'a' as usize],
+            _ => return TokenType::Identifier,
+        };
+        KEYWORDS[start..end]
+            .iter()
+            .find(|(keyword, _)| *keyword == lexeme)
+            .map_or(TokenType::Identifier, |(_, token_type)| *token_type)
     }
 
     fn string(&mut self) -> Token<'a> {
         while !self.is_at_end() && self.peek() != '"' {
+            // The character following a backslash belongs to an escape sequence, so an escaped
+            // quote does not end the string. Escape sequences are interpreted by the compiler.
+            if self.peek() == '\\' {
+                self.advance();
+                if self.is_at_end() {
+                    break;
+                }
+            }
+
             if self.peek() == '\n' {
-                self.line += 1;
+                self.new_line();
             }
 
             self.advance();
@@ -238,44 +274,133 @@ impl<'a> ScannerImpl<'a> {
         }
     }
 
+    // A number has at most one decimal point, which has to be followed by a digit. A further
+    // decimal point followed by a digit, as in '1.2.3', is reported as an error. Otherwise it would
+    // be scanned as '1.2', '.' and '3', which only fails later with a confusing message.
+    // The number can end in an exponent, which is an 'e' or 'E' followed by an optional sign and
+    // digits. Digits can be separated by underscores, e.g. '1_000.5e1_0'.
     fn number(&mut self) -> Token<'a> {
-        while !self.is_at_end() && self.peek().is_ascii_digit() {
+        let mut valid = self.digits();
+
+        if self.is_decimal_point() {
             self.advance();
+            valid &= self.digits();
+
+            if self.is_decimal_point() {
+                while self.is_decimal_point() {
+                    self.advance();
+                    self.digits();
+                }
+                return self.error_token(MULTIPLE_DECIMAL_POINTS.as_slice());
+            }
         }
 
-        if !self.is_at_end() && self.peek() == '.' && self.peek_next().is_ascii_digit() {
-            self.advance();
+        if !self.is_at_end() && (self.peek() == 'e' || self.peek() == 'E') {
+            let sign = matches!(self.peek_next(), '+' | '-');
+            let digit = if sign {
+                self.current + 2 < self.source.len()
+                    && self.source[self.current + 2].is_ascii_digit()
+            } else {
+                self.peek_next().is_ascii_digit()
+            };
+
+            if digit {
+                self.advance();
+                if sign {
+                    self.advance();
+                }
+                valid &= self.digits();
+            }
+        }
+
+        if valid {
+            self.make_token(TokenType::Number)
+        } else {
+            self.error_token(INVALID_DIGIT_SEPARATOR.as_slice())
         }
+    }
+
+    fn is_decimal_point(&self) -> bool {
+        !self.is_at_end() && self.peek() == '.' && self.peek_next().is_ascii_digit()
+    }
+
+    // Consumes digits separated by underscores. Returns false if there is an underscore which is
+    // not surrounded by digits.
+    fn digits(&mut self) -> bool {
+        let mut valid = true;
+        while !self.is_at_end() && (self.peek().is_ascii_digit() || self.peek() == '_') {
+            if self.peek() == '_'
+                && !(self.source[self.current - 1].is_ascii_digit()
+                    && self.peek_next().is_ascii_digit())
+            {
+                valid = false;
+            }
 
-        while !self.is_at_end() && self.peek().is_ascii_digit() {
             self.advance();
         }
 
-        self.make_token(TokenType::Number)
+        valid
     }
 
-    fn skip_whitespace(&mut self) {
+    // Skips whitespace and comments. Returns an error token if a block comment is not terminated.
+    fn skip_whitespace(&mut self) -> Option<Token<'a>> {
         while !self.is_at_end() {
             match self.peek() {
                 ' ' | '\r' | '\t' => {
                     self.current += 1;
                 }
                 '\n' => {
-                    self.line += 1;
+                    self.new_line();
                     self.current += 1;
                 }
-                '/' => {
-                    if self.peek_next() == '/' {
-                        while !self.is_at_end() && self.peek() != '\n' {
-                            self.advance();
-                        }
-                    } else {
-                        return;
+                '/' if self.peek_next() == '/' => {
+                    while !self.is_at_end() && self.peek() != '\n' {
+                        self.advance();
+                    }
+                }
+                '/' if self.peek_next() == '*' => {
+                    // An unterminated comment is reported at the column it starts at.
+                    self.mark_start();
+                    if !self.block_comment() {
+                        return Some(self.error_token(UNTERMINATED_BLOCK_COMMENT.as_slice()));
                     }
                 }
-                _ => return,
+                _ => return None,
+            }
+        }
+
+        None
+    }
+
+    // Skips a block comment, which may contain nested block comments, e.g. '/* a /* b */ c */'.
+    // Returns false if the source ends before the comment is closed.
+    fn block_comment(&mut self) -> bool {
+        self.current += 2;
+        let mut depth = 1;
+
+        while depth > 0 {
+            if self.is_at_end() {
+                return false;
+            }
+
+            match (self.peek(), self.peek_next()) {
+                ('/', '*') => {
+                    depth += 1;
+                    self.current += 2;
+                }
+                ('*', '/') => {
+                    depth -= 1;
+                    self.current += 2;
+                }
+                ('\n', _) => {
+                    self.new_line();
+                    self.current += 1;
+                }
+                _ => self.current += 1,
             }
         }
+
+        true
     }
 
     fn matches(&mut self, expected: char) -> bool {
@@ -309,13 +434,31 @@ impl<'a> ScannerImpl<'a> {
         self.current == self.source.len()
     }
 
+    // Begins a new token at the current position.
+    fn mark_start(&mut self) {
+        self.start = self.current;
+        self.column = (self.start - self.line_start + 1) as u32;
+    }
+
+    // Has to be called while self.current points to the newline character.
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.line_start = self.current + 1;
+    }
+
     fn make_token(&self, token_type: TokenType) -> Token<'a> {
         let lexeme = &self.source[self.start..self.current];
-        Token::new(token_type, lexeme, self.line)
+        Token::new(token_type, lexeme, self.line, self.column, self.start)
     }
 
     fn error_token(&self, message: &'static [char]) -> Token<'a> {
-        Token::new(TokenType::Error, message, self.line)
+        Token::new(
+            TokenType::Error,
+            message,
+            self.line,
+            self.column,
+            self.start,
+        )
     }
 }
 
@@ -368,7 +511,7 @@ mod tests {
 
     #[test]
     fn punctuation() {
-        let input = chars!("(){};,.-+/*!!====<<=>>=");
+        let input = chars!("(){};,.-+/ *!!====<<=>>=");
         let result = scan!(input);
 
         let expected_types = vec![
@@ -409,16 +552,88 @@ mod tests {
         assert_eq!(lexemes!(result), expected);
     }
 
+    #[test]
+    fn digit_separators_and_exponents() {
+        let expected = vec!["1_000", "1_0.2_5", "1.5e3", "2E-4", "3e+1_0", "4_2e1"];
+        let input = chars!(expected.join(" "));
+        let result = scan!(input);
+        assert_eq!(result.len(), expected.len() + 1);
+        assert!(tt!(result)
+            .iter()
+            .filter(|tt| *tt != &TokenType::EOF)
+            .all(|t| t.eq(&TokenType::Number)));
+        assert_eq!(lexemes!(result), expected);
+    }
+
+    #[test]
+    fn invalid_digit_separators() {
+        for input in ["1_", "1__0", "1_.5", "1.5_", "1.5__5", "2e5_", "2e-1__0"] {
+            let input = chars!(input);
+            let result = scan!(input);
+            assert!(
+                tt!(result).contains(&TokenType::Error),
+                "Expected an error for '{}'.",
+                input.iter().collect::<String>()
+            );
+        }
+    }
+
+    #[test]
+    fn incomplete_exponent() {
+        let input = chars!("1e 2e+");
+        let result = scan!(input);
+
+        let expected = vec![
+            TokenType::Number,
+            TokenType::Identifier,
+            TokenType::Number,
+            TokenType::Identifier,
+            TokenType::Plus,
+            TokenType::EOF,
+        ];
+        assert_eq!(tt!(result), expected);
+    }
+
+    #[test]
+    fn multiple_decimal_points() {
+        let input = chars!("1.2.3");
+        let result = scan!(input);
+
+        assert_eq!(tt!(result), vec![TokenType::Error, TokenType::EOF]);
+        assert_eq!(
+            lexemes!(result),
+            vec!["Number has more than one decimal point."]
+        );
+
+        // A decimal point which is not followed by a digit is still a property access.
+        let input = chars!("1.2.foo 1.2.3.4");
+        let result = scan!(input);
+        let expected = vec![
+            TokenType::Number,
+            TokenType::Dot,
+            TokenType::Identifier,
+            TokenType::Error,
+            TokenType::EOF,
+        ];
+        assert_eq!(tt!(result), expected);
+    }
+
     #[test]
     fn keywords() {
         let keyword = vec![
-            "and", "class", "else", "false", "for", "fun", "if", "nil", "or", "print", "return",
-            "super", "this", "true", "var", "while",
+            "and", "break", "case", "class", "const", "continue", "default", "else", "false",
+            "for", "fun", "if", "nil", "or", "print", "return", "super", "switch", "this", "true",
+            "var", "while",
         ];
 
         let tokens = vec![
             TokenType::And,
+            TokenType::Break,
+            TokenType::Case,
             TokenType::Class,
+            TokenType::Const,
+            TokenType::Continue,
+            TokenType::Default,
             TokenType::Else,
             TokenType::False,
             TokenType::For,
@@ -429,6 +644,7 @@ mod tests {
             TokenType::Print,
             TokenType::Return,
             TokenType::Super,
+            TokenType::Switch,
             TokenType::This,
             TokenType::True,
             TokenType::Var,
@@ -457,6 +673,19 @@ mod tests {
         assert_eq!(lexemes!(result), expected);
     }
 
+    #[test]
+    fn keyword_near_misses() {
+        // Prefixes, extensions and other spellings of keywords are identifiers.
+        let input = chars!("an andd c clas classes f fo thi While _if s sw");
+        let result = scan!(input);
+
+        assert_eq!(result.len(), 13);
+        assert!(tt!(result)
+            .iter()
+            .filter(|tt| *tt != &TokenType::EOF)
+            .all(|t| t.eq(&TokenType::Identifier)));
+    }
+
     #[test]
     fn strings() {
         let input = chars!("\"if\" \"super\" \"h3110\"");
@@ -482,6 +711,69 @@ mod tests {
         assert_eq![result[1].get_token_type(), TokenType::EOF];
     }
 
+    #[test]
+    fn nested_block_comments() {
+        let input = chars!("/* a /* b */ c */ if /**/ else");
+        let result = scan!(input);
+
+        let expected = vec![TokenType::If, TokenType::Else, TokenType::EOF];
+        assert_eq!(tt!(result), expected);
+    }
+
+    #[test]
+    fn block_comment_counts_lines() {
+        let input = chars!("/* a\n/* b\n*/\n*/ if");
+        let result = scan!(input);
+
+        assert_eq!(result.len(), 2);
+        assert_eq![result[0].get_token_type(), TokenType::If];
+        assert_eq![result[0].get_line(), 4];
+    }
+
+    #[test]
+    fn columns() {
+        let input = chars!("var ab = \"c\nd\";\n\tprint ab;");
+        let result = scan!(input);
+
+        let columns = result
+            .iter()
+            .map(|t| (t.get_line(), t.get_column()))
+            .collect::<Vec<(u32, u32)>>();
+        let expected = vec![
+            (1, 1),
+            (1, 5),
+            (1, 8),
+            (2, 10),
+            (2, 3),
+            (3, 2),
+            (3, 8),
+            (3, 10),
+            (3, 11),
+        ];
+        assert_eq!(columns, expected);
+    }
+
+    #[test]
+    fn not_terminated_block_comment_column() {
+        let input = chars!("if\n  /* a");
+        let result = scan!(input);
+
+        assert_eq![result[1].get_token_type(), TokenType::Error];
+        assert_eq![result[1].get_column(), 3];
+    }
+
+    #[test]
+    fn not_terminated_block_comment() {
+        let input = chars!("if /* a /* b */ c");
+        let result = scan!(input);
+
+        assert_eq!(result.len(), 3);
+        assert_eq![result[0].get_token_type(), TokenType::If];
+        assert_eq![result[1].get_token_type(), TokenType::Error];
+        assert_eq![result[2].get_token_type(), TokenType::EOF];
+        assert_eq!(lexemes!(result), vec!["if", "Unterminated block comment."]);
+    }
+
     #[test]
     fn unexpected_character() {
         let input = chars!("if$");
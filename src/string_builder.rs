@@ -0,0 +1,44 @@
+//! This module contains string builders, which build a long string piece by piece. Appending to a
+//! builder only copies the new piece, while building a string with + copies everything built so
+//! far for every piece.
+
+use std::cell::RefCell;
+use std::ops::Deref;
+use std::rc::Rc;
+
+/// Like ListRef, StringBuilderRef shares its text between all values referring to the builder.
+/// The text can't refer to other objects, so a builder is never part of a reference cycle. Two
+/// builders are only equal if they are the same builder.
+#[derive(Clone, Debug, Default)]
+pub struct StringBuilderRef {
+    text: Rc<RefCell<String>>,
+}
+
+impl StringBuilderRef {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn append(&self, text: &str) {
+        self.text.deref().borrow_mut().push_str(text);
+    }
+
+    /// Returns a copy of the text built so far. The builder can still be appended to afterwards.
+    pub(crate) fn build(&self) -> String {
+        self.text.deref().borrow().clone()
+    }
+}
+
+impl PartialEq for StringBuilderRef {
+    fn eq(&self, other: &StringBuilderRef) -> bool {
+        Rc::ptr_eq(&self.text, &other.text)
+    }
+}
+
+impl Eq for StringBuilderRef {}
+
+impl std::fmt::Display for StringBuilderRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        f.write_str("<string builder>")
+    }
+}
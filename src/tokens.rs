@@ -1,5 +1,6 @@
 use std::fmt::Write;
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq, ::enum_map::Enum)]
 pub enum TokenType {
     // Single-character tokens.
@@ -7,9 +8,13 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
+    Colon,
     Comma,
     Dot,
     Minus,
+    Percent,
     Plus,
     Semicolon,
     Slash,
@@ -32,7 +37,12 @@ pub enum TokenType {
 
     // KEYWORDS.
     And,
+    Break,
+    Case,
     Class,
+    Const,
+    Continue,
+    Default,
     Else,
     False,
     Fun,
@@ -43,6 +53,7 @@ pub enum TokenType {
     Print,
     Return,
     Super,
+    Switch,
     This,
     True,
     Var,
@@ -64,14 +75,24 @@ pub struct Token<'a> {
     token_type: TokenType,
     lexeme: &'a [char],
     line: u32,
+    column: u32,
+    offset: usize,
 }
 
 impl<'a> Token<'a> {
-    pub fn new(token_type: TokenType, lexeme: &'a [char], line: u32) -> Self {
+    pub fn new(
+        token_type: TokenType,
+        lexeme: &'a [char],
+        line: u32,
+        column: u32,
+        offset: usize,
+    ) -> Self {
         Token {
             token_type,
             lexeme,
             line,
+            column,
+            offset,
         }
     }
 
@@ -87,9 +108,42 @@ impl<'a> Token<'a> {
         self.line
     }
 
+    /// The column of the first character of the lexeme, starting at 1.
+    pub fn get_column(&self) -> u32 {
+        self.column
+    }
+
+    /// The index of the first character of the lexeme in the scanned source.
+    pub fn get_offset(&self) -> usize {
+        self.offset
+    }
+
     pub fn get_lexeme_string(&self) -> String {
         self.lexeme.iter().collect::<String>()
     }
+
+    /// Returns the token as a JSON object, e.g. {"type": "Identifier", "lexeme": "a", "line": 1,
+    /// "column": 5}. The type is the name of the TokenType.
+    pub fn to_json(&self) -> String {
+        let mut lexeme = String::new();
+        for c in self.lexeme.iter() {
+            match c {
+                '"' => lexeme.push_str("\\\""),
+                '\\' => lexeme.push_str("\\\\"),
+                '\n' => lexeme.push_str("\\n"),
+                '\r' => lexeme.push_str("\\r"),
+                '\t' => lexeme.push_str("\\t"),
+                c if c.is_control() => {
+                    let _ = write!(lexeme, "\\u{:04x}", *c as u32);
+                }
+                c => lexeme.push(*c),
+            }
+        }
+        format!(
+            "{{\"type\": \"{}\", \"lexeme\": \"{}\", \"line\": {}, \"column\": {}}}",
+            self.token_type, lexeme, self.line, self.column
+        )
+    }
 }
 
 impl<'a> std::fmt::Display for Token<'a> {
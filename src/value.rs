@@ -1,8 +1,18 @@
+use std::hash::{Hash, Hasher};
+
 use crate::classes::{BoundMethod, ClazzRef, InstanceRef};
 use crate::function::{Closure, Function, NativeFunction};
 use crate::intern_string::Symbol;
+use crate::list::ListRef;
+use crate::map::MapRef;
+use crate::string_builder::StringBuilderRef;
 
 /// This enum represents all constants that can be stored in the constant pool.
+///
+/// Equality is what == means in Lox. Values of different types are never equal and objects are
+/// only equal to themselves, except for strings, which are equal if they have the same characters.
+/// Numbers follow IEEE 754, like in clox: NaN is not equal to anything, not even to itself, and
+/// 0 is equal to -0. Hash agrees with this for the values that can be map keys, see is_hashable().
 #[derive(Clone, Debug, PartialEq)]
 pub enum Value {
     Bool(bool),
@@ -14,30 +24,126 @@ pub enum Value {
     Class(ClazzRef),
     Instance(InstanceRef),
     BoundMethod(BoundMethod),
+    List(ListRef),
+    Map(MapRef),
+    StringBuilder(StringBuilderRef),
     Nil,
 }
 
 impl Value {
+    /// Creates a string value, e.g. to be returned by a native function. The VM interns the
+    /// strings returned by natives, so they are compared as fast as strings created in Lox.
+    pub fn string(s: &str) -> Self {
+        Value::String(Symbol::uninterned(s))
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s.as_str()),
+            _ => None,
+        }
+    }
+
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            Value::Double(d) => Some(*d),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
     pub fn is_falsy(&self) -> bool {
         matches!(self, Value::Nil | Value::Bool(false))
     }
+
+    /// Whether the value can be used as a map key. Only numbers, strings, booleans and nil are
+    /// hashable, except for NaN which is not equal to itself.
+    pub(crate) fn is_hashable(&self) -> bool {
+        match self {
+            Value::Double(d) => !d.is_nan(),
+            Value::Bool(_) | Value::String(_) | Value::Nil => true,
+            _ => false,
+        }
+    }
+}
+
+// Value is not reflexive for NaN, but NaN is not hashable, so it never ends up as a map key.
+impl Eq for Value {}
+
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            // 0 and -0 are equal, so they have to have the same hash.
+            Value::Double(d) if *d == 0.0 => 0f64.to_bits().hash(state),
+            Value::Double(d) => d.to_bits().hash(state),
+            Value::Bool(b) => b.hash(state),
+            Value::String(s) => s.hash(state),
+            // The other values are not hashable, so they are never used as map keys.
+            _ => {}
+        }
+    }
 }
 
 impl std::fmt::Display for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
-        let s = match &self {
-            Value::Bool(b) => b.to_string(),
-            Value::Double(f) => f.to_string(),
-            Value::String(s) => s.to_string(),
-            Value::Function(f) => f.to_string(),
-            Value::NativeFunction(_) => String::from("<native fn>"),
-            Value::Closure(c) => c.to_string(),
-            Value::Class(c) => c.to_string(),
-            Value::Instance(i) => i.to_string(),
-            Value::BoundMethod(b) => b.to_string(),
-            Value::Nil => String::from("nil"),
+        match &self {
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Double(d) => write!(f, "{}", d),
+            Value::String(s) => f.write_str(s.as_str()),
+            Value::Function(function) => write!(f, "{}", function),
+            Value::NativeFunction(_) => f.write_str("<native fn>"),
+            Value::Closure(c) => write!(f, "{}", c),
+            Value::Class(c) => write!(f, "{}", c),
+            Value::Instance(i) => write!(f, "{}", i),
+            Value::BoundMethod(b) => write!(f, "{}", b),
+            Value::List(l) => write!(f, "{}", l),
+            Value::Map(m) => write!(f, "{}", m),
+            Value::StringBuilder(b) => write!(f, "{}", b),
+            Value::Nil => f.write_str("nil"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    use crate::value::Value;
+
+    #[test]
+    fn value_size() {
+        // Every object a Value holds is behind a single pointer, so a stack slot is a pointer plus
+        // the tag. Larger variants make every stack slot larger, so they should be boxed.
+        assert_eq!(std::mem::size_of::<Value>(), 16);
+    }
+
+    #[test]
+    fn numbers_are_compared_like_ieee_754() {
+        assert_eq!(Value::Nil, Value::Nil);
+        assert_ne!(Value::Double(f64::NAN), Value::Double(f64::NAN));
+        assert_eq!(Value::Double(0.0), Value::Double(-0.0));
+        assert_ne!(Value::Double(0.0), Value::Nil);
+    }
+
+    #[test]
+    fn equal_numbers_hash_equally() {
+        let hash = |value: &Value| {
+            let mut hasher = DefaultHasher::new();
+            value.hash(&mut hasher);
+            hasher.finish()
         };
 
-        f.write_str(s.as_str())
+        assert_eq!(hash(&Value::Double(0.0)), hash(&Value::Double(-0.0)));
+        assert_eq!(hash(&Value::Double(1.0)), hash(&Value::Double(1.0)));
+        assert!(!Value::Double(f64::NAN).is_hashable());
+        assert!(Value::Nil.is_hashable());
     }
 }
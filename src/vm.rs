@@ -1,91 +1,293 @@
 use std::collections::HashMap;
-use std::io::Write;
+use std::io::BufRead;
 use std::ops::Deref;
 
+use crate::chunk::Chunk;
 use crate::classes::{BoundMethod, Clazz, ClazzRef, InstanceRef};
-use crate::function::{clock, Closure, NativeFunction, ObjUpvalue, UpvalueLocation};
+use crate::error::RuntimeError;
+use crate::function::{
+    abs, append, arity, build, ceil, char_at, classname, classof, clock, depth, floor, len, max,
+    min, num, pow, printf, read_line, sqrt, str, string_builder, write, Closure, FunctionType,
+    NativeFn, NativeFunction, ObjUpvalue, UpvalueLocation,
+};
+use crate::gc::{Heap, Tracer};
 use crate::intern_string::{Symbol, SymbolTable};
+use crate::list::ListRef;
+use crate::map::MapRef;
 use crate::opcodes::OpCode;
+use crate::output::OutputSink;
 use crate::value::Value;
 
+/// The number of values on the stack at which a call is a stack overflow. The stack grows as
+/// needed and is only checked when calling, so a frame with many locals can take it beyond this.
+const STACK_MAX: usize = 256 * 1024;
+
+/// The maximum call depth. It bounds the recursion of functions with small frames, which would not
+/// fill the stack before using up a lot of memory for their call frames.
+const FRAMES_MAX: usize = 64 * 1024;
+
+/// The natives defined in every VM, with their names and arities.
+const STANDARD_NATIVES: [(&str, usize, NativeFn); 18] = [
+    ("clock", 0, clock),
+    ("len", 1, len),
+    ("charAt", 2, char_at),
+    ("str", 1, str),
+    ("num", 1, num),
+    ("sqrt", 1, sqrt),
+    ("floor", 1, floor),
+    ("ceil", 1, ceil),
+    ("abs", 1, abs),
+    ("pow", 2, pow),
+    ("min", 2, min),
+    ("max", 2, max),
+    ("classof", 1, classof),
+    ("classname", 1, classname),
+    ("arity", 1, arity),
+    ("string_builder", 0, string_builder),
+    ("append", 2, append),
+    ("build", 1, build),
+];
+
+/// Gets the values printed by the program, see VMBuilder::on_print().
+type PrintCallback = Box<dyn FnMut(&Value)>;
+
 #[derive(PartialEq, Eq, Debug)]
 pub enum InterpretResult {
     RuntimeError,
 }
 
-pub struct VM<O: Write, E: Write> {
+pub struct VM<O: OutputSink, E: OutputSink> {
     frames: Vec<CallFrame>,
     stack: Vec<Value>,
     symbol_table: SymbolTable,
-    globals: HashMap<Symbol, Value>,
+    globals: Globals,
     open_upvalues: Vec<ObjUpvalue>,
-    init_symbol: Symbol,
+    global_cache: GlobalCache,
+    heap: Heap,
+    symbols: MethodSymbols,
+    error: Option<RuntimeError>,
+    formatter: Option<fn(&Value) -> String>,
+    on_print: Option<PrintCallback>,
+    lenient_arity: bool,
+    // Natives like read_line() read from it. None reads from stdin, which is only locked while a
+    // native reads from it.
+    input: Option<Box<dyn BufRead>>,
+    trace: bool,
     print_output: O,
     error_output: E,
 }
 
 impl VM<std::io::Stdout, std::io::Stderr> {
-    pub fn new(closure: Closure, mut symbol_table: SymbolTable) -> Self {
-        let init_symbol = symbol_table.intern(String::from("init"));
-        let mut vm = VM {
-            stack: Vec::new(),
-            symbol_table,
-            globals: HashMap::new(),
-            frames: Vec::new(),
-            open_upvalues: Vec::new(),
-            init_symbol,
-            print_output: std::io::stdout(),
-            error_output: std::io::stderr(),
-        };
+    pub fn builder() -> VMBuilder {
+        VMBuilder::new()
+    }
+}
 
-        vm.stack.push(Value::Closure(closure.clone()));
-        vm.call(closure, 0);
-        vm.define_native(String::from("clock"), NativeFunction::new(clock, 0));
+/// Creates a VM with native functions provided by the program embedding rlox, in addition to the
+/// natives every VM has: clock, len, charAt, str, num, sqrt, floor, ceil, abs, pow, min, max,
+/// classof, classname, arity, string_builder, append, build, read_line, depth, write and printf.
+#[derive(Default)]
+pub struct VMBuilder {
+    natives: Vec<(String, NativeFunction)>,
+    formatter: Option<fn(&Value) -> String>,
+    on_print: Option<PrintCallback>,
+    lenient_arity: bool,
+    input: Option<Box<dyn BufRead>>,
+    trace: bool,
+}
+
+impl VMBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes the function callable from Lox as a global with the given name. Lox checks that it
+    /// is called with arity arguments before calling it.
+    /// If the function returns an error, its message is reported as a runtime error, which ends
+    /// the Lox program.
+    pub fn native(mut self, name: &str, arity: usize, function: NativeFn) -> Self {
+        self.natives
+            .push((name.to_string(), NativeFunction::new(function, arity)));
+        self
+    }
+
+    /// Sets a function which is used to turn values into text when they are printed, instead of
+    /// their Display implementation.
+    pub fn formatter(mut self, formatter: fn(&Value) -> String) -> Self {
+        self.formatter = Some(formatter);
+        self
+    }
+
+    /// Passes the printed values to the callback instead of writing them to the print output, so
+    /// that the program embedding rlox gets them as values rather than text. A print statement
+    /// with several values calls it once for each of them, write() and printf() call it with the
+    /// value and the formatted string. The formatter is not used then.
+    /// Objects the callback keeps are not seen by VM::collect_garbage(), so an instance that is
+    /// part of a reference cycle loses its fields once the program no longer refers to it.
+    pub fn on_print(mut self, callback: impl FnMut(&Value) + 'static) -> Self {
+        self.on_print = Some(Box::new(callback));
+        self
+    }
+
+    /// Lets Lox functions be called with a different number of arguments than they have parameters.
+    /// Missing arguments are nil and extra arguments are dropped. Natives still have to be called
+    /// with their exact arity. Off by default, which makes wrong argument counts a runtime error.
+    pub fn lenient_arity(mut self, lenient: bool) -> Self {
+        self.lenient_arity = lenient;
+        self
+    }
+
+    /// Sets the input read_line() reads from. By default it reads from stdin.
+    pub fn input(mut self, input: impl BufRead + 'static) -> Self {
+        self.input = Some(Box::new(input));
+        self
+    }
+
+    /// Writes the stack and the instruction to the error output before each instruction is
+    /// executed. Off by default.
+    pub fn trace(mut self, trace: bool) -> Self {
+        self.trace = trace;
+        self
+    }
+
+    /// Creates a VM without a script, the scripts are passed to rlox::run_source() afterwards.
+    pub fn build<O: OutputSink, E: OutputSink>(self, print_output: O, error_output: E) -> VM<O, E> {
+        let mut vm = VM::without_script(print_output, error_output);
+        vm.formatter = self.formatter;
+        vm.on_print = self.on_print;
+        vm.lenient_arity = self.lenient_arity;
+        vm.input = self.input;
+        vm.trace = self.trace;
+        for (name, function) in self.natives {
+            vm.define_native(name, function);
+        }
         vm
     }
 }
 
-impl<O: Write, E: Write> VM<O, E> {
-    pub fn with_write(
+impl<O: OutputSink, E: OutputSink> VM<O, E> {
+    pub(crate) fn with_write(
         closure: Closure,
-        mut symbol_table: SymbolTable,
+        symbol_table: SymbolTable,
         print_output: O,
         error_output: E,
     ) -> Self {
-        let init_symbol = symbol_table.intern(String::from("init"));
+        let mut vm = VM::create(symbol_table, print_output, error_output);
+        vm.stack.push(Value::Closure(closure.clone()));
+        vm.call(closure, 0);
+        vm
+    }
+
+    /// Creates a VM without a script to run. Scripts are passed to it one after another using
+    /// interpret_more(), which is what the REPL does.
+    pub(crate) fn without_script(print_output: O, error_output: E) -> Self {
+        VM::create(SymbolTable::new(), print_output, error_output)
+    }
+
+    fn create(mut symbol_table: SymbolTable, print_output: O, error_output: E) -> Self {
+        let symbols = MethodSymbols::new(&mut symbol_table);
 
         let mut vm = VM {
             stack: Vec::new(),
             symbol_table,
-            globals: HashMap::new(),
+            globals: Globals::new(),
             frames: Vec::new(),
             open_upvalues: Vec::new(),
-            init_symbol,
+            global_cache: GlobalCache::new(),
+            heap: Heap::new(),
+            symbols,
+            error: None,
+            formatter: None,
+            on_print: None,
+            lenient_arity: false,
+            input: None,
+            trace: false,
             print_output,
             error_output,
         };
 
-        vm.stack.push(Value::Closure(closure.clone()));
-        vm.call(closure, 0);
-        vm.define_native(String::from("clock"), NativeFunction::new(clock, 0));
+        for (name, arity, function) in STANDARD_NATIVES {
+            vm.define_native(String::from(name), NativeFunction::new(function, arity));
+        }
+        vm.define_native(
+            String::from("read_line"),
+            NativeFunction::reading_input(read_line, 0),
+        );
+        vm.define_native(
+            String::from("depth"),
+            NativeFunction::reading_depth(depth, 0),
+        );
+        vm.define_native(
+            String::from("write"),
+            NativeFunction::writing(write, 1, false),
+        );
+        vm.define_native(
+            String::from("printf"),
+            NativeFunction::writing(printf, 1, true),
+        );
         vm
     }
 }
 
-impl<O: Write, E: Write> VM<O, E> {
-    pub fn interpret(mut self) -> Result<(O, E), (InterpretResult, O, E)> {
+impl<O: OutputSink, E: OutputSink> VM<O, E> {
+    pub fn interpret(mut self) -> Result<(O, E), (RuntimeError, O, E)> {
         match self.run() {
             Ok(_) => Ok((self.print_output, self.error_output)),
-            Err(err) => Err((err, self.print_output, self.error_output)),
+            Err(_) => {
+                let error = self
+                    .error
+                    .take()
+                    .expect("Expect a runtime error to have been reported.");
+                Err((error, self.print_output, self.error_output))
+            }
         }
     }
 
+    /// Runs another script, keeping the globals defined by the scripts run before. The script has
+    /// to be compiled using the symbol table of this VM, see take_symbol_table().
+    pub(crate) fn interpret_more(&mut self, closure: Closure) -> Result<(), RuntimeError> {
+        self.stack.push(Value::Closure(closure.clone()));
+        self.call(closure, 0);
+        self.run().map_err(|_| {
+            self.error
+                .take()
+                .expect("Expect a runtime error to have been reported.")
+        })
+    }
+
+    /// Hands out the symbol table, so that the compiler can intern the names of the next script
+    /// with it. It has to be given back using set_symbol_table() before the script is run.
+    pub(crate) fn take_symbol_table(&mut self) -> SymbolTable {
+        std::mem::take(&mut self.symbol_table)
+    }
+
+    pub(crate) fn set_symbol_table(&mut self, symbol_table: SymbolTable) {
+        self.symbol_table = symbol_table;
+    }
+
+    pub(crate) fn set_input(&mut self, input: Box<dyn BufRead>) {
+        self.input = Some(input);
+    }
+
+    pub fn get_print_output(&mut self) -> &mut O {
+        &mut self.print_output
+    }
+
+    pub fn into_output(self) -> (O, E) {
+        (self.print_output, self.error_output)
+    }
+
     fn run(&mut self) -> Result<(), InterpretResult> {
+        self.global_cache.clear();
         loop {
             // Safety: Initially, self.ip is zero, so it points to an opcode in self.chunk.
             //         Each time we execute the loop we ensure that self.ip again points to an opcode.
             let opcode = unsafe { self.read_opcode() };
 
+            if self.trace {
+                self.trace_instruction();
+            }
+
             #[cfg(feature = "debug_print_stack")]
             self.print_stack();
 
@@ -108,6 +310,13 @@ impl<O: Write, E: Write> VM<O, E> {
                     if self.frames.is_empty() {
                         // Reached end of program.
                         self.stack.pop();
+                        // Every statement leaves the stack as it found it, so a value left here
+                        // was leaked by the compiled code, e.g. a jump of and/or skipping a pop.
+                        debug_assert!(
+                            self.stack.is_empty(),
+                            "The program left {} values on the stack.",
+                            self.stack.len()
+                        );
                         return Ok(());
                     } else {
                         self.stack.truncate(frame.get_slots());
@@ -115,55 +324,150 @@ impl<O: Write, E: Write> VM<O, E> {
                     }
                 }
                 OpCode::Print => {
-                    let _ = writeln!(self.print_output, "{}", self.stack.pop().unwrap());
+                    let value = self.stack.pop().unwrap();
+                    match &mut self.on_print {
+                        Some(on_print) => on_print(&value),
+                        None => print_value(&mut self.print_output, self.formatter, &value, "\n"),
+                    }
+                }
+                OpCode::PrintN => {
+                    // Safety: PrintN requires an index. The index is written by the compiler
+                    //         into the chunk and the chunk ensures that it is written.
+                    let count = unsafe { self.read_index() } as usize;
+                    let start = self.stack.len() - count;
+                    for (i, value) in self.stack[start..].iter().enumerate() {
+                        let end = if i + 1 == count { "\n" } else { " " };
+                        match &mut self.on_print {
+                            Some(on_print) => on_print(value),
+                            None => print_value(&mut self.print_output, self.formatter, value, end),
+                        }
+                    }
+                    self.stack.truncate(start);
                 }
                 OpCode::Pop => {
                     self.stack.pop();
                 }
-                OpCode::DefineGlobal => {
-                    // Safety: DefineGlobal requires a index. The index is written by the compiler
+                OpCode::BuildList => {
+                    // Safety: BuildList requires an index. The index is written by the compiler
                     //         into the chunk and the chunk ensures that it is written.
-                    let name = unsafe { self.read_constant() }.clone();
-                    if let Value::String(n) = name {
-                        let value = self.stack.pop().unwrap().clone();
-                        self.globals.insert(n, value);
-                    } else {
-                        unreachable!("OpDefineGlobal has an index pointing to a string which is enforced int the compiler.");
+                    let count = unsafe { self.read_index() } as usize;
+                    let elements = self.stack.split_off(self.stack.len() - count);
+                    let list = ListRef::new(elements);
+                    self.heap.track(list.traced());
+                    self.stack.push(Value::List(list));
+                    self.collect_garbage_if_needed();
+                }
+                OpCode::BuildMap => {
+                    // Safety: BuildMap requires an index. The index is written by the compiler
+                    //         into the chunk and the chunk ensures that it is written.
+                    let count = unsafe { self.read_index() } as usize;
+                    let entries = self.stack.split_off(self.stack.len() - 2 * count);
+                    let mut map = MapRef::new();
+                    for entry in entries.chunks_exact(2) {
+                        if !self.check_map_key(&entry[0]) {
+                            return Err(InterpretResult::RuntimeError);
+                        }
+                        map.set(entry[0].clone(), entry[1].clone());
                     }
+                    self.heap.track(map.traced());
+                    self.stack.push(Value::Map(map));
+                    self.collect_garbage_if_needed();
                 }
-                OpCode::GetGlobal => {
-                    // Safety: GetGlobal requires a index. The index is written by the compiler
+                OpCode::Destructure => {
+                    // Safety: Destructure requires an index. The index is written by the compiler
                     //         into the chunk and the chunk ensures that it is written.
-                    let name = unsafe { self.read_constant() }.clone();
-                    if let Value::String(ref n) = name {
-                        let value = self.globals.get(n);
-                        match value {
-                            Some(v) => self.stack.push(v.clone()),
-                            None => {
-                                self.runtime_error(format!("Undefined variable '{}'.", n).as_str());
-                                return Err(InterpretResult::RuntimeError);
-                            }
+                    let count = unsafe { self.read_index() } as usize;
+                    let list = match self.stack.pop().unwrap() {
+                        Value::List(list) => list,
+                        _ => {
+                            self.runtime_error("Can only destructure lists.");
+                            return Err(InterpretResult::RuntimeError);
+                        }
+                    };
+                    if list.len() != count {
+                        let message = format!(
+                            "Expected a list of {} elements but got {}.",
+                            count,
+                            list.len()
+                        );
+                        self.runtime_error(&message);
+                        return Err(InterpretResult::RuntimeError);
+                    }
+                    for index in 0..count {
+                        self.stack.push(list.get(index).unwrap());
+                    }
+                }
+                OpCode::IndexGet => {
+                    let index = self.stack.pop().unwrap();
+                    let target = self.stack.pop().unwrap();
+                    if let Value::Map(map) = target {
+                        // A key which is not in the map reads as nil.
+                        if !self.check_map_key(&index) {
+                            return Err(InterpretResult::RuntimeError);
                         }
+                        self.stack.push(map.get(&index).unwrap_or(Value::Nil));
                     } else {
-                        unreachable!("OpGetGlobal has an index pointing to a string which is enforced int the compiler.");
+                        match self.list_index(target, &index) {
+                            Some((list, index)) => self.stack.push(list.get(index).unwrap()),
+                            None => return Err(InterpretResult::RuntimeError),
+                        }
                     }
                 }
-                OpCode::SetGlobal => {
-                    // Safety: SetGlobal requires a index. The index is written by the compiler
-                    //         into the chunk and the chunk ensures that it is written.
-                    let name = unsafe { self.read_constant() }.clone();
-                    if let Value::String(ref n) = name {
-                        let value = self.globals.get_mut(n);
-                        match value {
-                            Some(v) => *v = self.stack.last().unwrap().clone(),
-                            None => {
-                                self.runtime_error(format!("Undefined variable '{}'.", n).as_str());
-                                return Err(InterpretResult::RuntimeError);
-                            }
+                OpCode::IndexSet => {
+                    let value = self.stack.pop().unwrap();
+                    let index = self.stack.pop().unwrap();
+                    let target = self.stack.pop().unwrap();
+                    if let Value::Map(mut map) = target {
+                        if !self.check_map_key(&index) {
+                            return Err(InterpretResult::RuntimeError);
                         }
+                        map.set(index, value.clone());
                     } else {
-                        unreachable!("OpSetGlobal has an index pointing to a string which is enforced int the compiler.");
+                        match self.list_index(target, &index) {
+                            Some((mut list, index)) => {
+                                list.set(index, value.clone());
+                            }
+                            None => return Err(InterpretResult::RuntimeError),
+                        }
                     }
+                    self.stack.push(value);
+                }
+                OpCode::DefineGlobal | OpCode::DefineGlobalLong => {
+                    // Safety: DefineGlobal requires a index. The index is written by the compiler
+                    //         into the chunk and the chunk ensures that it is written.
+                    let name = unsafe { self.read_string(opcode) }.clone();
+                    let value = self.stack.pop().unwrap();
+                    self.globals.define(name, value, false);
+                }
+                OpCode::DefineConstGlobal | OpCode::DefineConstGlobalLong => {
+                    // Safety: DefineConstGlobal requires a index. The index is written by the
+                    //         compiler into the chunk and the chunk ensures that it is written.
+                    let name = unsafe { self.read_string(opcode) }.clone();
+                    let value = self.stack.pop().unwrap();
+                    self.globals.define(name, value, true);
+                }
+                OpCode::GetGlobal | OpCode::GetGlobalLong => {
+                    // Safety: GetGlobal requires a index. The index is written by the compiler
+                    //         into the chunk and the chunk ensures that it is written.
+                    let index = unsafe { self.read_constant_index(opcode) };
+                    let slot = self.global_slot(index)?;
+                    let value = self.globals.get(slot).clone();
+                    self.stack.push(value);
+                }
+                OpCode::SetGlobal | OpCode::SetGlobalLong => {
+                    // Safety: SetGlobal requires a index. The index is written by the compiler
+                    //         into the chunk and the chunk ensures that it is written.
+                    let index = unsafe { self.read_constant_index(opcode) };
+                    let slot = self.global_slot(index)?;
+                    // A function compiled before the constant got declared can still assign to it,
+                    // so this is checked when running as well.
+                    if self.globals.is_const(slot) {
+                        let name = self.global_name(index);
+                        let message = format!("Cannot assign to constant '{}'.", name);
+                        self.runtime_error(&message);
+                        return Err(InterpretResult::RuntimeError);
+                    }
+                    self.globals.set(slot, self.stack.last().unwrap().clone());
                 }
                 OpCode::GetLocal => {
                     // Safety: GetLocal requires a index. The index is written by the compiler
@@ -181,6 +485,22 @@ impl<O: Write, E: Write> VM<O, E> {
                     let value = self.stack.last().unwrap().clone();
                     self.stack[frame.get_slots() + slot as usize] = value;
                 }
+                OpCode::GetLocalLong => {
+                    // Safety: GetLocalLong requires two indexes, which are written by the compiler
+                    //         into the chunk and the chunk ensures that they are written.
+                    let slot = unsafe { self.read_short() } as usize;
+                    let frame = self.frames.last().unwrap();
+                    let value = self.stack[frame.get_slots() + slot].clone();
+                    self.stack.push(value);
+                }
+                OpCode::SetLocalLong => {
+                    // Safety: SetLocalLong requires two indexes, which are written by the compiler
+                    //         into the chunk and the chunk ensures that they are written.
+                    let slot = unsafe { self.read_short() } as usize;
+                    let frame = self.frames.last().unwrap();
+                    let value = self.stack.last().unwrap().clone();
+                    self.stack[frame.get_slots() + slot] = value;
+                }
                 OpCode::GetUpvalue => {
                     // Safety: GetUpvalue requires a index. The index is written by the compiler
                     //         into the chunk and the chunk ensures that it is written.
@@ -205,8 +525,8 @@ impl<O: Write, E: Write> VM<O, E> {
                         self.stack[offset] = value;
                     } else {
                         frame
-                            .get_closure_mut()
-                            .get_upvalue_at_mut(slot)
+                            .get_closure()
+                            .get_upvalue_at(slot)
                             .set_location_value(value);
                     }
                 }
@@ -233,12 +553,10 @@ impl<O: Write, E: Write> VM<O, E> {
                         .pop()
                         .expect("Expecting stack size at least 2 for binary op.");
 
-                    if let (Value::Double(f1), Value::Double(f2)) = (a.clone(), b.clone()) {
+                    if let (Value::Double(f1), Value::Double(f2)) = (&a, &b) {
                         self.stack.push(Value::Double(f1 + f2));
-                    } else if let (Value::String(s1), Value::String(s2)) = (a, b) {
-                        let concat = format!("{}{}", s1, s2);
-                        let intern = self.symbol_table.intern(concat);
-                        self.stack.push(Value::String(intern));
+                    } else if let (Value::String(s1), Value::String(s2)) = (&a, &b) {
+                        self.stack.push(Value::String(s1.concat(s2)));
                     } else {
                         self.runtime_error("Operands must be two numbers or two strings.");
                         return Err(InterpretResult::RuntimeError);
@@ -265,6 +583,8 @@ impl<O: Write, E: Write> VM<O, E> {
                     self.binary_double_op(function)?;
                 }
                 OpCode::Divide => {
+                    // Dividing by zero is not an error. Like in clox it follows IEEE 754, so it
+                    // results in an infinity with the sign of the quotient, or NaN for 0 / 0.
                     let function = |a, b| {
                         if let (Value::Double(f1), Value::Double(f2)) = (a, b) {
                             Ok(Value::Double(f1 / f2))
@@ -274,6 +594,16 @@ impl<O: Write, E: Write> VM<O, E> {
                     };
                     self.binary_double_op(function)?;
                 }
+                OpCode::Modulo => {
+                    let function = |a, b| {
+                        if let (Value::Double(f1), Value::Double(f2)) = (a, b) {
+                            Ok(Value::Double(f1 % f2))
+                        } else {
+                            Err(InterpretResult::RuntimeError)
+                        }
+                    };
+                    self.binary_double_op(function)?;
+                }
                 OpCode::Not => {
                     let value = Value::Bool(self.stack.pop().unwrap().is_falsy());
                     self.stack.push(value);
@@ -283,6 +613,11 @@ impl<O: Write, E: Write> VM<O, E> {
                     let a = self.stack.pop().unwrap();
                     self.stack.push(Value::Bool(a == b));
                 }
+                OpCode::NotEqual => {
+                    let b = self.stack.pop().unwrap();
+                    let a = self.stack.pop().unwrap();
+                    self.stack.push(Value::Bool(a != b));
+                }
                 OpCode::Less => {
                     let function = |a, b| {
                         if let (Value::Double(f1), Value::Double(f2)) = (a, b) {
@@ -303,6 +638,28 @@ impl<O: Write, E: Write> VM<O, E> {
                     };
                     self.binary_double_op(function)?;
                 }
+                // Like Less and Greater, these follow IEEE 754, so they yield false if either
+                // operand is NaN.
+                OpCode::GreaterEqual => {
+                    let function = |a, b| {
+                        if let (Value::Double(f1), Value::Double(f2)) = (a, b) {
+                            Ok(Value::Bool(f1 >= f2))
+                        } else {
+                            Err(InterpretResult::RuntimeError)
+                        }
+                    };
+                    self.binary_double_op(function)?;
+                }
+                OpCode::LessEqual => {
+                    let function = |a, b| {
+                        if let (Value::Double(f1), Value::Double(f2)) = (a, b) {
+                            Ok(Value::Bool(f1 <= f2))
+                        } else {
+                            Err(InterpretResult::RuntimeError)
+                        }
+                    };
+                    self.binary_double_op(function)?;
+                }
 
                 OpCode::Constant => {
                     // Safety: We know that Constant takes one arguments to which self.ip points,
@@ -313,6 +670,13 @@ impl<O: Write, E: Write> VM<O, E> {
                     self.stack.push(value);
                 }
 
+                OpCode::ConstantLong => {
+                    // Safety: ConstantLong takes three indexes, which hold the index of the
+                    //         constant. Reading them moves self.ip to the next opcode.
+                    let value = unsafe { self.read_long_constant() }.clone();
+                    self.stack.push(value);
+                }
+
                 OpCode::True => self.stack.push(Value::Bool(true)),
                 OpCode::False => self.stack.push(Value::Bool(false)),
                 OpCode::Nil => self.stack.push(Value::Nil),
@@ -337,6 +701,8 @@ impl<O: Write, E: Write> VM<O, E> {
                     }
                 }
                 OpCode::Loop => {
+                    #[cfg(feature = "profile_loops")]
+                    self.count_back_edge();
                     // Safety: We know that Loop takes two arguments to which self.ip
                     //         points, and it is incremented by two after reading this opcode.
                     //         The offset has been calculated in the compiler s.t. self.ip
@@ -344,6 +710,26 @@ impl<O: Write, E: Write> VM<O, E> {
                     let offset = unsafe { self.read_short() };
                     self.frames.last_mut().unwrap().dec_ip(offset as usize);
                 }
+                OpCode::JumpLong => {
+                    // Safety: Same as for Jump, except that JumpLong takes three arguments.
+                    let offset = unsafe { self.read_long() };
+                    self.frames.last_mut().unwrap().inc_ip(offset as usize);
+                }
+                OpCode::JumpIfFalseLong => {
+                    // Safety: Same as for JumpIfFalse, except that JumpIfFalseLong takes three
+                    //         arguments.
+                    let offset = unsafe { self.read_long() };
+                    if self.stack.last().unwrap().is_falsy() {
+                        self.frames.last_mut().unwrap().inc_ip(offset as usize);
+                    }
+                }
+                OpCode::LoopLong => {
+                    #[cfg(feature = "profile_loops")]
+                    self.count_back_edge();
+                    // Safety: Same as for Loop, except that LoopLong takes three arguments.
+                    let offset = unsafe { self.read_long() };
+                    self.frames.last_mut().unwrap().dec_ip(offset as usize);
+                }
                 OpCode::Call => {
                     let arg_count = unsafe { self.read_index() };
                     let callee = self.stack[self.stack.len() - 1 - arg_count as usize].clone();
@@ -351,16 +737,16 @@ impl<O: Write, E: Write> VM<O, E> {
                         return Err(InterpretResult::RuntimeError);
                     }
                 }
-                OpCode::Closure => {
+                OpCode::Closure | OpCode::ClosureLong => {
                     // Safety: We know that Closure takes one arguments to which self.ip points,
                     //         because it is incremented after reading this opcode.
                     //         Also self.ip gets incremented after reading the constant so it will
                     //         point to the next opcode after this.
-                    let function = unsafe { self.read_constant() };
+                    let function = unsafe { self.read_constant_of(opcode) }.clone();
 
                     if let Value::Function(function) = function {
-                        let mut closure = Closure::new(function.clone());
-                        let count = closure.upvalue_count();
+                        let count = function.get_upvalue_count();
+                        let mut upvalues = Vec::with_capacity(count);
 
                         for _ in 0..count {
                             let is_local = unsafe { self.read_index() } != 0;
@@ -374,10 +760,12 @@ impl<O: Write, E: Write> VM<O, E> {
                                 frame.get_closure().get_upvalue_at(index).clone()
                             };
 
-                            closure.push_upvalue(upvalue);
+                            upvalues.push(upvalue);
                         }
 
+                        let closure = Closure::with_upvalues(function, upvalues);
                         self.stack.push(Value::Closure(closure));
+                        self.collect_garbage_if_needed();
                     } else {
                         panic!("Expected a function value.");
                     }
@@ -386,21 +774,23 @@ impl<O: Write, E: Write> VM<O, E> {
                     self.close_upvalues(self.stack.len() - 1);
                     self.stack.pop();
                 }
-                OpCode::Class => {
+                OpCode::Class | OpCode::ClassLong => {
                     // Safety: We know that Class takes one arguments to which self.ip points,
                     //         because it is incremented after reading this opcode.
                     //         Also self.ip gets incremented after reading the constant so it will
                     //         point to the next opcode after this.
-                    let name = unsafe { self.read_string() }.clone();
+                    let name = unsafe { self.read_string(opcode) }.clone();
                     let clazz = ClazzRef::from(Clazz::new(name));
+                    self.heap.track(clazz.traced());
                     self.stack.push(Value::Class(clazz));
+                    self.collect_garbage_if_needed();
                 }
-                OpCode::GetProperty => {
+                OpCode::GetProperty | OpCode::GetPropertyLong => {
                     // Safety: We know that GetProperty takes one arguments to which self.ip
                     //         points, because it is incremented after reading this opcode.
                     //         Also self.ip gets incremented after reading the constant so it will
                     //         point to the next opcode after this.
-                    let name = unsafe { self.read_string() }.clone();
+                    let name = unsafe { self.read_string(opcode) }.clone();
                     let instance_ref = self.stack.last().unwrap();
                     if let Value::Instance(instance_ref) = instance_ref {
                         let value = instance_ref.get_instance().get_value(&name).cloned();
@@ -413,17 +803,37 @@ impl<O: Write, E: Write> VM<O, E> {
                                 return Err(InterpretResult::RuntimeError);
                             }
                         }
+                    } else if let Value::Class(clazz_ref) = instance_ref {
+                        // Static methods are not bound, as there is no instance to bind them to.
+                        // Any other property of a class is reported like in clox.
+                        let method = clazz_ref.get_clazz().get_static_method(&name);
+                        if let Some(method) = method {
+                            self.stack.pop();
+                            self.stack.push(Value::Closure(method));
+                        } else {
+                            self.runtime_error("Only instances have properties.");
+                            return Err(InterpretResult::RuntimeError);
+                        }
                     } else {
-                        self.runtime_error("Only instances have properties.");
+                        // Name the kind of callable, since obj.method.x looks like it accesses a
+                        // property of an instance.
+                        let message = match instance_ref {
+                            Value::BoundMethod(_) => "Bound methods have no properties.",
+                            Value::Closure(_) | Value::NativeFunction(_) => {
+                                "Functions have no properties."
+                            }
+                            _ => "Only instances have properties.",
+                        };
+                        self.runtime_error(message);
                         return Err(InterpretResult::RuntimeError);
                     }
                 }
-                OpCode::SetProperty => {
+                OpCode::SetProperty | OpCode::SetPropertyLong => {
                     // Safety: We know that GetProperty takes one arguments to which self.ip
                     //         points, because it is incremented after reading this opcode.
                     //         Also self.ip gets incremented after reading the constant so it will
                     //         point to the next opcode after this.
-                    let name = unsafe { self.read_string() }.clone();
+                    let name = unsafe { self.read_string(opcode) }.clone();
                     let value = self.stack.pop().unwrap();
                     let instance = self.stack.pop().unwrap();
 
@@ -435,21 +845,33 @@ impl<O: Write, E: Write> VM<O, E> {
                         return Err(InterpretResult::RuntimeError);
                     }
                 }
-                OpCode::Method => {
+                OpCode::Method | OpCode::MethodLong => {
                     // Safety: We know that Method takes one arguments to which self.ip
                     //         points, because it is incremented after reading this opcode.
                     //         Also self.ip gets incremented after reading the constant so it will
                     //         point to the next opcode after this.
-                    let name = unsafe { self.read_string() }.clone();
-                    self.define_method(name);
+                    let name = unsafe { self.read_string(opcode) }.clone();
+                    if !self.define_method(name, false) {
+                        return Err(InterpretResult::RuntimeError);
+                    }
+                }
+                OpCode::StaticMethod | OpCode::StaticMethodLong => {
+                    // Safety: We know that StaticMethod takes one arguments to which self.ip
+                    //         points, because it is incremented after reading this opcode.
+                    //         Also self.ip gets incremented after reading the constant so it will
+                    //         point to the next opcode after this.
+                    let name = unsafe { self.read_string(opcode) }.clone();
+                    if !self.define_method(name, true) {
+                        return Err(InterpretResult::RuntimeError);
+                    }
                 }
 
-                OpCode::Invoke => {
+                OpCode::Invoke | OpCode::InvokeLong => {
                     // Safety: We know that Invoke takes two arguments to which self.ip
                     //         points, because it is incremented after reading this opcode.
                     //         Also self.ip gets incremented after reading the constant so it will
                     //         point to the next opcode after this.
-                    let method = unsafe { self.read_string() }.clone();
+                    let method = unsafe { self.read_string(opcode) }.clone();
                     let arg_count = unsafe { self.read_index() };
                     let success = self.invoke(&method, arg_count);
                     if !success {
@@ -463,8 +885,15 @@ impl<O: Write, E: Write> VM<O, E> {
                             superclass
                                 .get_clazz()
                                 .get_methods()
-                                .map(|(s, m)| (s.clone(), std::rc::Rc::clone(m)))
-                                .for_each(|(s, m)| subclass.get_clazz_mut().set_method_ref(s, m));
+                                .map(|(s, m)| (s.clone(), m.clone()))
+                                .for_each(|(s, m)| subclass.get_clazz_mut().set_method(s, m));
+                            superclass
+                                .get_clazz()
+                                .get_static_methods()
+                                .map(|(s, m)| (s.clone(), m.clone()))
+                                .for_each(|(s, m)| {
+                                    subclass.get_clazz_mut().set_static_method(s, m)
+                                });
                             self.stack.pop();
                         } else {
                             panic!("Expected class");
@@ -474,12 +903,12 @@ impl<O: Write, E: Write> VM<O, E> {
                         return Err(InterpretResult::RuntimeError);
                     }
                 }
-                OpCode::GetSuper => {
+                OpCode::GetSuper | OpCode::GetSuperLong => {
                     // Safety: We know that GetSuper takes one arguments to which self.ip
                     //         points, because it is incremented after reading this opcode.
                     //         Also self.ip gets incremented after reading the constant so it will
                     //         point to the next opcode after this.
-                    let name = unsafe { self.read_string() }.clone();
+                    let name = unsafe { self.read_string(opcode) }.clone();
                     if let Value::Class(superclass) = self.stack.pop().unwrap().clone() {
                         if !self.bind_method(superclass, name) {
                             return Err(InterpretResult::RuntimeError);
@@ -489,12 +918,12 @@ impl<O: Write, E: Write> VM<O, E> {
                         return Err(InterpretResult::RuntimeError);
                     }
                 }
-                OpCode::SuperInvoke => {
+                OpCode::SuperInvoke | OpCode::SuperInvokeLong => {
                     // Safety: We know that SuperInvoke takes two arguments to which self.ip
                     //         points, because it is incremented after reading this opcode.
                     //         Also self.ip gets incremented after reading the constant so it will
                     //         point to the next opcode after this.
-                    let method = unsafe { self.read_string() }.clone();
+                    let method = unsafe { self.read_string(opcode) }.clone();
                     let arg_count = unsafe { self.read_index() };
                     if let Value::Class(superclass) = self.stack.pop().unwrap().clone() {
                         if !self.invoke_from_class(&superclass, &method, arg_count) {
@@ -509,6 +938,87 @@ impl<O: Write, E: Write> VM<O, E> {
         }
     }
 
+    // Returns the slot of the global whose name is the constant at the index of the current chunk,
+    // reporting a runtime error if there is no such global.
+    #[inline]
+    fn global_slot(&mut self, index: usize) -> Result<usize, InterpretResult> {
+        let chunk = self
+            .frames
+            .last()
+            .unwrap()
+            .get_closure()
+            .get_function()
+            .get_chunk() as *const Chunk;
+        match self.global_cache.get(chunk, index) {
+            Some(slot) => Ok(slot),
+            None => self.lookup_global_slot(chunk, index),
+        }
+    }
+
+    // Returns the name of the global whose name is the constant at the index of the current chunk.
+    fn global_name(&self, index: usize) -> Symbol {
+        match &self
+            .frames
+            .last()
+            .unwrap()
+            .get_closure()
+            .get_function()
+            .get_chunk()
+            .get_constants()[index]
+        {
+            Value::String(n) => n.clone(),
+            _ => unreachable!(
+                "Global instructions have an index pointing to a string which is enforced in the compiler."
+            ),
+        }
+    }
+
+    // The slow path of global_slot, which looks the global up by name and caches its slot.
+    #[cold]
+    fn lookup_global_slot(
+        &mut self,
+        chunk: *const Chunk,
+        index: usize,
+    ) -> Result<usize, InterpretResult> {
+        let name = self.global_name(index);
+        match self.globals.get_slot(&name) {
+            Some(slot) => {
+                self.global_cache.insert(chunk, index, slot);
+                Ok(slot)
+            }
+            None => {
+                self.runtime_error(format!("Undefined variable '{}'.", name).as_str());
+                Err(InterpretResult::RuntimeError)
+            }
+        }
+    }
+
+    /// Frees the objects which are only kept alive by reference cycles, like an instance holding
+    /// itself in a field, and which can't be reached from the stack or the globals any more.
+    /// Returns the number of objects freed. The VM also does this on its own whenever enough
+    /// objects have been allocated since the last time.
+    pub fn collect_garbage(&mut self) -> usize {
+        let mut tracer = Tracer::new();
+        for value in self.stack.iter().chain(self.globals.values.iter()) {
+            tracer.mark_value(value);
+        }
+        for frame in self.frames.iter() {
+            tracer.mark_closure(frame.get_closure());
+        }
+        for upvalue in self.open_upvalues.iter() {
+            tracer.mark_upvalue(upvalue);
+        }
+        self.heap.collect(tracer)
+    }
+
+    // Collects garbage if enough objects have been allocated. It is only called when every value
+    // in use is reachable from the VM, e.g. after a new object has been pushed onto the stack.
+    fn collect_garbage_if_needed(&mut self) {
+        if self.heap.needs_collection() {
+            self.collect_garbage();
+        }
+    }
+
     fn capture_upvalue(&mut self, location: UpvalueLocation) -> ObjUpvalue {
         if let Some(upvalue) = self
             .open_upvalues
@@ -520,6 +1030,7 @@ impl<O: Write, E: Write> VM<O, E> {
             upvalue
         } else {
             let upvalue = ObjUpvalue::new(location);
+            self.heap.track(upvalue.traced());
             self.open_upvalues.push(upvalue.clone());
             upvalue
         }
@@ -531,7 +1042,7 @@ impl<O: Write, E: Write> VM<O, E> {
         while i < self.open_upvalues.len() {
             if let UpvalueLocation::Stack(s) = self.open_upvalues[i].get_location() {
                 if s >= last {
-                    let mut upvalue = self.open_upvalues.remove(i);
+                    let upvalue = self.open_upvalues.remove(i);
                     if let UpvalueLocation::Stack(index) = upvalue.get_location().clone() {
                         let val = self.stack[index].clone();
                         upvalue.set_location(UpvalueLocation::Heap(std::rc::Rc::new(val)));
@@ -547,15 +1058,22 @@ impl<O: Write, E: Write> VM<O, E> {
         }
     }
 
-    fn define_method(&mut self, name: Symbol) {
-        let method = self.stack.pop().unwrap();
-        if let Value::Closure(method) = method {
-            match self.stack.last_mut().unwrap() {
-                Value::Class(ref mut clazz) => clazz.get_clazz_mut().set_method(name, method),
-                _ => panic!("Expected a class value."),
+    fn define_method(&mut self, name: Symbol, is_static: bool) -> bool {
+        if let Some(Value::Closure(method)) = self.stack.pop() {
+            if let Some(Value::Class(ref mut clazz)) = self.stack.last_mut() {
+                if is_static {
+                    clazz.get_clazz_mut().set_static_method(name, method);
+                } else {
+                    clazz.get_clazz_mut().set_method(name, method);
+                }
+                true
+            } else {
+                self.runtime_error("Can only define methods on classes.");
+                false
             }
         } else {
-            panic!("Expected a closure.");
+            self.runtime_error("Method must be a function.");
+            false
         }
     }
 
@@ -564,33 +1082,58 @@ impl<O: Write, E: Write> VM<O, E> {
             Value::Function(_) => unreachable!("Functions are always wrapped in closures."),
             Value::Closure(closure) => self.call(closure, arg_count),
             Value::NativeFunction(fun) => {
-                if arg_count as usize == fun.get_arity() {
+                if fun.accepts(arg_count as usize) {
+                    let depth = self.frames.len();
                     let args = &self.stack[self.stack.len() - arg_count as usize..];
-                    let result = fun.call(args);
+                    // Natives print like a print statement, but without a line break.
+                    let on_print = &mut self.on_print;
+                    let print_output = &mut self.print_output;
+                    let formatter = self.formatter;
+                    let mut output = |value: &Value| match on_print {
+                        Some(on_print) => on_print(value),
+                        None => print_value(print_output, formatter, value, ""),
+                    };
+                    let result = if !fun.reads_input() {
+                        fun.call(args, &mut std::io::empty(), depth, &mut output)
+                    } else if let Some(input) = &mut self.input {
+                        fun.call(args, input.as_mut(), depth, &mut output)
+                    } else {
+                        fun.call(args, &mut std::io::stdin().lock(), depth, &mut output)
+                    };
+                    let result = match result {
+                        Ok(Value::String(s)) => Value::String(self.symbol_table.intern_str(&s)),
+                        Ok(result) => result,
+                        Err(message) => {
+                            self.runtime_error(&message);
+                            return false;
+                        }
+                    };
                     self.stack
                         .truncate(self.stack.len().saturating_sub(arg_count as usize + 1));
                     self.stack.push(result);
                     true
                 } else {
+                    let expected = if fun.is_variadic() {
+                        format!("at least {}", fun.get_arity())
+                    } else {
+                        fun.get_arity().to_string()
+                    };
                     self.runtime_error(
-                        format!(
-                            "Expected {} arguments but got {}.",
-                            fun.get_arity(),
-                            arg_count
-                        )
-                        .as_str(),
+                        format!("Expected {} arguments but got {}.", expected, arg_count).as_str(),
                     );
                     false
                 }
             }
             Value::Class(clazz_ref) => {
                 let instance = InstanceRef::from(clazz_ref.clone());
+                self.heap.track(instance.traced());
                 let len = self.stack.len();
                 self.stack[len - 1 - arg_count as usize] = Value::Instance(instance);
+                self.collect_garbage_if_needed();
                 clazz_ref
                     .get_clazz()
-                    .get_method(&self.init_symbol)
-                    .map(|m| self.call(m.deref().clone(), arg_count))
+                    .get_method(self.symbols.get_init())
+                    .map(|m| self.call(m, arg_count))
                     .unwrap_or_else(|| {
                         if arg_count == 0 {
                             true
@@ -617,14 +1160,36 @@ impl<O: Write, E: Write> VM<O, E> {
     fn invoke(&mut self, name: &Symbol, arg_count: u8) -> bool {
         let len = self.stack.len();
         if let Value::Instance(instance_ref) = self.stack[len - 1 - arg_count as usize].clone() {
-            let instance = instance_ref.get_instance();
-
-            if let Some(value) = instance.get_value(name).cloned() {
+            // The instance is not borrowed during the call, because the call can collect garbage,
+            // which clears the instance if it is not reachable any more.
+            let field = instance_ref.get_instance().get_value(name).cloned();
+            if let Some(value) = field {
+                // Name the field, since the call itself does not show what was called.
+                if !matches!(
+                    value,
+                    Value::Closure(_)
+                        | Value::NativeFunction(_)
+                        | Value::Class(_)
+                        | Value::BoundMethod(_)
+                ) {
+                    self.runtime_error(format!("Property '{}' is not callable.", name).as_str());
+                    return false;
+                }
                 let len = self.stack.len();
                 self.stack[len - 1 - arg_count as usize] = value.clone();
                 self.call_value(value, arg_count)
             } else {
-                self.invoke_from_class(instance.get_clazz_ref(), name, arg_count)
+                let clazz_ref = instance_ref.get_instance().get_clazz_ref().clone();
+                self.invoke_from_class(&clazz_ref, name, arg_count)
+            }
+        } else if let Value::Class(clazz_ref) = &self.stack[len - 1 - arg_count as usize] {
+            let method = clazz_ref.get_clazz().get_static_method(name);
+            match method {
+                Some(method) => self.call(method, arg_count),
+                None => {
+                    self.runtime_error("Only instances have methods.");
+                    false
+                }
             }
         } else {
             self.runtime_error("Only instances have methods.");
@@ -633,29 +1198,51 @@ impl<O: Write, E: Write> VM<O, E> {
     }
 
     fn invoke_from_class(&mut self, class_ref: &ClazzRef, name: &Symbol, arg_count: u8) -> bool {
-        class_ref
-            .get_clazz()
-            .get_method(name)
-            .map(|m| self.call(m.deref().clone(), arg_count))
-            .unwrap_or_else(|| {
-                self.runtime_error(format!("Undefined property '{}'.\n", name).as_str());
+        let method = class_ref.get_clazz().get_method(name);
+        match method {
+            Some(method) if method.get_function().get_kind() == FunctionType::Getter => {
+                self.runtime_error(format!("Can't call getter '{}'.", name).as_str());
                 false
-            })
+            }
+            Some(method) => self.call(method, arg_count),
+            None => {
+                self.runtime_error(format!("Undefined property '{}'.", name).as_str());
+                false
+            }
+        }
     }
 
+    // Replaces the receiver on top of the stack by the method bound to it. A getter is called with
+    // the receiver instead, so that its result replaces the receiver once it returns.
     fn bind_method(&mut self, clazz_ref: ClazzRef, name: Symbol) -> bool {
         if let Some(method) = clazz_ref.get_clazz().get_method(&name) {
+            if method.get_function().get_kind() == FunctionType::Getter {
+                return self.call(method, 0);
+            }
             let bound = BoundMethod::new(self.stack.pop().unwrap(), method);
             self.stack.push(Value::BoundMethod(bound));
             true
         } else {
-            self.runtime_error(format!("Undefined property '{}'.\n", name).as_str());
+            self.runtime_error(format!("Undefined property '{}'.", name).as_str());
             false
         }
     }
 
-    fn call(&mut self, closure: Closure, arg_count: u8) -> bool {
-        if arg_count as usize == closure.get_function().get_arity() {
+    fn call(&mut self, closure: Closure, mut arg_count: u8) -> bool {
+        if self.frames.len() == FRAMES_MAX || self.stack.len() >= STACK_MAX {
+            self.runtime_error("Stack overflow.");
+            return false;
+        }
+
+        let arity = closure.get_function().get_arity();
+        if self.lenient_arity && arg_count as usize != arity {
+            // Pads missing arguments with nil or drops the extra ones.
+            let len = self.stack.len() - arg_count as usize + arity;
+            self.stack.resize(len, Value::Nil);
+            arg_count = arity as u8;
+        }
+
+        if arg_count as usize == arity {
             let frame = CallFrame::new(closure, 0, self.stack.len() - arg_count as usize - 1);
             self.frames.push(frame);
             true
@@ -672,9 +1259,53 @@ impl<O: Write, E: Write> VM<O, E> {
         }
     }
 
+    // Checks that the value is a list and that the index is in its range, reporting a runtime
+    // error otherwise.
+    fn list_index(&mut self, list: Value, index: &Value) -> Option<(ListRef, usize)> {
+        let list = match list {
+            Value::List(list) => list,
+            _ => {
+                self.runtime_error("Only lists and maps can be indexed.");
+                return None;
+            }
+        };
+        let index = match index {
+            Value::Double(index) if index.fract() == 0.0 && *index >= 0.0 => *index as usize,
+            Value::Double(_) => {
+                self.runtime_error("Index must be a non-negative integer.");
+                return None;
+            }
+            _ => {
+                self.runtime_error("Index must be a number.");
+                return None;
+            }
+        };
+
+        if index < list.len() {
+            Some((list, index))
+        } else {
+            self.runtime_error("Index out of range.");
+            None
+        }
+    }
+
+    // Checks that the value can be used as a map key, reporting a runtime error otherwise.
+    fn check_map_key(&mut self, key: &Value) -> bool {
+        if key.is_hashable() {
+            true
+        } else if let Value::Double(_) = key {
+            self.runtime_error("Map key can't be NaN.");
+            false
+        } else {
+            self.runtime_error("Map key must be a number, a string, a boolean or nil.");
+            false
+        }
+    }
+
     fn define_native(&mut self, name: String, function: NativeFunction) {
         let intern = self.symbol_table.intern(name);
-        self.globals.insert(intern, Value::NativeFunction(function));
+        self.globals
+            .define(intern, Value::NativeFunction(function), false);
     }
 
     fn binary_double_op(
@@ -704,6 +1335,7 @@ impl<O: Write, E: Write> VM<O, E> {
     fn reset_stack(&mut self) {
         self.stack.clear();
         self.frames.clear();
+        self.open_upvalues.clear();
     }
 
     /// Safety: It is only safe to call this function when self.ip is the index of an index in
@@ -732,19 +1364,66 @@ impl<O: Write, E: Write> VM<O, E> {
         ((high as u16) << 8) + (low as u16)
     }
 
+    /// Safety: It is only safe to call this function when self.ip is the index of a long value
+    /// consisting of three consecutive indexes in self.chunk.
+    unsafe fn read_long(&mut self) -> u32 {
+        let frame = self.frames.last_mut().unwrap();
+        let chunk = frame.get_closure().get_function().get_chunk();
+        let ip = frame.get_ip();
+        let high = chunk.get_code_unit(ip).get_index();
+        let middle = chunk.get_code_unit(ip + 1).get_index();
+        let low = chunk.get_code_unit(ip + 2).get_index();
+        frame.inc_ip(3);
+
+        ((high as u32) << 16) + ((middle as u32) << 8) + (low as u32)
+    }
+
     /// Safety: It is only safe to call this function when self.ip is the index of an index in
     /// self.chunk.
     unsafe fn read_constant(&mut self) -> &Value {
-        let index = self.read_index();
+        // Reads the index and the constant through the same frame, instead of looking the frame up
+        // again after read_index().
+        let frame = self.frames.last_mut().unwrap();
+        let ip = frame.get_ip();
+        frame.inc_ip(1);
+        let chunk = frame.get_closure().get_function().get_chunk();
+        chunk.get_value_at_index(chunk.get_code_unit(ip).get_index())
+    }
+
+    /// Safety: It is only safe to call this function when self.ip is the index of the first of
+    /// three consecutive indexes in self.chunk.
+    unsafe fn read_long_constant(&mut self) -> &Value {
+        let index = self.read_long();
         let frame = self.frames.last().unwrap();
         let chunk = frame.get_closure().get_function().get_chunk();
-        chunk.get_value_at_index(index)
+        chunk.get_value_at_long_index(index)
     }
 
-    /// Safety: It is only safe to call this function when self.ip is the index of an index in
-    /// self.chunk.
-    unsafe fn read_string(&mut self) -> &Symbol {
-        match self.read_constant() {
+    /// Reads the index of a constant, which takes three indexes for the long form of an opcode.
+    /// Safety: It is only safe to call this function when self.ip is the index of the first
+    /// operand of the given opcode, which has to be the index of a constant.
+    unsafe fn read_constant_index(&mut self, opcode: OpCode) -> usize {
+        if opcode.has_long_constant_index() {
+            self.read_long() as usize
+        } else {
+            self.read_index() as usize
+        }
+    }
+
+    /// Safety: It is only safe to call this function when self.ip is the index of the first
+    /// operand of the given opcode, which has to be the index of a constant.
+    unsafe fn read_constant_of(&mut self, opcode: OpCode) -> &Value {
+        if opcode.has_long_constant_index() {
+            self.read_long_constant()
+        } else {
+            self.read_constant()
+        }
+    }
+
+    /// Safety: It is only safe to call this function when self.ip is the index of the first
+    /// operand of the given opcode, which has to be the index of a string constant.
+    unsafe fn read_string(&mut self, opcode: OpCode) -> &Symbol {
+        match self.read_constant_of(opcode) {
             Value::String(s) => s,
             _ => panic!("Expected a string value"),
         }
@@ -762,25 +1441,56 @@ impl<O: Write, E: Write> VM<O, E> {
     }
 
     fn runtime_error(&mut self, message: &str) {
-        for frame in self.frames.iter().rev() {
-            let function = frame.get_closure().get_function();
-            let ip = frame.get_ip() - 1;
-            let name = match function.get_name() {
-                Some(name) => name.as_str(),
-                None => "script",
-            };
-            let _ = writeln!(
-                self.error_output,
-                "[line {}] in {}(): {}",
-                function.get_chunk().get_source_code_line(ip),
-                name,
-                message
-            );
-        }
+        let stack_trace = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                let function = frame.get_closure().get_function();
+                let ip = frame.get_ip() - 1;
+                let name = match function.get_name() {
+                    Some(name) => name.to_string(),
+                    None => String::from("script"),
+                };
+                (function.get_chunk().get_source_code_line(ip), name)
+            })
+            .collect();
 
+        let error = RuntimeError::new(String::from(message), stack_trace);
+        self.error_output.write_output(format_args!("{}", error));
+        self.error = Some(error);
         self.reset_stack();
     }
 
+    // Counts the Loop or LoopLong instruction that was just read.
+    #[cfg(feature = "profile_loops")]
+    fn count_back_edge(&self) {
+        let frame = self.frames.last().unwrap();
+        crate::loop_counts::count_back_edge(frame.get_closure().get_function(), frame.get_ip() - 1);
+    }
+
+    // Writes the stack and the instruction that was just read to the error output.
+    fn trace_instruction(&mut self) {
+        let mut stack = String::new();
+        for value in self.stack.iter() {
+            stack.push_str(&format!("[{}]", value));
+        }
+
+        let frame = self.frames.last().unwrap();
+        let chunk = frame.get_closure().get_function().get_chunk();
+        let mut instruction: Vec<u8> = Vec::new();
+        // Safety: The last instruction read is an opcode and self.ip got incremented by one
+        //         after reading it. So self.ip - 1 points to that opcode.
+        let _ =
+            unsafe { chunk.disassemble_instruction_unsafe(frame.get_ip() - 1, &mut instruction) };
+
+        self.error_output.write_output(format_args!(
+            "{}\n{}",
+            stack,
+            String::from_utf8_lossy(&instruction)
+        ));
+    }
+
     #[cfg(feature = "debug_print_stack")]
     fn print_stack(&self) {
         self.stack.iter().for_each(|value| print!("[{}]", value));
@@ -788,6 +1498,129 @@ impl<O: Write, E: Write> VM<O, E> {
     }
 }
 
+// Prints the value followed by end, using the formatter if there is one.
+fn print_value<O: OutputSink>(
+    output: &mut O,
+    formatter: Option<fn(&Value) -> String>,
+    value: &Value,
+    end: &str,
+) {
+    match formatter {
+        Some(formatter) => output.write_output(format_args!("{}{}", formatter(value), end)),
+        None => output.write_output(format_args!("{}{}", value, end)),
+    }
+}
+
+/// The names of the methods that are looked up by the VM itself, rather than by the program.
+/// These are interned once when the VM is created, so they do not have to be interned on each use.
+struct MethodSymbols {
+    init: Symbol,
+}
+
+impl MethodSymbols {
+    fn new(symbol_table: &mut SymbolTable) -> Self {
+        MethodSymbols {
+            init: symbol_table.intern(String::from("init")),
+        }
+    }
+
+    fn get_init(&self) -> &Symbol {
+        &self.init
+    }
+}
+
+/// The values of the global variables. Each global gets a slot when it is first defined, which it
+/// keeps while the VM lives, as globals can not be removed. Redefining a global reuses its slot.
+/// A global is constant if its last definition was a constant declaration. This outlives the
+/// compiler of the declaration, e.g. for the next line of the REPL.
+struct Globals {
+    slots: HashMap<Symbol, usize>,
+    values: Vec<Value>,
+    constants: Vec<bool>,
+}
+
+impl Globals {
+    fn new() -> Self {
+        Globals {
+            slots: HashMap::new(),
+            values: Vec::new(),
+            constants: Vec::new(),
+        }
+    }
+
+    fn define(&mut self, name: Symbol, value: Value, is_const: bool) {
+        match self.slots.get(&name) {
+            Some(&slot) => {
+                self.values[slot] = value;
+                self.constants[slot] = is_const;
+            }
+            None => {
+                self.slots.insert(name, self.values.len());
+                self.values.push(value);
+                self.constants.push(is_const);
+            }
+        }
+    }
+
+    fn is_const(&self, slot: usize) -> bool {
+        self.constants[slot]
+    }
+
+    fn get_slot(&self, name: &Symbol) -> Option<usize> {
+        self.slots.get(name).copied()
+    }
+
+    fn get(&self, slot: usize) -> &Value {
+        &self.values[slot]
+    }
+
+    fn set(&mut self, slot: usize, value: Value) {
+        self.values[slot] = value;
+    }
+}
+
+/// Remembers the slots of the globals GetGlobal and SetGlobal found, so that accessing a global
+/// again does not need a lookup in the globals table. Constant indexes are per chunk, so an entry
+/// is only used if it was found in the same chunk. The cache grows with the largest index used.
+/// A slot always belongs to the same global, so assignments do not invalidate entries, they only
+/// change the value in the slot.
+/// The chunks of a program stay alive while it runs, so the cache only needs to be cleared before
+/// each run, when a new chunk could have been allocated at the address of one that got freed.
+struct GlobalCache {
+    entries: Vec<Option<GlobalCacheEntry>>,
+}
+
+struct GlobalCacheEntry {
+    chunk: *const Chunk,
+    slot: usize,
+}
+
+impl GlobalCache {
+    fn new() -> Self {
+        GlobalCache {
+            entries: (0..=u8::MAX).map(|_| None).collect(),
+        }
+    }
+
+    fn get(&self, chunk: *const Chunk, index: usize) -> Option<usize> {
+        match self.entries.get(index) {
+            Some(Some(entry)) if entry.chunk == chunk => Some(entry.slot),
+            _ => None,
+        }
+    }
+
+    fn insert(&mut self, chunk: *const Chunk, index: usize, slot: usize) {
+        if index >= self.entries.len() {
+            self.entries.resize_with(index + 1, || None);
+        }
+        self.entries[index] = Some(GlobalCacheEntry { chunk, slot });
+    }
+
+    fn clear(&mut self) {
+        self.entries.iter_mut().for_each(|entry| *entry = None);
+    }
+}
+
 struct CallFrame {
     closure: Closure,
     ip: usize,
@@ -803,13 +1636,11 @@ impl CallFrame {
         &self.closure
     }
 
-    pub fn get_closure_mut(&mut self) -> &mut Closure {
-        &mut self.closure
-    }
     pub fn get_ip(&self) -> usize {
         self.ip
     }
 
+    #[allow(dead_code)]
     pub fn set_ip(&mut self, position: usize) {
         self.ip = position;
     }
@@ -826,3 +1657,320 @@ impl CallFrame {
         self.slots
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::compile::Parser;
+    use crate::function::{Closure, FunctionBuilder, FunctionType};
+    use crate::intern_string::SymbolTable;
+    use crate::opcodes::OpCode;
+    use crate::scanner::Scanner;
+    use crate::value::Value;
+    use crate::vm::VM;
+
+    // Returns the printed output if the program runs successfully and the error output otherwise.
+    fn interpret(closure: Closure, symbol_table: SymbolTable) -> Result<String, String> {
+        match VM::with_write(closure, symbol_table, Vec::new(), Vec::new()).interpret() {
+            Ok((output, _)) => Ok(String::from_utf8(output).unwrap()),
+            Err((_, _, error)) => Err(String::from_utf8(error).unwrap()),
+        }
+    }
+
+    fn run(source: &str) -> String {
+        let chars = source.chars().collect::<Vec<char>>();
+        let scanner = Scanner::new(chars.as_slice());
+        let parser = Parser::new(scanner.parse(), std::io::sink());
+        let (closure, symbol_table) = match parser.compile() {
+            Ok((closure, symbol_table, _)) => (closure, symbol_table),
+            Err(_) => panic!("Expected the program to compile."),
+        };
+
+        interpret(closure, symbol_table).expect("Expected the program to run.")
+    }
+
+    #[test]
+    fn negated_comparisons_with_nan() {
+        // Folding a negation into a comparison must not change the result for NaN operands.
+        let source = "var nan = 0 / 0;
+            print !(nan < 1);
+            print nan >= 1;
+            print !(nan >= 1);
+            print !(nan > 1);
+            print nan <= 1;
+            print !(nan <= 1);
+            print !(nan == nan);
+            print nan != nan;
+            print !(nan != nan);";
+        let expected = "true\nfalse\ntrue\ntrue\nfalse\ntrue\ntrue\ntrue\nfalse\n";
+        assert_eq!(run(source), expected);
+    }
+
+    #[test]
+    fn not_equal_matches_negated_equal() {
+        // The jump of the and lands on the negation, so !(true and a == b) compiles to Equal
+        // followed by Not, while a != b compiles to NotEqual.
+        let pairs = [
+            ("1", "2"),
+            ("1", "1"),
+            ("0 / 0", "0 / 0"),
+            ("0", "-0"),
+            ("\"a\"", "\"a\""),
+            ("\"a\"", "\"b\""),
+            ("nil", "false"),
+            ("Foo()", "Foo()"),
+            ("foo", "foo"),
+            ("[1]", "[1]"),
+        ];
+        for (a, b) in pairs {
+            let source = format!(
+                "class Foo {{}}
+                var foo = Foo();
+                print {a} != {b};
+                print !(true and {a} == {b});"
+            );
+            let output = run(&source);
+            let (direct, negated) = output.split_once('\n').unwrap();
+            assert_eq!(format!("{}\n", direct), negated, "{} != {}", a, b);
+        }
+    }
+
+    #[test]
+    fn runtime_error_stack_trace() {
+        let source = "fun inner() {\n  return -\"a\";\n}\nfun outer() {\n  inner();\n}\nouter();";
+        let chars = source.chars().collect::<Vec<char>>();
+        let scanner = Scanner::new(chars.as_slice());
+        let parser = Parser::new(scanner.parse(), std::io::sink());
+        let (closure, symbol_table) = match parser.compile() {
+            Ok((closure, symbol_table, _)) => (closure, symbol_table),
+            Err(_) => panic!("Expected the program to compile."),
+        };
+
+        let vm = VM::with_write(closure, symbol_table, Vec::new(), Vec::new());
+        let (error, _, output) = match vm.interpret() {
+            Ok(_) => panic!("Expected the program to fail."),
+            Err(result) => result,
+        };
+
+        assert_eq!(error.get_message(), "Operand must be a number.");
+        let expected = [
+            (2, String::from("inner")),
+            (5, String::from("outer")),
+            (7, String::from("script")),
+        ];
+        assert_eq!(error.get_stack_trace(), expected);
+        assert_eq!(String::from_utf8(output).unwrap(), error.to_string());
+    }
+
+    #[test]
+    fn deep_recursion_overflows_stack() {
+        let source = "fun recurse(n) {\n  return recurse(n + 1);\n}\nrecurse(0);";
+        let chars = source.chars().collect::<Vec<char>>();
+        let scanner = Scanner::new(chars.as_slice());
+        let parser = Parser::new(scanner.parse(), std::io::sink());
+        let (closure, symbol_table) = match parser.compile() {
+            Ok((closure, symbol_table, _)) => (closure, symbol_table),
+            Err(_) => panic!("Expected the program to compile."),
+        };
+
+        let vm = VM::with_write(closure, symbol_table, Vec::new(), Vec::new());
+        let (error, _, _) = match vm.interpret() {
+            Ok(_) => panic!("Expected the program to fail."),
+            Err(result) => result,
+        };
+
+        assert_eq!(error.get_message(), "Stack overflow.");
+        let stack_trace = error.get_stack_trace();
+        assert_eq!(stack_trace.first(), Some(&(2, String::from("recurse"))));
+        assert_eq!(stack_trace.last(), Some(&(4, String::from("script"))));
+    }
+
+    #[test]
+    fn formatter() {
+        fn uppercase(value: &Value) -> String {
+            match value {
+                Value::String(string) => string.to_uppercase(),
+                value => value.to_string(),
+            }
+        }
+
+        let source = "print \"hello\"; print 1.5; print nil; print \"Hello \" + \"World\";";
+        let chars = source.chars().collect::<Vec<char>>();
+        let scanner = Scanner::new(chars.as_slice());
+        let mut vm = VM::builder()
+            .formatter(uppercase)
+            .build(Vec::new(), std::io::sink());
+        let parser =
+            Parser::with_symbol_table(scanner.parse(), std::io::sink(), vm.take_symbol_table());
+        let closure = match parser.compile() {
+            Ok((closure, symbol_table, _)) => {
+                vm.set_symbol_table(symbol_table);
+                closure
+            }
+            Err(_) => panic!("Expected the program to compile."),
+        };
+
+        assert!(vm.interpret_more(closure).is_ok());
+        let output = String::from_utf8(vm.into_output().0).unwrap();
+        assert_eq!(output, "HELLO\n1.5\nnil\nHELLO WORLD\n");
+    }
+
+    #[test]
+    fn call_statement_balances_stack() {
+        // The value returned by a call in statement position is popped. Otherwise the local
+        // declared after the calls would not be found in its slot.
+        let source = "fun foo() { return 1; }
+            class C { m() { return 2; } }
+            var c = C();
+            {
+                foo();
+                c.m();
+                C();
+                var a = \"a\";
+                print a;
+            }";
+        let chars = source.chars().collect::<Vec<char>>();
+        let scanner = Scanner::new(chars.as_slice());
+        let mut vm = VM::without_script(Vec::new(), std::io::sink());
+        let parser =
+            Parser::with_symbol_table(scanner.parse(), std::io::sink(), vm.take_symbol_table());
+        let closure = match parser.compile() {
+            Ok((closure, symbol_table, _)) => {
+                vm.set_symbol_table(symbol_table);
+                closure
+            }
+            Err(_) => panic!("Expected the program to compile."),
+        };
+
+        assert!(vm.interpret_more(closure).is_ok());
+        assert!(vm.stack.is_empty());
+        let output = String::from_utf8(vm.into_output().0).unwrap();
+        assert_eq!(output, "a\n");
+    }
+
+    #[test]
+    fn initializer() {
+        let source = "class Point {
+                init(x, y) {
+                    this.x = x;
+                    this.y = y;
+                }
+            }
+
+            var point = Point(1, 2);
+            print point.x + point.y;
+            print point.init(3, 4) == point;
+            print point.x + point.y;";
+        assert_eq!(run(source), "3\ntrue\n7\n");
+    }
+
+    #[test]
+    fn method_without_class() {
+        let mut symbol_table = SymbolTable::new();
+        let name = Value::String(symbol_table.intern(String::from("method")));
+        let method = FunctionBuilder::new(None, 0, FunctionType::Method).build();
+
+        let mut script = FunctionBuilder::new(None, 0, FunctionType::Script);
+        let name = script.add_constant(name) as u8;
+        let method = script.add_constant(Value::Function(method)) as u8;
+        script.write_opcode(OpCode::Nil, 1);
+        script.write_opcode(OpCode::Closure, 1);
+        script.write_index(method);
+        script.write_opcode(OpCode::Method, 2);
+        script.write_index(name);
+        script.write_opcode(OpCode::Return, 3);
+
+        let error = interpret(Closure::new(script.build()), symbol_table).unwrap_err();
+        assert_eq!(
+            error,
+            "[line 2] in script(): Can only define methods on classes.\n"
+        );
+    }
+
+    #[test]
+    fn method_without_closure() {
+        let mut symbol_table = SymbolTable::new();
+        let name = Value::String(symbol_table.intern(String::from("method")));
+
+        let mut script = FunctionBuilder::new(None, 0, FunctionType::Script);
+        let name = script.add_constant(name) as u8;
+        script.write_opcode(OpCode::Nil, 1);
+        script.write_opcode(OpCode::Nil, 1);
+        script.write_opcode(OpCode::Method, 2);
+        script.write_index(name);
+        script.write_opcode(OpCode::Return, 3);
+
+        let error = interpret(Closure::new(script.build()), symbol_table).unwrap_err();
+        assert_eq!(error, "[line 2] in script(): Method must be a function.\n");
+    }
+
+    #[test]
+    fn long_jumps() {
+        // Each statement of the body compiles to eight code units, so both branches of the if
+        // statement as well as the loop body need offsets that do not fit into two indexes.
+        let body = "x = x + 1;\n".repeat(10_000);
+        let source = format!(
+            "var x = 0;
+            for (var i = 0; i < 2; i = i + 1) {{
+                if (i == 0) {{ {} }} else {{ {} }}
+            }}
+            print x;",
+            body, body
+        );
+        assert_eq!(run(&source), "20000\n");
+    }
+
+    #[test]
+    fn cached_globals_are_invalidated() {
+        // Reads of a global must see every assignment to it, including assignments made by a
+        // different function than the one reading it.
+        let source = "var a = 1;
+            fun set(value) { a = value; }
+            fun get() { return a; }
+            var sum = 0;
+            for (var i = 0; i < 4; i = i + 1) {
+                sum = sum + a + get();
+                if (i == 1) a = 10;
+                if (i == 2) set(100);
+            }
+            print sum;";
+        assert_eq!(run(source), "224\n");
+    }
+
+    #[test]
+    fn many_constants() {
+        // Constants beyond the first 256 are loaded with the long constant instruction, and the
+        // instructions referring to them by name use their long forms.
+        let body = (0..300)
+            .map(|i| format!("sum = sum + {}.5;", i))
+            .collect::<Vec<String>>()
+            .join("\n");
+        let source = format!(
+            "var sum = 0; {} print sum;
+             var late = 1; late = late + 1; print late;
+             fun twice(x) {{ return 2 * x; }} print twice(late);
+             class A {{ get() {{ return this.value; }} }}
+             class B < A {{ get() {{ return super.get() + 1; }} }}
+             var b = B(); b.value = 3; print b.value; print b.get();",
+            body
+        );
+        assert_eq!(run(&source), "45000\n2\n4\n3\n4\n");
+    }
+
+    #[test]
+    fn many_locals() {
+        // Locals beyond the first 256 of a function are accessed with the long local instructions.
+        let declarations = (0..300)
+            .map(|i| format!("var l{} = {};", i, i))
+            .collect::<Vec<String>>()
+            .join("\n");
+        let sum = (0..300)
+            .map(|i| format!("l{}", i))
+            .collect::<Vec<String>>()
+            .join(" + ");
+        let source = format!(
+            "{{ {} l299 = l299 + 1; print {}; print l299; }}",
+            declarations, sum
+        );
+        assert_eq!(run(&source), "44851\n300\n");
+    }
+}
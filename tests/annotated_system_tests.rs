@@ -0,0 +1,9 @@
+use test_generator::make_tests;
+
+use crate::ci_test_utilities::test_program;
+
+mod ci_test_utilities;
+
+// The system tests in sub-directories use the annotations of the Crafting Interpreters test suite,
+// so that they can check compile and runtime errors as well.
+make_tests!("tests/files/system_test_files");
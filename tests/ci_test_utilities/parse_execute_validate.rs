@@ -15,6 +15,10 @@ lazy_static! {
     static ref SYNTAX_ERROR_PATTERN: Regex =
         Regex::new(r"\[.*line (?P<line>\d+)\] (?P<error>Error.+)").unwrap();
     static ref STACK_TRACE_PATTERN: Regex = Regex::new(r"\[line (?P<line>\d+)\]").unwrap();
+    // Marks the line of the call that is on the given frame of the stack trace of a runtime
+    // error, counting the frame of the runtime error itself as zero.
+    static ref EXPECTED_FRAME_PATTERN: Regex =
+        Regex::new(r"// expect frame (?P<frame>\d+): (?P<function>\S+)").unwrap();
 }
 
 pub struct ExpectedOutput {
@@ -41,6 +45,7 @@ pub struct Test {
     expected_output: Vec<ExpectedOutput>,
     expected_errors: Vec<String>,
     expected_runtime_error: Option<ExpectedOutput>,
+    expected_frames: Vec<(usize, String)>,
     expected_exit_code: u32,
 }
 
@@ -57,6 +62,7 @@ impl Test {
             expected_output: vec![],
             expected_errors: vec![],
             expected_runtime_error: None,
+            expected_frames: vec![],
             expected_exit_code: 0,
         };
 
@@ -98,6 +104,13 @@ impl Test {
                 continue;
             }
 
+            if let Some(capture) = EXPECTED_FRAME_PATTERN.captures_iter(line).next() {
+                let frame = capture["frame"].parse::<usize>().unwrap();
+                let expected = format!("[line {}] in {}:", line_number + 1, &capture["function"]);
+                test.expected_frames.push((frame, expected));
+                continue;
+            }
+
             if !test.expected_errors.is_empty() && test.expected_runtime_error.is_some() {
                 eprintln!("Test cannot have expected compile error and expected runtime error!");
                 return Err(TestParseError::HasCompileAndRuntimeError);
@@ -118,14 +131,19 @@ impl Test {
     pub fn expected_runtime_error(&self) -> Option<&ExpectedOutput> {
         self.expected_runtime_error.as_ref()
     }
+    pub fn expected_frames(&self) -> &[(usize, String)] {
+        &self.expected_frames
+    }
     pub fn expected_exit_code(&self) -> u32 {
         self.expected_exit_code
     }
 }
 
 fn run_and_validate_test(test: &Test) {
-    let (result, output) = rlox::run_program(
+    // The tests do not read input, an empty one keeps them from waiting for stdin if they do.
+    let (result, output) = rlox::run_program_with_input(
         test.path().to_str().unwrap(),
+        std::io::empty(),
         Vec::<u8>::new(),
         Vec::<u8>::new(),
         Vec::<u8>::new(),
@@ -167,21 +185,28 @@ fn validate_runtime_errors(test: &Test, actual_runtime_error: &[String]) {
             expected_runtime_error.line()
         );
         assert_eq!(
-            actual_runtime_error[0].split(':').last().unwrap().trim(),
+            actual_runtime_error[0]
+                .split(':')
+                .next_back()
+                .unwrap()
+                .trim(),
             expected_runtime_error.line(),
             "Expected runtime error '{}' but got '{}'",
             expected_runtime_error.line(),
-            actual_runtime_error[0].split(':').last().unwrap().trim()
+            actual_runtime_error[0]
+                .split(':')
+                .next_back()
+                .unwrap()
+                .trim()
         );
 
-        // No stack trace is printed in rlox by default.
-        /*
-        match actual_runtime_error[1..].iter().find_map(|line| {
-            STACK_TRACE_PATTERN
-                .captures_iter(line)
-                .next()
-                .map(|capture| capture["line"].parse::<usize>().unwrap())
-        }) {
+        // Rlox reports the error on every line of the stack trace, so the first line holds the
+        // line of the innermost call frame.
+        match STACK_TRACE_PATTERN
+            .captures_iter(&actual_runtime_error[0])
+            .next()
+            .map(|capture| capture["line"].parse::<usize>().unwrap())
+        {
             Some(stack_trace_line) => assert_eq!(
                 stack_trace_line,
                 expected_runtime_error.line_number(),
@@ -189,12 +214,21 @@ fn validate_runtime_errors(test: &Test, actual_runtime_error: &[String]) {
                 expected_runtime_error.line_number(),
                 stack_trace_line
             ),
-            None => panic!(
-                "Expected stack trace but got '{}'",
-                actual_runtime_error[1..].concat()
-            ),
+            None => panic!("Expected stack trace but got '{}'", actual_runtime_error[0]),
         };
-         */
+
+        for (frame, expected) in test.expected_frames() {
+            match actual_runtime_error.get(*frame) {
+                Some(actual) => assert!(
+                    actual.starts_with(expected),
+                    "Expected frame {} to start with '{}' but got '{}'",
+                    frame,
+                    expected,
+                    actual
+                ),
+                None => panic!("Expected frame {} in the stack trace.", frame),
+            }
+        }
     }
 }
 
@@ -256,7 +290,7 @@ fn validate_exit_code(test: &Test, actual_exit_code: u8) {
 fn validate_output(test: &Test, actual_output_lines: &[String]) {
     let actual = match actual_output_lines
         .last()
-        .map_or(false, |line| line.is_empty())
+        .is_some_and(|line| line.is_empty())
     {
         true => &actual_output_lines[1..],
         false => actual_output_lines,
@@ -4,4 +4,25 @@ use crate::ci_test_utilities::test_program;
 
 mod ci_test_utilities;
 
-make_tests!("tests/files/crafting_interpreters_test_files");
+// These upstream tests do not hold for rlox and are replaced by tests of its own:
+// - Loops with a body this large compile to a long loop instruction, see limit/long_loop.lox in
+//   system_test_files.
+// - Each iteration of a for-loop has its own copy of the loop variable for closures to capture,
+//   see for/closure_in_body.lox in system_test_files.
+// - Constants beyond the first 256 are loaded with a long constant instruction, see the
+//   many_constants test in src/vm.rs.
+// - Locals beyond the first 256 are accessed with long local instructions, see the many_locals
+//   test in src/vm.rs.
+// - Calling a field which does not hold a function names the field in the error, see
+//   method/call_nonfunction_field.lox in system_test_files.
+// - Getting a property of a function reports that functions have no properties, see
+//   method/get_on_function.lox in system_test_files.
+make_tests!(
+    "tests/files/crafting_interpreters_test_files",
+    "limit/loop_too_large",
+    "for/closure_in_body",
+    "limit/too_many_constants",
+    "limit/too_many_locals",
+    "field/call_nonfunction_field",
+    "field/get_on_function"
+);
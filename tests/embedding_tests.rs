@@ -0,0 +1,418 @@
+use std::cell::RefCell;
+use std::fmt::Arguments;
+use std::rc::Rc;
+
+use rlox::{
+    compile, compile_to_bytes, declarations, disassemble, dump_tokens_json, run_bytes, run_source,
+    DeclarationKind, DeserializeError, Error, NativeResult, OpCode, OutputSink, Value, VM,
+};
+
+fn double(args: &[Value]) -> NativeResult {
+    match args[0] {
+        Value::Double(d) => Ok(Value::Double(2.0 * d)),
+        _ => Ok(Value::Nil),
+    }
+}
+
+fn greet(args: &[Value]) -> NativeResult {
+    match args[0].as_str() {
+        Some(name) => Ok(Value::string(&format!("Hello {}!", name))),
+        None => Ok(Value::Nil),
+    }
+}
+
+fn half(args: &[Value]) -> NativeResult {
+    match args[0].as_number() {
+        Some(d) if d % 2.0 == 0.0 => Ok(Value::Double(d / 2.0)),
+        _ => Err(String::from("Argument must be an even number.")),
+    }
+}
+
+// Collects the output in lines, without implementing std::io::Write.
+#[derive(Default)]
+struct Lines {
+    lines: Vec<String>,
+    pending: String,
+}
+
+impl OutputSink for Lines {
+    fn write_output(&mut self, args: Arguments) {
+        self.pending.push_str(&args.to_string());
+        while let Some(end) = self.pending.find('\n') {
+            self.lines.push(self.pending[..end].to_string());
+            self.pending.drain(..=end);
+        }
+    }
+}
+
+#[test]
+fn call_registered_native() {
+    let mut vm = VM::builder()
+        .native("double", 1, double)
+        .build(Vec::new(), Vec::new());
+    let source = "print double(21);\nprint double(\"lox\");";
+    assert!(run_source(&mut vm, source, std::io::sink()).is_ok());
+
+    let (output, _) = vm.into_output();
+    assert_eq!(String::from_utf8(output).unwrap(), "42\nnil\n");
+}
+
+#[test]
+fn keep_globals_between_sources() {
+    let mut vm = VM::builder()
+        .native("double", 1, double)
+        .build(Vec::new(), Vec::new());
+    assert!(run_source(&mut vm, "var a = double(2);", std::io::sink()).is_ok());
+    assert!(run_source(&mut vm, "print double(a);", std::io::sink()).is_ok());
+
+    let (output, _) = vm.into_output();
+    assert_eq!(String::from_utf8(output).unwrap(), "8\n");
+}
+
+#[test]
+fn wrong_native_arity() {
+    let mut vm = VM::builder()
+        .native("double", 1, double)
+        .build(Vec::new(), Vec::new());
+    match run_source(&mut vm, "double(1, 2);", std::io::sink()) {
+        Err(rlox::Error::Run(error)) => {
+            assert_eq!(error.get_message(), "Expected 1 arguments but got 2.")
+        }
+        _ => panic!("Expected a runtime error."),
+    }
+}
+
+#[test]
+fn native_returns_string() {
+    let mut vm = VM::builder()
+        .native("greet", 1, greet)
+        .build(Vec::new(), Vec::new());
+    let source = "var s = greet(\"lox\");\nprint s;\nprint s == \"Hello lox!\";\nprint greet(1);";
+    assert!(run_source(&mut vm, source, std::io::sink()).is_ok());
+
+    let (output, _) = vm.into_output();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "Hello lox!\ntrue\nnil\n"
+    );
+}
+
+#[test]
+fn depth_in_built_vm() {
+    let mut vm = VM::builder().build(Vec::new(), Vec::new());
+    let source = "print depth();\nfun f() { return depth(); }\nprint f();";
+    assert!(run_source(&mut vm, source, std::io::sink()).is_ok());
+    assert!(run_source(&mut vm, "print depth();", std::io::sink()).is_ok());
+
+    let (output, _) = vm.into_output();
+    assert_eq!(String::from_utf8(output).unwrap(), "1\n2\n1\n");
+}
+
+#[test]
+fn print_callback_gets_values() {
+    let printed = Rc::new(RefCell::new(Vec::new()));
+    let collected = Rc::clone(&printed);
+    let mut vm = VM::builder()
+        .on_print(move |value| collected.borrow_mut().push(value.clone()))
+        .build(Vec::new(), Vec::new());
+    let source = "print 1;\nprint \"a\" + \"b\";\nprint nil, true;\nprint [1, 2];";
+    assert!(run_source(&mut vm, source, std::io::sink()).is_ok());
+
+    let printed = printed.borrow();
+    assert_eq!(printed.len(), 5);
+    assert_eq!(printed[0].as_number(), Some(1.0));
+    assert_eq!(printed[1].as_str(), Some("ab"));
+    assert_eq!(printed[2], Value::Nil);
+    assert_eq!(printed[3].as_bool(), Some(true));
+    assert_eq!(printed[4].to_string(), "[1, 2]");
+
+    // Nothing is written to the print output.
+    let (output, _) = vm.into_output();
+    assert!(output.is_empty());
+}
+
+#[test]
+fn print_callback_gets_written_values() {
+    let printed = Rc::new(RefCell::new(Vec::new()));
+    let collected = Rc::clone(&printed);
+    let mut vm = VM::builder()
+        .on_print(move |value| collected.borrow_mut().push(value.clone()))
+        .build(Vec::new(), Vec::new());
+    let source = "write(1);\nprintf(\"{} and {}\", 2, \"three\");";
+    assert!(run_source(&mut vm, source, std::io::sink()).is_ok());
+
+    let printed = printed.borrow();
+    assert_eq!(printed.len(), 2);
+    assert_eq!(printed[0].as_number(), Some(1.0));
+    assert_eq!(printed[1].as_str(), Some("2 and three"));
+}
+
+#[test]
+fn strict_arity_by_default() {
+    let mut vm = VM::builder().build(Vec::new(), Vec::new());
+    let source = "fun pair(a, b) { print a; print b; }\npair(1);";
+    match run_source(&mut vm, source, std::io::sink()) {
+        Err(rlox::Error::Run(error)) => {
+            assert_eq!(error.get_message(), "Expected 2 arguments but got 1.")
+        }
+        _ => panic!("Expected a runtime error."),
+    }
+}
+
+#[test]
+fn lenient_arity() {
+    let mut vm = VM::builder()
+        .lenient_arity(true)
+        .build(Vec::new(), Vec::new());
+    let source = "fun pair(a, b) { print a; print b; }
+        pair(1);
+        pair(1, 2, 3);
+        class Point { init(x, y) { this.x = x; this.y = y; } }
+        var p = Point(4);
+        print p.y;";
+    assert!(run_source(&mut vm, source, std::io::sink()).is_ok());
+
+    let (output, _) = vm.into_output();
+    assert_eq!(String::from_utf8(output).unwrap(), "1\nnil\n1\n2\nnil\n");
+}
+
+#[test]
+fn lenient_arity_keeps_native_arity() {
+    let mut vm = VM::builder()
+        .native("double", 1, double)
+        .lenient_arity(true)
+        .build(Vec::new(), Vec::new());
+    match run_source(&mut vm, "double(1, 2);", std::io::sink()) {
+        Err(rlox::Error::Run(error)) => {
+            assert_eq!(error.get_message(), "Expected 1 arguments but got 2.")
+        }
+        _ => panic!("Expected a runtime error."),
+    }
+}
+
+#[test]
+fn custom_output_sink() {
+    let mut vm = VM::builder().build(Lines::default(), Lines::default());
+    assert!(run_source(&mut vm, "print 1;\nprint \"two\";", std::io::sink()).is_ok());
+    assert!(run_source(&mut vm, "print -nil;", std::io::sink()).is_err());
+
+    let (output, error) = vm.into_output();
+    assert_eq!(output.lines, ["1", "two"]);
+    assert_eq!(
+        error.lines,
+        ["[line 1] in script(): Operand must be a number."]
+    );
+}
+
+#[test]
+fn native_reports_error() {
+    let mut vm = VM::builder()
+        .native("half", 1, half)
+        .build(Vec::new(), Vec::new());
+    let source = "print half(4);\nprint half(3);\nprint \"unreachable\";";
+    match run_source(&mut vm, source, std::io::sink()) {
+        Err(rlox::Error::Run(error)) => {
+            assert_eq!(error.get_message(), "Argument must be an even number.");
+            assert_eq!(error.get_stack_trace(), [(2, String::from("script"))]);
+        }
+        _ => panic!("Expected a runtime error."),
+    }
+
+    let (output, _) = vm.into_output();
+    assert_eq!(String::from_utf8(output).unwrap(), "2\n");
+}
+
+#[test]
+fn read_line_from_input() {
+    let mut vm = VM::builder()
+        .input(b"hello\r\nworld\nlast".as_slice())
+        .build(Vec::new(), Vec::new());
+    let source = "var line = read_line();
+        while (line != nil) {
+          print \"echo \" + line;
+          line = read_line();
+        }";
+    assert!(run_source(&mut vm, source, std::io::sink()).is_ok());
+
+    let (output, _) = vm.into_output();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "echo hello\necho world\necho last\n"
+    );
+}
+
+#[test]
+fn declaration_ranges_are_in_bytes() {
+    let source = "var greeting = \"Grüß dich\";\nfun greet() {\n  print greeting;\n}\ngreet();\n";
+    let ranges = declarations(source, std::io::sink());
+
+    let spans = ranges
+        .iter()
+        .map(|(range, kind)| (&source[range.clone()], *kind))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        spans,
+        vec![
+            ("var greeting = \"Grüß dich\";", DeclarationKind::Variable),
+            (
+                "fun greet() {\n  print greeting;\n}",
+                DeclarationKind::Function
+            ),
+            ("greet();", DeclarationKind::Statement),
+        ]
+    );
+}
+
+#[test]
+fn disassemble_nested_functions() {
+    let source = "fun outer() {\n  fun inner() { return 1; }\n  return inner;\n}\nprint outer()();";
+    let mut buffer: Vec<u8> = Vec::new();
+    assert!(disassemble(source, std::io::sink(), &mut buffer).is_ok());
+
+    let output = String::from_utf8(buffer).unwrap();
+    let headers = output
+        .lines()
+        .filter(|line| line.starts_with("=="))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        headers,
+        vec!["== <script> ==", "== outer ==", "== inner =="]
+    );
+}
+
+#[test]
+fn tokens_as_json() {
+    let source = "print \"a\\\"\";\n@";
+    let mut buffer: Vec<u8> = Vec::new();
+    assert!(dump_tokens_json(source, &mut buffer).is_ok());
+
+    let expected = r#"[
+{"type": "Print", "lexeme": "print", "line": 1, "column": 1},
+{"type": "String", "lexeme": "\"a\\\"\"", "line": 1, "column": 7},
+{"type": "Semicolon", "lexeme": ";", "line": 1, "column": 12},
+{"type": "Error", "lexeme": "Unexpected character.", "line": 2, "column": 1},
+{"type": "EOF", "lexeme": "", "line": 2, "column": 2}
+]
+"#;
+    assert_eq!(String::from_utf8(buffer).unwrap(), expected);
+}
+
+#[test]
+fn inspect_compiled_chunk() {
+    let script = compile("print 1.5;\nprint \"lox\";", std::io::sink()).unwrap();
+    let chunk = script.get_chunk();
+
+    let constants = chunk.get_constants();
+    assert_eq!(constants.len(), 2);
+    assert_eq!(constants[0].as_number(), Some(1.5));
+    assert_eq!(constants[1].as_str(), Some("lox"));
+    // Constant, Print, Constant, Print, Nil and Return, with an index after each constant.
+    assert_eq!(chunk.get_code_len(), 8);
+}
+
+#[test]
+fn iterate_compiled_instructions() {
+    let script = compile("var x = 1;\nprint x;", std::io::sink()).unwrap();
+    let instructions = script
+        .get_chunk()
+        .get_instructions()
+        .map(|i| (i.get_offset(), i.get_opcode(), i.get_operands().to_vec()))
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        instructions,
+        vec![
+            (0, OpCode::Constant, vec![1]),
+            (2, OpCode::DefineGlobal, vec![0]),
+            (4, OpCode::GetGlobal, vec![0]),
+            (6, OpCode::Print, vec![]),
+            (7, OpCode::Nil, vec![]),
+            (8, OpCode::Return, vec![]),
+        ]
+    );
+}
+
+#[test]
+fn run_compiled_program() {
+    let source = "class Counter {
+  init(start) { this.count = start; }
+  next() { this.count = this.count + 1; return this.count; }
+}
+fun twice(f) { f(); return f(); }
+var counter = Counter(40);
+print twice(counter.next);
+print \"done\";";
+    let bytes = compile_to_bytes(source, std::io::sink()).unwrap();
+
+    // The program runs again without being compiled.
+    for _ in 0..2 {
+        let (result, (output, _)) = run_bytes(&bytes, Vec::new(), Vec::new());
+        assert!(result.is_ok());
+        assert_eq!(String::from_utf8(output).unwrap(), "42\ndone\n");
+    }
+}
+
+#[test]
+fn run_compiled_program_with_runtime_error() {
+    let bytes = compile_to_bytes("print 1;\nprint -\"a\";", std::io::sink()).unwrap();
+    let (result, (output, error)) = run_bytes(&bytes, Vec::new(), Vec::new());
+    assert!(matches!(result, Err(Error::Run(_))));
+    assert_eq!(String::from_utf8(output).unwrap(), "1\n");
+    assert_eq!(
+        String::from_utf8(error).unwrap(),
+        "[line 2] in script(): Operand must be a number.\n"
+    );
+}
+
+#[test]
+fn run_bytes_rejects_other_bytes() {
+    let (result, _) = run_bytes(b"print 1;", Vec::new(), Vec::new());
+    assert!(matches!(
+        result,
+        Err(Error::Load(DeserializeError::NotAChunk))
+    ));
+
+    let bytes = compile_to_bytes("print 1;", std::io::sink()).unwrap();
+    let (result, (output, _)) = run_bytes(&bytes[..bytes.len() - 1], Vec::new(), Vec::new());
+    assert!(matches!(
+        result,
+        Err(Error::Load(DeserializeError::Truncated))
+    ));
+    assert!(output.is_empty());
+}
+
+#[test]
+fn compile_to_bytes_reports_compile_errors() {
+    let result = compile_to_bytes("print 1", std::io::sink());
+    assert!(matches!(result, Err(Error::Compile(errors)) if errors.len() == 1));
+}
+
+#[test]
+fn disassemble_reports_compile_errors() {
+    let mut buffer: Vec<u8> = Vec::new();
+    let result = disassemble("print ;", std::io::sink(), &mut buffer);
+    assert!(matches!(result, Err(rlox::Error::Compile(_))));
+    assert!(buffer.is_empty());
+}
+
+#[test]
+fn trace_instructions() {
+    let mut vm = VM::builder().trace(true).build(Vec::new(), Vec::new());
+    assert!(run_source(&mut vm, "print 1 + 2;", std::io::sink()).is_ok());
+
+    let (output, trace) = vm.into_output();
+    assert_eq!(String::from_utf8(output).unwrap(), "3\n");
+    let trace = String::from_utf8(trace).unwrap();
+    assert!(trace.contains("Constant"));
+    assert!(trace.contains("[1][2]\n0004    | Add"));
+    assert!(trace.contains("[3]\n0005    | Print"));
+}
+
+#[test]
+fn no_trace_by_default() {
+    let mut vm = VM::builder().build(Vec::new(), Vec::new());
+    assert!(run_source(&mut vm, "print 1 + 2;", std::io::sink()).is_ok());
+
+    let (_, errors) = vm.into_output();
+    assert!(errors.is_empty());
+}
@@ -0,0 +1,85 @@
+use std::process::Command;
+
+// Runs the rlox binary with the given arguments and returns its exit code.
+fn exit_code(args: &[&str]) -> Option<i32> {
+    Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .args(args)
+        .output()
+        .expect("Expected the binary to run.")
+        .status
+        .code()
+}
+
+#[test]
+fn success() {
+    assert_eq!(
+        exit_code(&["tests/files/system_test_files/fib.lox"]),
+        Some(0)
+    );
+}
+
+#[test]
+fn compile_error() {
+    let path = "tests/files/crafting_interpreters_test_files/other/unexpected_character.lox";
+    assert_eq!(exit_code(&[path]), Some(65));
+}
+
+#[test]
+fn runtime_error() {
+    let path = "tests/files/crafting_interpreters_test_files/operator/negate_nonnum.lox";
+    assert_eq!(exit_code(&[path]), Some(70));
+}
+
+#[test]
+fn missing_file() {
+    assert_eq!(exit_code(&["tests/files/does_not_exist.lox"]), Some(74));
+}
+
+#[test]
+fn dump_tokens_json() {
+    let path = "tests/files/crafting_interpreters_test_files/other/unexpected_character.lox";
+    assert_eq!(exit_code(&["--dump-tokens-json", path]), Some(0));
+    assert_eq!(
+        exit_code(&["--dump-tokens-json", "tests/files/does_not_exist.lox"]),
+        Some(74)
+    );
+}
+
+#[test]
+fn compile_and_run_compiled() {
+    let compiled = std::env::temp_dir().join(format!("rlox-exit-code-{}.loxc", std::process::id()));
+    let compiled = compiled.to_str().unwrap();
+    let path = "tests/files/system_test_files/fib.lox";
+    assert_eq!(exit_code(&["--compile", path, compiled]), Some(0));
+    assert_eq!(exit_code(&["--run-compiled", compiled]), Some(0));
+    std::fs::remove_file(compiled).unwrap();
+
+    // A source file is not a compiled program.
+    assert_eq!(exit_code(&["--run-compiled", path]), Some(65));
+    let path = "tests/files/crafting_interpreters_test_files/other/unexpected_character.lox";
+    assert_eq!(exit_code(&["--compile", path, compiled]), Some(65));
+}
+
+#[test]
+fn too_many_arguments() {
+    assert_eq!(exit_code(&["a.lox", "b.lox"]), Some(64));
+}
+
+#[test]
+fn unused_local_warning() {
+    let path = std::env::temp_dir().join(format!("rlox-warning-{}.lox", std::process::id()));
+    std::fs::write(&path, "{\n  var unused = 1;\n  print \"ran\";\n}\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .arg(&path)
+        .output()
+        .expect("Expected the binary to run.");
+    std::fs::remove_file(&path).unwrap();
+
+    // The warning does not stop the program from running.
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "ran\n");
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "[line 2] Warning: Local variable 'unused' is never read.\n"
+    );
+}
@@ -0,0 +1,20 @@
+use std::process::Command;
+
+// Only debug builds can be made to panic on purpose.
+#[cfg(debug_assertions)]
+#[test]
+fn report_internal_error() {
+    let output = Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .arg("tests/files/system_test_files/fib.lox")
+        .env("RLOX_FORCE_PANIC", "1")
+        .output()
+        .expect("Expected the binary to run.");
+
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(output.status.code(), Some(101));
+    assert!(stderr
+        .starts_with("Internal interpreter error. This is a bug in rlox, please report it.\n"));
+    // Debug builds also print the panic message.
+    assert!(stderr.contains("Panic forced by RLOX_FORCE_PANIC."));
+    assert!(output.stdout.is_empty());
+}
@@ -0,0 +1,65 @@
+use rlox::run_repl;
+
+// Runs the lines in the REPL and returns what got printed, without the prompts, and the errors.
+fn repl(lines: &str) -> (String, String) {
+    let (result, output) = run_repl(lines.as_bytes(), Vec::new(), Vec::new(), Vec::new());
+    assert!(result.is_ok());
+
+    let (compiler_output, vm_output, vm_err) = output.decompose();
+    let printed = String::from_utf8(vm_output).unwrap().replace("> ", "");
+    let errors = String::from_utf8(compiler_output).unwrap() + &String::from_utf8(vm_err).unwrap();
+    (printed, errors)
+}
+
+#[test]
+fn keep_globals_between_lines() {
+    let (printed, errors) = repl("var a = 1;\nfun f(x) { return x + a; }\nprint f(2);\n");
+    assert_eq!(printed, "3\n\n");
+    assert_eq!(errors, "");
+}
+
+#[test]
+fn print_expression_without_semicolon() {
+    let (printed, _) = repl("var a = \"lox\";\na\n1 + 2\n");
+    assert_eq!(printed, "lox\n3\n\n");
+}
+
+#[test]
+fn continue_after_errors() {
+    let (printed, errors) = repl("var = 1;\nprint b;\nvar b = 2;\nprint b;\n");
+    assert_eq!(printed, "2\n\n");
+    assert_eq!(
+        errors,
+        "[line 1] Error at '=': Expect variable name.\n\
+         [line 1] in script(): Undefined variable 'b'.\n"
+    );
+}
+
+#[test]
+fn keep_constants_between_lines() {
+    let (printed, errors) = repl("const a = 1;\na = 2;\nprint a;\n");
+    assert_eq!(printed, "1\n\n");
+    assert_eq!(
+        errors,
+        "[line 1] in script(): Cannot assign to constant 'a'.\n"
+    );
+
+    // The function is compiled before 'b' is declared.
+    let (printed, errors) = repl("fun f() { b = 5; }\nconst b = 1;\nf();\nprint b;\n");
+    assert_eq!(printed, "1\n\n");
+    assert_eq!(
+        errors,
+        "[line 1] in f(): Cannot assign to constant 'b'.\n\
+         [line 1] in script(): Cannot assign to constant 'b'.\n"
+    );
+}
+
+#[test]
+fn warn_about_unused_locals() {
+    let (printed, errors) = repl("{ var a = 1; print 2; }\n");
+    assert_eq!(printed, "2\n\n");
+    assert_eq!(
+        errors,
+        "[line 1] Warning: Local variable 'a' is never read.\n"
+    );
+}
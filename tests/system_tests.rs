@@ -1,6 +1,6 @@
 use std::io::Read;
 
-use rlox::{run_program, Error};
+use rlox::{run_program, run_program_with_input, Error};
 
 fn read_file(path: &str) -> Result<String, Error> {
     let path = std::path::Path::new(path);
@@ -71,5 +71,46 @@ tests! {
     op_invoke,
     super_method_call,
     super_get_closure,
-    bin_search_tree
+    bin_search_tree,
+    instance_identity,
+    initializer_return,
+    bound_method_mutation,
+    chained_property_assignment,
+    break_statement,
+    continue_statement,
+    switch_statement,
+    modulo,
+    for_continue_increment,
+    string_escapes,
+    closure_loop_variables
+}
+
+#[test]
+fn compile_errors_of_separate_statements() {
+    let path = "tests/files/system_test_files/compile_error/separate_statements.lox";
+    let errors = match run_program(path, Vec::new(), Vec::new(), Vec::new()) {
+        (Err(Error::Compile(errors)), _) => errors,
+        _ => panic!("Expected the program not to compile."),
+    };
+
+    let positions = errors
+        .iter()
+        .map(|error| (error.get_line(), error.get_column(), error.get_message()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        positions,
+        [(3, 9, "Expect expression."), (5, 10, "Expect expression.")]
+    );
+}
+
+#[test]
+fn read_input() {
+    let path = "tests/files/system_test_files/read_input.lox";
+    let input = b"Lox\n21\n".as_slice();
+    let (result, output) =
+        run_program_with_input(path, input, std::io::sink(), Vec::new(), std::io::sink());
+
+    assert!(result.is_ok());
+    let output = String::from_utf8(output.decompose().1).unwrap();
+    assert_eq!(output, "Hello Lox!\n42\nnil\n");
 }